@@ -2,7 +2,7 @@ use wasm_bindgen::prelude::*;
 
 use gbc::cartridge::Cartridge as Cartridge_;
 use gbc::joypad::{JoypadEvent, JoypadInput as JoypadInput_};
-use gbc::ppu::{GameboyRgb, LCD_HEIGHT, LCD_WIDTH};
+use gbc::ppu::{ColorCorrection as ColorCorrection_, PixelFormat, LCD_HEIGHT, LCD_WIDTH};
 use gbc::Gameboy as Gameboy_;
 
 // Re-exported JopypadInput enum
@@ -34,6 +34,34 @@ impl From<JoypadInput> for JoypadInput_ {
     }
 }
 
+// Re-exported ColorCorrection enum
+#[wasm_bindgen]
+#[derive(Clone, Copy)]
+pub enum ColorCorrection {
+    None,
+    GbcLcd,
+    GbaLcd,
+}
+
+impl From<ColorCorrection> for ColorCorrection_ {
+    fn from(correction: ColorCorrection) -> Self {
+        match correction {
+            ColorCorrection::None => ColorCorrection_::None,
+            ColorCorrection::GbcLcd => ColorCorrection_::GbcLcd,
+            ColorCorrection::GbaLcd => ColorCorrection_::GbaLcd,
+        }
+    }
+}
+
+/// Layers that can be shown or hidden for debugging
+#[wasm_bindgen]
+#[derive(Clone, Copy)]
+pub enum Layer {
+    Background,
+    Window,
+    Sprites,
+}
+
 #[wasm_bindgen]
 pub struct Cartridge(Cartridge_);
 
@@ -53,6 +81,9 @@ impl Cartridge {
 pub struct Gameboy {
     inner: Gameboy_,
     inputs: Vec<JoypadEvent>,
+
+    /// Audio samples produced during the last frame
+    audio: Vec<f32>,
 }
 
 #[wasm_bindgen]
@@ -60,27 +91,95 @@ impl Gameboy {
     /// Create a new `Gameboy` from a valid cartridge
     #[wasm_bindgen(constructor)]
     pub fn new(cartridge: Cartridge) -> Result<Gameboy, JsValue> {
-        let inner =
+        let mut inner =
             Gameboy_::init(cartridge.0, false).map_err(|e| JsValue::from_str(&e.to_string()))?;
+
+        // Frames are handed straight to a canvas `ImageData`
+        inner.set_pixel_format(PixelFormat::Rgba8888);
         let inputs = Vec::new();
+        let audio = Vec::new();
 
-        Ok(Self { inner, inputs })
+        Ok(Self {
+            inner,
+            inputs,
+            audio,
+        })
     }
 
     /// Run the Gameboy for a single frame.
     ///
-    /// Returns a pointer to the first pixel in the frame buffer. This
-    /// allows JS to overlay this memory on a Uint8Array to avoid copying the
-    /// entire frame.
-    pub fn frame(&mut self) -> *const GameboyRgb {
-        let frame_buffer = self.inner.frame(Some(&self.inputs));
+    /// Returns a pointer to the first pixel in the frame buffer, which is
+    /// in RGBA format (4 bytes per pixel). This allows JS to overlay this
+    /// memory on a Uint8ClampedArray and pass it to `ImageData` without copying
+    /// the entire frame.
+    pub fn frame(&mut self) -> *const u8 {
+        let frame_buffer = self.inner.frame(Some(&self.inputs)).as_bytes().as_ptr();
         self.inputs.clear();
-        frame_buffer.data.as_ptr()
+
+        // Collect the audio produced during this frame
+        self.audio.clear();
+        self.inner.audio_samples(&mut self.audio);
+
+        frame_buffer
+    }
+
+    /// Set the color correction applied to CGB colors.
+    ///
+    /// Like the sample rate, this is not part of the save state, so it needs to
+    /// be set again after `Gameboy::load`.
+    pub fn set_color_correction(&mut self, correction: ColorCorrection) {
+        self.inner.set_color_correction(correction.into());
+    }
+
+    /// Show or hide a whole layer (for debugging)
+    pub fn set_layer_visible(&mut self, layer: Layer, visible: bool) {
+        let mut mask = self.inner.layer_mask();
+
+        match layer {
+            Layer::Background => mask.bg = visible,
+            Layer::Window => mask.window = visible,
+            Layer::Sprites => mask.sprites = visible,
+        }
+
+        self.inner.set_layer_mask(mask);
+    }
+
+    /// Show or hide a single sprite, given its index in OAM (0-39)
+    pub fn set_sprite_visible(&mut self, oam_index: u8, visible: bool) {
+        let mut mask = self.inner.layer_mask();
+        mask.set_sprite_visible(oam_index, visible);
+        self.inner.set_layer_mask(mask);
     }
 
     pub fn reset(&mut self) {
         self.inner.reset();
         self.inputs.clear();
+        self.audio.clear();
+    }
+
+    /// Set the audio output sample rate, in Hz. A rate of 0 disables audio.
+    ///
+    /// This should match the sample rate of the `AudioContext`, and can be
+    /// nudged slightly between frames to keep playback at a steady latency. It
+    /// is not part of the save state, so it needs to be set again after
+    /// `Gameboy::load`.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.inner.set_sample_rate(sample_rate);
+    }
+
+    /// Returns a pointer to the audio samples produced during the last frame.
+    ///
+    /// Samples are interleaved stereo `f32` values in the range [-1.0, 1.0]. As
+    /// with `Gameboy::frame`, JS can overlay this memory on a Float32Array of
+    /// length `Gameboy::audio_samples_len` to avoid copying. The pointer is only
+    /// valid until the next call to `Gameboy::frame`.
+    pub fn audio_samples(&self) -> *const f32 {
+        self.audio.as_ptr()
+    }
+
+    /// Number of `f32` values (two per stereo frame) produced during the last frame
+    pub fn audio_samples_len(&self) -> usize {
+        self.audio.len()
     }
 
     /// Record a joypad input for the next frame
@@ -109,9 +208,14 @@ impl Gameboy {
     /// Create a Gameboy from an existing save state
     pub fn load(data: &[u8], cartridge: Cartridge) -> Result<Gameboy, JsValue> {
         Gameboy_::load(data, cartridge.0)
+            .map(|mut gameboy| {
+                gameboy.set_pixel_format(PixelFormat::Rgba8888);
+                gameboy
+            })
             .map(|gameboy| Self {
                 inner: gameboy,
                 inputs: Vec::new(),
+                audio: Vec::new(),
             })
             .map_err(|e| JsValue::from_str(&e.to_string()))
     }
@@ -0,0 +1,56 @@
+// AudioWorklet processor that plays back interleaved stereo samples posted
+// from the main thread after each emulated frame. After each frame, it reports
+// back how many samples it has queued.
+class GameboyAudioProcessor extends AudioWorkletProcessor {
+    constructor() {
+        super();
+
+        // Ring buffer of interleaved stereo samples (~0.5s at 48 kHz)
+        this.buffer = new Float32Array(48000);
+        this.readPos = 0;
+        this.writePos = 0;
+        this.count = 0;
+
+        this.port.onmessage = (event) => this.push(event.data);
+    }
+
+    push(samples) {
+        for (let i = 0; i < samples.length; i++) {
+            // Drop samples if the buffer is full
+            if (this.count == this.buffer.length) {
+                break;
+            }
+
+            this.buffer[this.writePos] = samples[i];
+            this.writePos = (this.writePos + 1) % this.buffer.length;
+            this.count++;
+        }
+
+        // Report the number of queued stereo frames, which the main thread
+        // uses to adjust the sample rate
+        this.port.postMessage(this.count / 2);
+    }
+
+    process(inputs, outputs) {
+        const left = outputs[0][0];
+        const right = outputs[0][1];
+
+        for (let i = 0; i < left.length; i++) {
+            // Output silence on underrun
+            if (this.count < 2) {
+                left[i] = 0;
+                right[i] = 0;
+                continue;
+            }
+
+            left[i] = this.buffer[this.readPos];
+            right[i] = this.buffer[this.readPos + 1];
+            this.readPos = (this.readPos + 2) % this.buffer.length;
+            this.count -= 2;
+        }
+
+        return true;
+    }
+}
+
+registerProcessor("gameboy-audio-processor", GameboyAudioProcessor);
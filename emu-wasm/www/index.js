@@ -2,6 +2,14 @@ import * as wasm from "gbcemu";
 import { memory } from "gbcemu/gbcemu_bg";
 import { get, set } from "idb-keyval";
 
+// Target audio latency, in ms
+const AUDIO_LATENCY = 60;
+
+// Maximum deviation from the audio context sample rate for rate control. This
+// is larger than in the SDL frontend, since frames are paced by a timer rather
+// than by the audio output.
+const MAX_RATE_DELTA = 0.02;
+
 class Emulator {
     constructor() {
         this.lcd_width = wasm.Gameboy.lcd_width();
@@ -11,6 +19,13 @@ class Emulator {
         this.romName = null;
         this.running = false;
 
+        // Web Audio output, setup on first start
+        this.audioContext = null;
+        this.audioNode = null;
+
+        // Number of stereo frames queued in the worklet, as last reported by it
+        this.audioQueued = 0;
+
         this.canvas = document.getElementById("emulator");
         this.ctx = this.canvas.getContext("2d");
 
@@ -41,9 +56,42 @@ class Emulator {
             throw e;
         }
 
+        this.setupGameboyAudio();
+
         console.log("Gameboy loaded!");
     }
 
+    // Setup the Web Audio context and worklet
+    //
+    // This must be called from a user gesture (e.g., a button click).
+    async initAudio() {
+        if (this.audioContext != null) {
+            return;
+        }
+
+        this.audioContext = new AudioContext();
+        await this.audioContext.audioWorklet.addModule("audio-processor.js");
+
+        this.audioNode = new AudioWorkletNode(this.audioContext, "gameboy-audio-processor", {
+            outputChannelCount: [2],
+        });
+        this.audioNode.connect(this.audioContext.destination);
+
+        // The worklet reports its fill level after each batch of samples
+        this.audioNode.port.onmessage = (event) => {
+            this.audioQueued = event.data;
+        };
+
+        this.setupGameboyAudio();
+    }
+
+    // Match the Gameboy audio output to the audio context
+    setupGameboyAudio() {
+        if (this.gameboy != null && this.audioContext != null) {
+            this.gameboy.set_sample_rate(this.audioContext.sampleRate);
+        }
+    }
+
     async loadRom() {
         if (this.romPicker.files.length == 0) {
             alert("Please load a ROM first!");
@@ -57,6 +105,8 @@ class Emulator {
     }
 
     start() {
+        this.initAudio().catch((e) => console.error("Failed to setup audio:", e));
+
         this.loadRom().then((romBuffer) => {
             this.init(romBuffer);
             this.run();
@@ -109,6 +159,7 @@ class Emulator {
             window.alert("No save state found for this ROM!");
         } else {
             this.gameboy = wasm.Gameboy.load(saveState, cartridge);
+            this.setupGameboyAudio();
             this.run();
         }
     }
@@ -124,37 +175,53 @@ class Emulator {
     }
 
     renderFrame() {
-        // Get a pointer to the frame in WASM memory, then overlay a Uint8Array on top
+        // Get a pointer to the frame in WASM memory, then overlay a Uint8ClampedArray on top
         // to avoid copying the frame data out of WASM into JS on every frame
         const frameBufferPtr = this.gameboy.frame();
 
-        // Each pixel in the frame buffer consists of 3 bytes for RGB values
-        const frameBuffer = new Uint8Array(memory.buffer, frameBufferPtr,
-                                           this.lcd_width * this.lcd_height * 3);
-
-        // Create an `imageData` to write pixels to
-        const imageData = this.ctx.createImageData(this.lcd_width, this.lcd_height);
-        const data = imageData.data;
-
-        // Iterate over the frame buffer pixels and write each one to the imageData buffer
-        for (var x = 0; x < this.lcd_width; x += 1) {
-            for (var y = 0; y < this.lcd_height; y += 1) {
-                const source_idx = y * this.lcd_width * 3 + x * 3;
-                const red = frameBuffer[source_idx];
-                const green = frameBuffer[source_idx+1];
-                const blue = frameBuffer[source_idx+2];
-
-                // Frame buffer pixels are just RGB, so we need to add the alpha
-                const dest_idx = y * this.lcd_width * 4 + x * 4;
-                data[dest_idx] = red;
-                data[dest_idx+1] = green;
-                data[dest_idx+2] = blue;
-                data[dest_idx+3] = 255; // alpha
-            }
-        }
+        // Each pixel in the frame buffer consists of 4 bytes for RGBA values,
+        // which is exactly what `ImageData` expects
+        const frameBuffer = new Uint8ClampedArray(memory.buffer, frameBufferPtr,
+                                                  this.lcd_width * this.lcd_height * 4);
+        const imageData = new ImageData(frameBuffer, this.lcd_width, this.lcd_height);
 
         // Render the frame on the canvas at position (0, 0)
         this.ctx.putImageData(imageData, 0, 0);
+
+        this.playAudio();
+    }
+
+    playAudio() {
+        if (this.audioNode == null) {
+            return;
+        }
+
+        // Overlay a Float32Array on the audio samples in WASM memory, same as
+        // the frame buffer. Posting that view would clone all of WASM memory,
+        // so copy out only the samples and transfer the copy to the worklet.
+        const samples = new Float32Array(memory.buffer, this.gameboy.audio_samples(),
+                                         this.gameboy.audio_samples_len()).slice();
+
+        if (samples.length > 0) {
+            this.audioNode.port.postMessage(samples, [samples.buffer]);
+        }
+
+        this.gameboy.set_sample_rate(this.audioRate());
+    }
+
+    // Sample rate to request from the Gameboy for the next frame
+    //
+    // The frame timer drifts against the audio clock, so the rate is nudged
+    // based on the worklet's fill level to keep it at the target latency (see
+    // `AudioPlayer::rate` in the SDL frontend).
+    audioRate() {
+        const sampleRate = this.audioContext.sampleRate;
+        const target = sampleRate * AUDIO_LATENCY / 1000;
+
+        // Positive if the worklet is above target, negative if below
+        const fill = Math.min(Math.max((this.audioQueued - target) / target, -1), 1);
+
+        return Math.round(sampleRate * (1 - MAX_RATE_DELTA * fill));
     }
 
     // Map a raw keycode to a emulator joypad input
@@ -1,18 +1,31 @@
+use std::fs::File;
 use std::fs::OpenOptions;
-use std::io::{Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
 
+use gbc::apu::{Channel, Sample};
 use gbc::cartridge::Cartridge;
 use gbc::joypad::{JoypadEvent, JoypadInput};
-use gbc::ppu::{FrameBuffer, GameboyRgb, LCD_HEIGHT, LCD_WIDTH};
+use gbc::link::{Link, TcpCable};
+use gbc::palette::DmgPalette;
+use gbc::ppu::{
+    ColorCorrection, FrameBuffer, LayerMask, PixelFormat, TileMap, TilePalette, LCD_HEIGHT,
+    LCD_WIDTH,
+};
+use gbc::printer::Printer;
+use gbc::serial::SerialDevice;
+use gbc::wav::WavWriter;
 use gbc::Gameboy;
 
-use sdl2::event::Event;
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::event::{Event, WindowEvent};
 use sdl2::keyboard::Keycode;
-use sdl2::pixels::Color;
-use sdl2::render::{Canvas, Texture, TextureAccess};
-use sdl2::video::Window;
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
+use sdl2::render::{Canvas, Texture, TextureCreator};
+use sdl2::video::{FullscreenType, Window, WindowContext};
+use sdl2::AudioSubsystem;
 
 use structopt::StructOpt;
 
@@ -49,6 +62,170 @@ impl FpsCounter {
     }
 }
 
+/// Emulation options for the SDL frontend
+struct EmulatorOptions {
+    /// Emulation speed multiplier
+    speed: u8,
+
+    /// Boot into the DMG boot ROM
+    boot_rom: bool,
+
+    /// Trace all instructions to a file in the current directory
+    trace: bool,
+
+    /// Load the emulator from the save state next to the ROM
+    load: bool,
+
+    /// Link cable or printer to plug into the serial port
+    serial_device: Option<Box<dyn SerialDevice>>,
+}
+
+/// Audio options for the SDL frontend
+struct AudioOptions {
+    mute: bool,
+
+    /// Volume, in percent
+    volume: u8,
+
+    /// Target audio latency, in ms
+    latency: u32,
+
+    /// Start recording audio to this WAV file on launch
+    record: Option<PathBuf>,
+}
+
+/// SDL renderer backend
+#[derive(Clone, Copy, Debug)]
+enum Renderer {
+    Software,
+    Accelerated,
+}
+
+impl std::str::FromStr for Renderer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "software" => Ok(Self::Software),
+            "accelerated" => Ok(Self::Accelerated),
+            _ => Err(format!(
+                "unknown renderer: {} (expected software or accelerated)",
+                s
+            )),
+        }
+    }
+}
+
+struct VideoOptions {
+    /// Initial window size, as a multiple of the LCD resolution
+    scale: u32,
+
+    renderer: Renderer,
+
+    /// Only scale the frame by whole multiples, with black bars around it
+    integer_scale: bool,
+
+    /// Colors used to render DMG games, or `None` to pick them like a CGB
+    palette: Option<DmgPalette>,
+
+    color_correction: ColorCorrection,
+
+    /// Open a debug window that shows the contents of VRAM
+    vram_viewer: bool,
+}
+
+/// Plays back Gameboy audio through an SDL audio queue.
+///
+/// When audio is active, frame pacing is driven by the audio queue: after each
+/// frame, we wait for the queue to drain down to the target latency instead of
+/// sleeping for a fixed frame duration.
+///
+/// The emulated clock and the audio device clock never match exactly, so the queue
+/// would slowly drift towards an underrun or an ever-increasing latency. To avoid
+/// this, the sample rate requested from the emulator is nudged up or down based on
+/// how full the queue is (dynamic rate control).
+struct AudioPlayer {
+    queue: AudioQueue<f32>,
+    samples: Vec<f32>,
+    volume: f32,
+
+    /// Target queue size, in stereo frames
+    target: u32,
+}
+
+impl AudioPlayer {
+    const SAMPLE_RATE: i32 = 48000;
+
+    /// Maximum deviation from the device sample rate for rate control
+    const MAX_RATE_DELTA: f64 = 0.005;
+
+    fn new(audio_subsystem: &AudioSubsystem, options: &AudioOptions) -> Self {
+        let desired = AudioSpecDesired {
+            freq: Some(Self::SAMPLE_RATE),
+            channels: Some(2),
+            samples: Some(512),
+        };
+
+        let queue = audio_subsystem
+            .open_queue::<f32, _>(None, &desired)
+            .expect("Failed to open audio device");
+
+        let target = queue.spec().freq as u32 * options.latency / 1000;
+
+        queue.resume();
+
+        Self {
+            queue,
+            samples: Vec::new(),
+            volume: options.volume as f32 / 100.0,
+            target,
+        }
+    }
+
+    /// Number of stereo frames currently in the queue
+    fn queued(&self) -> u32 {
+        self.queue.size() / (2 * std::mem::size_of::<f32>() as u32)
+    }
+
+    /// Sample rate of the audio device
+    fn freq(&self) -> u32 {
+        self.queue.spec().freq as u32
+    }
+
+    /// Push interleaved stereo samples to the audio queue.
+    fn push(&mut self, samples: &[f32]) {
+        let volume = self.volume;
+
+        self.samples.clear();
+        self.samples
+            .extend(samples.iter().map(|sample| sample * volume));
+
+        self.queue.queue(&self.samples);
+    }
+
+    /// Sample rate to request from the Gameboy for the next frame.
+    ///
+    /// This is adjusted based on the queue size and the current emulation speed.
+    /// For speeds other than 1x, the audio is pitch-shifted: fewer samples are
+    /// generated per emulated frame, so the emulator has to run faster to keep
+    /// the queue filled.
+    fn rate(&self, speed: u8) -> u32 {
+        // Positive if the queue is above target, negative if below
+        let fill = (self.queued() as f64 - self.target as f64) / self.target as f64;
+        let fill = fill.clamp(-1.0, 1.0);
+
+        let rate = self.freq() as f64 * (1.0 - Self::MAX_RATE_DELTA * fill);
+        (rate / speed as f64) as u32
+    }
+
+    /// Block until the queue drains to the target latency.
+    fn wait(&self, sleeper: &spin_sleep::SpinSleeper) {
+        while self.queued() > self.target {
+            sleeper.sleep(Duration::from_micros(500));
+        }
+    }
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(about = "A simple GBC emulator written in Rust")]
 enum Args {
@@ -60,6 +237,16 @@ enum Args {
         #[structopt(default_value = "4", long, help = "Emulation resolution multiplier")]
         scale: u32,
 
+        #[structopt(
+            default_value = "software",
+            long,
+            help = "SDL renderer: software or accelerated (GPU)"
+        )]
+        renderer: Renderer,
+
+        #[structopt(long, help = "Only scale the screen by whole multiples")]
+        integer_scale: bool,
+
         #[structopt(default_value = "1", long, help = "Emulation speed multiplier")]
         speed: u8,
 
@@ -77,6 +264,95 @@ enum Args {
             help = "Load emulator from existing save state file (/path/to/rom_file.state)"
         )]
         load: bool,
+
+        #[structopt(long, help = "Disable audio output")]
+        mute: bool,
+
+        #[structopt(default_value = "100", long, help = "Audio volume, in percent (0-100)")]
+        volume: u8,
+
+        #[structopt(default_value = "60", long, help = "Target audio latency, in ms")]
+        audio_latency: u32,
+
+        #[structopt(
+            long,
+            parse(from_os_str),
+            help = "Record audio to a WAV file (toggle recording with R)"
+        )]
+        record_audio: Option<PathBuf>,
+
+        #[structopt(
+            long,
+            conflicts_with = "link-connect",
+            help = "Wait for another emulator to connect a link cable on this port"
+        )]
+        link_listen: Option<u16>,
+
+        #[structopt(long, help = "Connect a link cable to another emulator at HOST:PORT")]
+        link_connect: Option<String>,
+
+        #[structopt(
+            long,
+            parse(from_os_str),
+            conflicts_with_all = &["link-listen", "link-connect"],
+            help = "Connect a Game Boy Printer that saves printouts as PNGs to this directory"
+        )]
+        printer: Option<PathBuf>,
+
+        #[structopt(
+            default_value = "dmg",
+            long,
+            help = "Palette for DMG games: auto (pick it like a CGB), dmg, grayscale, a CGB preset \
+                    (brown, red, dark-brown, pastel, orange, yellow, blue, dark-blue, gray, green, \
+                    dark-green, reverse), or 4 or 12 comma-separated hex colors"
+        )]
+        palette: String,
+
+        #[structopt(
+            default_value = "none",
+            long,
+            help = "Color correction for CGB games: none, gbc (GBC LCD), or gba (GBA LCD)"
+        )]
+        color_correction: ColorCorrection,
+
+        #[structopt(
+            long,
+            help = "Open a debug window that shows the tiles and tile maps in VRAM"
+        )]
+        vram_viewer: bool,
+    },
+    #[structopt(about = "Run two ROMs on two Gameboys connected by a link cable")]
+    Link {
+        #[structopt(parse(from_os_str), help = "Path to ROM file for the first Gameboy")]
+        rom_file_1: PathBuf,
+
+        #[structopt(parse(from_os_str), help = "Path to ROM file for the second Gameboy")]
+        rom_file_2: PathBuf,
+
+        #[structopt(default_value = "4", long, help = "Emulation resolution multiplier")]
+        scale: u32,
+
+        #[structopt(
+            default_value = "software",
+            long,
+            help = "SDL renderer: software or accelerated (GPU)"
+        )]
+        renderer: Renderer,
+
+        #[structopt(long, help = "Only scale the screen by whole multiples")]
+        integer_scale: bool,
+
+        #[structopt(long, help = "Boot into the DMG boot ROM")]
+        boot_rom: bool,
+
+        #[structopt(long, help = "Disable audio output")]
+        mute: bool,
+
+        #[structopt(default_value = "100", long, help = "Audio volume, in percent (0-100)")]
+        volume: u8,
+
+        #[structopt(default_value = "60", long, help = "Target audio latency, in ms")]
+        audio_latency: u32,
     },
     #[structopt(about = "Inspect one or more ROMs")]
     Inspect {
@@ -120,66 +396,166 @@ fn event_to_joypad(event: Event) -> Option<JoypadEvent> {
     }
 }
 
-/// Renders a single Gameboy frame to the SDL canvas using a texture as the render target.
+/// Create a canvas for a window.
+///
+/// The canvas uses the given logical size (e.g., the LCD resolution), so the
+/// frame and the outline overlay are scaled up to the window size (preserving
+/// aspect ratio).
+fn new_canvas(window: Window, options: &VideoOptions, logical_size: (u32, u32)) -> Canvas<Window> {
+    let builder = window.into_canvas();
+    let builder = match options.renderer {
+        Renderer::Software => builder.software(),
+        Renderer::Accelerated => builder.accelerated(),
+    };
+    let mut canvas = builder.build().unwrap();
+
+    canvas
+        .set_logical_size(logical_size.0, logical_size.1)
+        .unwrap();
+
+    if options.integer_scale {
+        // Not wrapped by the sdl2 crate
+        unsafe {
+            sdl2::sys::SDL_RenderSetIntegerScale(canvas.raw(), sdl2::sys::SDL_bool::SDL_TRUE);
+        }
+    }
+
+    canvas
+}
+
+/// Create a streaming texture that frames are uploaded to.
 ///
-/// Once the texture is ready, it is copied back to the canvas and presented.
+/// SDL's RGB888 is a 32-bit XRGB value per pixel, so the Gameboy needs to render
+/// frames in `PixelFormat::Xrgb8888`.
+fn new_texture(texture_creator: &TextureCreator<WindowContext>) -> Texture<'_> {
+    texture_creator
+        .create_texture_streaming(PixelFormatEnum::RGB888, LCD_WIDTH as u32, LCD_HEIGHT as u32)
+        .unwrap()
+}
+
+/// Toggle between windowed and (desktop) fullscreen mode.
+fn toggle_fullscreen(canvas: &mut Canvas<Window>) {
+    let window = canvas.window_mut();
+
+    let fullscreen = match window.fullscreen_state() {
+        FullscreenType::Off => FullscreenType::Desktop,
+        _ => FullscreenType::Off,
+    };
+
+    if let Err(err) = window.set_fullscreen(fullscreen) {
+        log::warn!("Failed to toggle fullscreen: {}", err);
+    }
+}
+
+/// Renders a single Gameboy frame to the SDL canvas.
+///
+/// The whole frame is uploaded to a streaming texture in a single update, which
+/// is then copied to the canvas and presented.
 fn render_frame(
     frame_buffer: &FrameBuffer,
     canvas: &mut Canvas<Window>,
     texture: &mut Texture,
     outline: bool,
 ) {
-    // With the following, we are setting the texture as a render target for
-    // our main canvas. This allows us to use regular canvas drawing functions -
-    // e.g., rect, point - to update the underlyinh texture. Note that the texture
-    // will be updated only when all canvas operations are complete.
-    //
-    // Note that, if GPU rendering is enabled, the texture lives in GPU VRAM. If
-    // this is the case, updates are fairly expensive, as we need to round-trip
-    // to GPU VRAM on every frame (?).
-    //
-    // Once this closure ends, the canvas target is reset back for us.
-    //
-    // Helpful C example: https://wiki.libsdl.org/SDL_CreateTexture
-    canvas
-        .with_texture_canvas(texture, |canvas| {
-            canvas.clear();
-            canvas.set_draw_color(Color::BLACK);
-
-            // Draw the rendered frame
-            for x in 0..LCD_WIDTH {
-                for y in 0..LCD_HEIGHT {
-                    let GameboyRgb { red, green, blue } = frame_buffer.read(x, y);
-                    canvas.set_draw_color(Color::RGBA(red, green, blue, 0xFF));
-                    canvas.draw_point((x as i32, y as i32)).unwrap();
-                }
-            }
+    texture
+        .update(None, frame_buffer.as_bytes(), frame_buffer.pitch())
+        .unwrap();
 
-            if outline {
-                // Draw an outline showing the tiles in the frame
-                canvas.set_draw_color(Color::GRAY);
+    // Clear the borders around the frame (e.g., in fullscreen mode)
+    canvas.set_draw_color(Color::BLACK);
+    canvas.clear();
 
-                for row in (0i32..LCD_HEIGHT as i32).step_by(8) {
-                    canvas
-                        .draw_line((0, row), (LCD_WIDTH as i32 - 1, row))
-                        .unwrap();
-                }
+    canvas.copy(texture, None, None).unwrap();
 
-                for col in (0i32..LCD_WIDTH as i32).step_by(8) {
-                    canvas
-                        .draw_line((col, 0), (col, LCD_HEIGHT as i32 - 1))
-                        .unwrap();
-                }
-            }
-        })
-        .unwrap();
+    if outline {
+        // Draw an outline showing the tiles in the frame
+        canvas.set_draw_color(Color::GRAY);
+
+        for row in (0i32..LCD_HEIGHT as i32).step_by(8) {
+            canvas
+                .draw_line((0, row), (LCD_WIDTH as i32 - 1, row))
+                .unwrap();
+        }
+
+        for col in (0i32..LCD_WIDTH as i32).step_by(8) {
+            canvas
+                .draw_line((col, 0), (col, LCD_HEIGHT as i32 - 1))
+                .unwrap();
+        }
+    }
+
+    canvas.present();
+}
+
+/// Size of the VRAM viewer: the tiles in both banks, followed by the two tile maps
+const VRAM_VIEWER_WIDTH: u32 = 3 * TileMap::SIZE as u32;
+const VRAM_VIEWER_HEIGHT: u32 = TileMap::SIZE as u32;
+
+/// Renders the contents of VRAM to the debug window.
+///
+/// From left to right: the tiles in both banks (using BG palette 0), and the
+/// tile maps at 0x9800 and 0x9C00. The area shown on the LCD is outlined on the
+/// current BG map.
+fn render_vram_viewer(gameboy: &Gameboy, canvas: &mut Canvas<Window>, texture: &mut Texture) {
+    let images = [
+        gameboy.render_tiles(TilePalette::Bg(0)),
+        gameboy.render_tile_map(TileMap::Low),
+        gameboy.render_tile_map(TileMap::High),
+    ];
+
+    canvas.set_draw_color(Color::BLACK);
+    canvas.clear();
+
+    for (i, image) in images.iter().enumerate() {
+        let rect = Rect::new(
+            (i * TileMap::SIZE) as i32,
+            0,
+            image.width as u32,
+            image.height as u32,
+        );
+
+        texture.update(rect, &image.data, image.pitch()).unwrap();
+        canvas.copy(texture, rect, rect).unwrap();
+    }
 
-    // Once we've completed our texture operations, we need to copy the texture
-    // back to the canvas and then present to the screen.
-    canvas.copy(&texture, None, None).unwrap();
     canvas.present();
 }
 
+/// Describes the hidden layers and the selected sprite, for the window title.
+fn describe_layers(mask: &LayerMask, selected_sprite: Option<u8>) -> String {
+    let mut hidden = Vec::new();
+
+    if !mask.bg {
+        hidden.push("BG".to_string());
+    }
+
+    if !mask.window {
+        hidden.push("window".to_string());
+    }
+
+    if !mask.sprites {
+        hidden.push("sprites".to_string());
+    }
+
+    for idx in 0..LayerMask::SPRITE_COUNT {
+        if mask.hidden_sprites & 1 << idx != 0 {
+            hidden.push(format!("sprite {}", idx));
+        }
+    }
+
+    let mut description = String::new();
+
+    if !hidden.is_empty() {
+        description += &format!(" - hidden: {}", hidden.join(", "));
+    }
+
+    if let Some(idx) = selected_sprite {
+        description += &format!(" - selected sprite {}", idx);
+    }
+
+    description
+}
+
 /// Handles a single Gameboy frame.
 ///
 /// This advances the Gameboy for the number of CPU cycles in a single frame. Once the
@@ -218,7 +594,81 @@ fn new_persist_file(path: &PathBuf) -> std::fs::File {
         .unwrap()
 }
 
-fn gui(rom_file: PathBuf, scale: u32, mut speed: u8, boot_rom: bool, trace: bool, load: bool) {
+/// Persists cartridge RAM and RTC state to files next to the ROM.
+struct Persister {
+    ram: Option<File>,
+    rtc: Option<File>,
+}
+
+impl Persister {
+    /// Load persisted state, if any, into the `Gameboy`
+    fn new(gameboy: &mut Gameboy, rom_file: &Path) -> Self {
+        let ram_path = &rom_file.with_extension("ram");
+        let rtc_path = &rom_file.with_extension("rtc");
+        let mut ram = None;
+        let mut rtc = None;
+
+        if gameboy.is_persist_required() {
+            let ram_state = std::fs::read(ram_path).ok();
+            let rtc_state = std::fs::read(rtc_path).ok();
+
+            gameboy
+                .unpersist(ram_state.as_ref(), rtc_state.as_ref())
+                .expect("Failed to load persisted data");
+
+            if gameboy.is_persist_ram() {
+                ram = Some(new_persist_file(ram_path));
+            }
+
+            if gameboy.is_persist_rtc() {
+                rtc = Some(new_persist_file(rtc_path));
+            }
+        }
+
+        Self { ram, rtc }
+    }
+
+    /// If state needs to be persisted, write it out. Call this at the end of each frame.
+    fn persist(&mut self, gameboy: &mut Gameboy) {
+        if !gameboy.is_persist_required() {
+            return;
+        }
+
+        let state = gameboy.persist().expect("Failed to persist state");
+
+        if let Some(state) = state.ram {
+            let file = self.ram.as_mut().unwrap();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.write_all(&state).unwrap();
+        }
+
+        if let Some(state) = state.rtc {
+            let file = self.rtc.as_mut().unwrap();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.write_all(&state).unwrap();
+        }
+    }
+}
+
+fn new_recording(path: &PathBuf, sample_rate: u32) -> WavWriter<BufWriter<File>> {
+    log::info!("Recording audio to {}", path.display());
+    WavWriter::create(path, sample_rate).expect("Failed to create audio recording")
+}
+
+fn gui(
+    rom_file: PathBuf,
+    options: EmulatorOptions,
+    video_options: VideoOptions,
+    audio_options: AudioOptions,
+) {
+    let EmulatorOptions {
+        mut speed,
+        boot_rom,
+        trace,
+        load,
+        serial_device,
+    } = options;
+
     let rom_name = match rom_file.file_name() {
         None => None,
         Some(n) => Some(n.to_str().unwrap()),
@@ -228,8 +678,16 @@ fn gui(rom_file: PathBuf, scale: u32, mut speed: u8, boot_rom: bool, trace: bool
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
 
-    let width = LCD_WIDTH as u32 * scale;
-    let height = LCD_HEIGHT as u32 * scale;
+    // Setup audio playback, unless muted
+    let mut audio = if !audio_options.mute {
+        let audio_subsystem = sdl_context.audio().unwrap();
+        Some(AudioPlayer::new(&audio_subsystem, &audio_options))
+    } else {
+        None
+    };
+
+    let width = LCD_WIDTH as u32 * video_options.scale;
+    let height = LCD_HEIGHT as u32 * video_options.scale;
 
     // Setup an SDL2 Window
     let window = video_subsystem
@@ -242,28 +700,61 @@ fn gui(rom_file: PathBuf, scale: u32, mut speed: u8, boot_rom: bool, trace: bool
 
     // Convert the Window into a Canvas
     // This is what we will use to render content in the Window
-    // TODO: Add flag for software vs. GPU
-    let mut canvas = window.into_canvas().software().build().unwrap();
-
-    // Fix aspect ratio of canvas
-    canvas.set_logical_size(width, height).unwrap();
+    let mut canvas = new_canvas(
+        window,
+        &video_options,
+        (LCD_WIDTH as u32, LCD_HEIGHT as u32),
+    );
 
     // Get a handle to the Canvas texture creator
     let texture_creator = canvas.texture_creator();
 
     // Create a Texture
     // We write raw pixel data here and copy it to the Canvas for rendering
-    let mut texture = texture_creator
-        .create_texture(
-            None,
-            TextureAccess::Target,
-            LCD_WIDTH as u32,
-            LCD_HEIGHT as u32,
-        )
-        .unwrap();
+    let mut texture = new_texture(&texture_creator);
+
+    // Optional debug window that shows the contents of VRAM
+    let mut vram_canvas = if video_options.vram_viewer {
+        let scale = (video_options.scale / 2).max(1);
+        let window = video_subsystem
+            .window(
+                &format!("{} - VRAM", rom_name),
+                VRAM_VIEWER_WIDTH * scale,
+                VRAM_VIEWER_HEIGHT * scale,
+            )
+            .allow_highdpi()
+            .resizable()
+            .build()
+            .unwrap();
+
+        Some(new_canvas(
+            window,
+            &video_options,
+            (VRAM_VIEWER_WIDTH, VRAM_VIEWER_HEIGHT),
+        ))
+    } else {
+        None
+    };
+
+    let vram_texture_creator = vram_canvas.as_ref().map(|canvas| canvas.texture_creator());
+    let mut vram_texture = vram_texture_creator.as_ref().map(|texture_creator| {
+        texture_creator
+            .create_texture_streaming(
+                PixelFormatEnum::RGBA32,
+                VRAM_VIEWER_WIDTH,
+                VRAM_VIEWER_HEIGHT,
+            )
+            .unwrap()
+    });
 
     let cartridge = get_cartridge(&rom_file, boot_rom);
 
+    // Without an explicit palette, pick the one a CGB would use for this game
+    let palette = video_options
+        .palette
+        .unwrap_or_else(|| DmgPalette::for_cartridge(&cartridge));
+    let color_correction = video_options.color_correction;
+
     let save_state_path = &rom_file.with_extension("state");
 
     let mut gameboy = if load {
@@ -276,32 +767,23 @@ fn gui(rom_file: PathBuf, scale: u32, mut speed: u8, boot_rom: bool, trace: bool
         Gameboy::init(cartridge, trace).unwrap()
     };
 
-    let ram_path = &rom_file.with_extension("ram");
-    let rtc_path = &rom_file.with_extension("rtc");
-    let mut ram_persist = None;
-    let mut rtc_persist = None;
-
-    // Load persisted state, if any, into the `Gameboy`
-    if gameboy.is_persist_required() {
-        let ram_state = std::fs::read(ram_path).ok();
-        let rtc_state = std::fs::read(rtc_path).ok();
-
-        gameboy
-            .unpersist(ram_state.as_ref(), rtc_state.as_ref())
-            .expect("Failed to load persisted data");
+    gameboy.set_dmg_palette(palette);
+    gameboy.set_color_correction(color_correction);
+    gameboy.set_pixel_format(PixelFormat::Xrgb8888);
 
-        if gameboy.is_persist_ram() {
-            ram_persist = Some(new_persist_file(ram_path));
-        }
+    let mut persister = Persister::new(&mut gameboy, &rom_file);
 
-        if gameboy.is_persist_rtc() {
-            rtc_persist = Some(new_persist_file(rtc_path));
-        }
+    // Plug in the link cable or printer, if any
+    if let Some(device) = serial_device {
+        gameboy.attach_serial_device(device);
     }
 
     let mut paused = false;
     let mut outline = false;
 
+    // Sprite that is shown/hidden with F7 (selected with F5/F6)
+    let mut selected_sprite: Option<u8> = None;
+
     // List of joypad events to push to the Gameboy
     let mut joypad_events = Vec::new();
 
@@ -313,6 +795,24 @@ fn gui(rom_file: PathBuf, scale: u32, mut speed: u8, boot_rom: bool, trace: bool
     let mut prev_speed = speed;
     let mut fast_forward = false;
 
+    // Audio recording always uses a fixed sample rate
+    let record_rate = audio
+        .as_ref()
+        .map(|audio| audio.freq())
+        .unwrap_or(AudioPlayer::SAMPLE_RATE as u32);
+    let record_path = audio_options
+        .record
+        .clone()
+        .unwrap_or_else(|| rom_file.with_extension("wav"));
+    let mut recorder = audio_options
+        .record
+        .as_ref()
+        .map(|path| new_recording(path, record_rate));
+
+    // Audio samples for the current frame
+    let mut samples: Vec<f32> = Vec::new();
+    let mut pcm: Vec<i16> = Vec::new();
+
     // Start the event loop
     let mut event_pump = sdl_context.event_pump().unwrap();
     'running: loop {
@@ -323,7 +823,25 @@ fn gui(rom_file: PathBuf, scale: u32, mut speed: u8, boot_rom: bool, trace: bool
 
         for event in event_pump.poll_iter() {
             match event {
+                Event::Window {
+                    window_id,
+                    win_event: WindowEvent::Close,
+                    ..
+                } if vram_canvas
+                    .as_ref()
+                    .is_some_and(|vram| vram.window().id() == window_id) =>
+                {
+                    // Close the VRAM viewer. The window is only destroyed once the
+                    // texture creator is dropped, so hide it first.
+                    if let Some(mut vram) = vram_canvas.take() {
+                        vram.window_mut().hide();
+                    }
+                }
                 Event::Quit { .. }
+                | Event::Window {
+                    win_event: WindowEvent::Close,
+                    ..
+                }
                 | Event::KeyDown {
                     keycode: Some(Keycode::Escape),
                     ..
@@ -389,6 +907,79 @@ fn gui(rom_file: PathBuf, scale: u32, mut speed: u8, boot_rom: bool, trace: bool
                 } => {
                     outline = !outline;
                 }
+                // Fullscreen
+                Event::KeyDown {
+                    keycode: Some(Keycode::F11),
+                    repeat: false,
+                    ..
+                } => {
+                    toggle_fullscreen(&mut canvas);
+                }
+                // Show/hide layers
+                Event::KeyDown {
+                    keycode: Some(key @ (Keycode::F1 | Keycode::F2 | Keycode::F3)),
+                    ..
+                } => {
+                    let mut mask = gameboy.layer_mask();
+                    match key {
+                        Keycode::F1 => mask.bg = !mask.bg,
+                        Keycode::F2 => mask.window = !mask.window,
+                        _ => mask.sprites = !mask.sprites,
+                    }
+                    gameboy.set_layer_mask(mask);
+                }
+                // Select a sprite
+                Event::KeyDown {
+                    keycode: Some(key @ (Keycode::F5 | Keycode::F6)),
+                    ..
+                } => {
+                    let count = LayerMask::SPRITE_COUNT;
+                    selected_sprite = Some(match (key, selected_sprite) {
+                        (Keycode::F5, Some(idx)) => (idx + count - 1) % count,
+                        (Keycode::F5, None) => count - 1,
+                        (_, Some(idx)) => (idx + 1) % count,
+                        (_, None) => 0,
+                    });
+                }
+                // Show/hide the selected sprite
+                Event::KeyDown {
+                    keycode: Some(Keycode::F7),
+                    ..
+                } => {
+                    if let Some(idx) = selected_sprite {
+                        let mut mask = gameboy.layer_mask();
+                        let hidden = mask.hidden_sprites & 1 << idx != 0;
+                        mask.set_sprite_visible(idx, hidden);
+                        gameboy.set_layer_mask(mask);
+                    }
+                }
+                // Mute/unmute an audio channel
+                Event::KeyDown {
+                    keycode:
+                        Some(key @ (Keycode::Num1 | Keycode::Num2 | Keycode::Num3 | Keycode::Num4)),
+                    ..
+                } => {
+                    let channel = match key {
+                        Keycode::Num1 => Channel::Square1,
+                        Keycode::Num2 => Channel::Square2,
+                        Keycode::Num3 => Channel::Wave,
+                        _ => Channel::Noise,
+                    };
+                    let muted = gameboy.channel_muted(channel);
+                    gameboy.set_channel_muted(channel, !muted);
+                }
+                // Start/stop audio recording
+                Event::KeyDown {
+                    keycode: Some(Keycode::R),
+                    repeat: false,
+                    ..
+                } => {
+                    if let Some(mut wav) = recorder.take() {
+                        wav.finish().expect("Failed to finish audio recording");
+                    } else {
+                        recorder = Some(new_recording(&record_path, record_rate));
+                    }
+                }
 
                 // Save state
                 Event::KeyDown {
@@ -407,7 +998,16 @@ fn gui(rom_file: PathBuf, scale: u32, mut speed: u8, boot_rom: bool, trace: bool
                     // Load a Gameboy from a save state
                     let cartridge = get_cartridge(&rom_file, boot_rom);
                     let data = std::fs::read(save_state_path).expect("Save state not found!");
+                    let device = gameboy.detach_serial_device();
                     gameboy = Gameboy::load(&data, cartridge).unwrap();
+                    gameboy.set_dmg_palette(palette);
+                    gameboy.set_color_correction(color_correction);
+                    gameboy.set_pixel_format(PixelFormat::Xrgb8888);
+
+                    // Keep the link cable or printer plugged in
+                    if let Some(device) = device {
+                        gameboy.attach_serial_device(device);
+                    }
                 }
 
                 // Joypad event
@@ -420,6 +1020,17 @@ fn gui(rom_file: PathBuf, scale: u32, mut speed: u8, boot_rom: bool, trace: bool
             }
         }
 
+        // Audio is muted in fast-forward mode. While recording, the Gameboy needs
+        // to produce samples at a fixed rate, so audio is only played back at 1x.
+        let playing = audio.is_some() && !fast_forward && (recorder.is_none() || speed == 1);
+
+        let sample_rate = match audio.as_ref() {
+            _ if recorder.is_some() => record_rate,
+            Some(audio) if playing => audio.rate(speed),
+            _ => 0,
+        };
+        gameboy.set_sample_rate(sample_rate);
+
         if !paused {
             // Render a single frame
             handle_frame(
@@ -430,48 +1041,273 @@ fn gui(rom_file: PathBuf, scale: u32, mut speed: u8, boot_rom: bool, trace: bool
                 outline,
             );
 
-            // If state needs to be persisted, do this at the end of each frame
-            if gameboy.is_persist_required() {
-                let state = gameboy.persist().expect("Failed to persist state");
-
-                if let Some(state) = state.ram {
-                    ram_persist
-                        .as_mut()
-                        .unwrap()
-                        .seek(SeekFrom::Start(0))
-                        .unwrap();
-                    ram_persist.as_mut().unwrap().write_all(&state).unwrap();
-                }
+            samples.clear();
+            gameboy.audio_samples(&mut samples);
+
+            if let Some(wav) = recorder.as_mut() {
+                pcm.clear();
+                pcm.extend(samples.iter().map(|sample| i16::from_f32(*sample)));
+                wav.write_samples(&pcm)
+                    .expect("Failed to write audio recording");
+            }
 
-                if let Some(state) = state.rtc {
-                    rtc_persist
-                        .as_mut()
-                        .unwrap()
-                        .seek(SeekFrom::Start(0))
-                        .unwrap();
-                    rtc_persist.as_mut().unwrap().write_all(&state).unwrap();
+            // Queue up the audio for this frame
+            if let Some(audio) = audio.as_mut() {
+                if playing {
+                    audio.push(&samples);
                 }
             }
+
+            // If state needs to be persisted, do this at the end of each frame
+            persister.persist(&mut gameboy);
+        }
+
+        if let (Some(vram), Some(texture)) = (vram_canvas.as_mut(), vram_texture.as_mut()) {
+            render_vram_viewer(&gameboy, vram, texture);
         }
 
         let elapsed = frame_start.elapsed();
 
         log::debug!("Frame time: {:?}", elapsed);
 
-        // Sleep for the rest of the frame
-        //
-        // TODO: Evaluate if we need VSYNC to avoid tearing on higher Hz displays
-        if elapsed < frame_duration {
-            sleeper.sleep(frame_duration - elapsed);
+        match audio.as_ref() {
+            Some(audio) if playing && !paused => {
+                // Wait for the audio queue to drain to the target latency
+                audio.wait(&sleeper);
+            }
+            _ => {
+                // Sleep for the rest of the frame
+                //
+                // TODO: Evaluate if we need VSYNC to avoid tearing on higher Hz displays
+                if elapsed < frame_duration {
+                    sleeper.sleep(frame_duration - elapsed);
+                }
+            }
         }
 
         // Update FPS counter in window title
         let fps = fps_counter.frame();
-        let title = format!("{} - {:.2} fps ({}x speed)", rom_name, fps, speed);
+        let title = format!(
+            "{} - {:.2} fps ({}x speed){}",
+            rom_name,
+            fps,
+            speed,
+            describe_layers(&gameboy.layer_mask(), selected_sprite)
+        );
         canvas.window_mut().set_title(&title).unwrap();
     }
 }
 
+/// Runs two linked Gameboys, each in its own window.
+///
+/// Joypad input goes to the Gameboy whose window has focus. Only the first
+/// Gameboy's audio is played back.
+fn link_gui(
+    rom_files: [PathBuf; 2],
+    video_options: VideoOptions,
+    boot_rom: bool,
+    audio_options: AudioOptions,
+) {
+    let rom_names: Vec<&str> = rom_files
+        .iter()
+        .map(|f| {
+            f.file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or("Unknown ROM")
+        })
+        .collect();
+
+    let sdl_context = sdl2::init().unwrap();
+    let video_subsystem = sdl_context.video().unwrap();
+
+    // Setup audio playback, unless muted
+    let mut audio = if !audio_options.mute {
+        let audio_subsystem = sdl_context.audio().unwrap();
+        Some(AudioPlayer::new(&audio_subsystem, &audio_options))
+    } else {
+        None
+    };
+
+    let width = LCD_WIDTH as u32 * video_options.scale;
+    let height = LCD_HEIGHT as u32 * video_options.scale;
+
+    // Place the two windows side-by-side in the middle of the display
+    let (x, y) = match video_subsystem.display_bounds(0) {
+        Ok(bounds) => (
+            bounds.x() + (bounds.width() as i32 - 2 * width as i32) / 2,
+            bounds.y() + (bounds.height() as i32 - height as i32) / 2,
+        ),
+        Err(_) => (0, 0),
+    };
+
+    let mut canvases: Vec<Canvas<Window>> = (0..2)
+        .map(|i| {
+            let window = video_subsystem
+                .window(rom_names[i], width, height)
+                .position(x + i as i32 * width as i32, y)
+                .allow_highdpi()
+                .resizable()
+                .build()
+                .unwrap();
+
+            new_canvas(
+                window,
+                &video_options,
+                (LCD_WIDTH as u32, LCD_HEIGHT as u32),
+            )
+        })
+        .collect();
+
+    let texture_creators: Vec<_> = canvases.iter().map(|c| c.texture_creator()).collect();
+    let mut textures: Vec<Texture> = texture_creators.iter().map(new_texture).collect();
+
+    let window_ids: Vec<u32> = canvases.iter().map(|c| c.window().id()).collect();
+
+    let mut gameboys: Vec<Gameboy> = rom_files
+        .iter()
+        .map(|f| {
+            let mut gameboy = Gameboy::init(get_cartridge(f, boot_rom), false).unwrap();
+            gameboy.set_pixel_format(PixelFormat::Xrgb8888);
+            gameboy
+        })
+        .collect();
+
+    // Both Gameboys would write to the same save files if running the same ROM
+    let mut persisters: Vec<Option<Persister>> = Vec::new();
+    for (i, gameboy) in gameboys.iter_mut().enumerate() {
+        if i == 1 && rom_files[0] == rom_files[1] {
+            log::warn!(
+                "Both Gameboys are running the same ROM; not persisting RAM for the second one"
+            );
+            persisters.push(None);
+        } else {
+            persisters.push(Some(Persister::new(gameboy, &rom_files[i])));
+        }
+    }
+
+    let second = gameboys.pop().unwrap();
+    let first = gameboys.pop().unwrap();
+    let mut link = Link::new(first, second);
+
+    let mut paused = false;
+    let mut outline = false;
+
+    // List of joypad events to push to each Gameboy
+    let mut joypad_events: [Vec<JoypadEvent>; 2] = [Vec::new(), Vec::new()];
+
+    let sleeper = spin_sleep::SpinSleeper::default();
+    let mut fps_counter = FpsCounter::new();
+    let frame_duration = Duration::from_nanos(Gameboy::FRAME_DURATION);
+
+    let mut samples: Vec<f32> = Vec::new();
+
+    let mut event_pump = sdl_context.event_pump().unwrap();
+    'running: loop {
+        let frame_start = Instant::now();
+
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. }
+                | Event::Window {
+                    win_event: WindowEvent::Close,
+                    ..
+                }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::Escape),
+                    ..
+                } => break 'running,
+
+                // Pause
+                Event::KeyDown {
+                    keycode: Some(Keycode::P),
+                    ..
+                } => {
+                    paused = !paused;
+                }
+                // Outline
+                Event::KeyDown {
+                    keycode: Some(Keycode::O),
+                    ..
+                } => {
+                    outline = !outline;
+                }
+                // Fullscreen (window with focus)
+                Event::KeyDown {
+                    keycode: Some(Keycode::F11),
+                    repeat: false,
+                    window_id,
+                    ..
+                } => {
+                    if let Some(idx) = window_ids.iter().position(|id| *id == window_id) {
+                        toggle_fullscreen(&mut canvases[idx]);
+                    }
+                }
+
+                // Joypad event
+                Event::KeyDown { window_id, .. } | Event::KeyUp { window_id, .. } => {
+                    let idx = window_ids.iter().position(|id| *id == window_id);
+
+                    if let (Some(idx), Some(e)) = (idx, event_to_joypad(event)) {
+                        joypad_events[idx].push(e);
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        if let Some(audio) = audio.as_ref() {
+            link.gameboys()[0].set_sample_rate(audio.rate(1));
+        }
+
+        if !paused {
+            let [events_1, events_2] = &joypad_events;
+            let frames = link.frame([Some(events_1), Some(events_2)]);
+
+            for (i, frame_buffer) in frames.iter().enumerate() {
+                render_frame(frame_buffer, &mut canvases[i], &mut textures[i], outline);
+            }
+
+            for events in joypad_events.iter_mut() {
+                events.clear();
+            }
+
+            if let Some(audio) = audio.as_mut() {
+                samples.clear();
+                link.gameboys()[0].audio_samples(&mut samples);
+                audio.push(&samples);
+            }
+
+            for (gameboy, persister) in link.gameboys().iter_mut().zip(persisters.iter_mut()) {
+                if let Some(persister) = persister {
+                    persister.persist(gameboy);
+                }
+            }
+        }
+
+        let elapsed = frame_start.elapsed();
+
+        match audio.as_ref() {
+            Some(audio) if !paused => {
+                // Wait for the audio queue to drain to the target latency
+                audio.wait(&sleeper);
+            }
+            _ => {
+                // Sleep for the rest of the frame
+                if elapsed < frame_duration {
+                    sleeper.sleep(frame_duration - elapsed);
+                }
+            }
+        }
+
+        // Update FPS counter in window titles
+        let fps = fps_counter.frame();
+        for (canvas, rom_name) in canvases.iter_mut().zip(rom_names.iter()) {
+            let title = format!("{} - {:.2} fps (linked)", rom_name, fps);
+            canvas.window_mut().set_title(&title).unwrap();
+        }
+    }
+}
+
 fn get_cartridge(path: &PathBuf, boot_rom: bool) -> Cartridge {
     let data = std::fs::read(path).expect("Failed to open ROM file");
     let cartridge = Cartridge::from_bytes(data, boot_rom);
@@ -487,17 +1323,134 @@ fn main() {
         Args::Run {
             rom_file,
             scale,
+            renderer,
+            integer_scale,
             speed,
             boot_rom,
             trace,
             load,
+            mute,
+            volume,
+            audio_latency,
+            record_audio,
+            link_listen,
+            link_connect,
+            printer,
+            palette,
+            color_correction,
+            vram_viewer,
         } => {
             if speed == 0 {
                 eprintln!("Error: Speed must be greater than 0");
                 return;
             }
 
-            gui(rom_file, scale, speed, boot_rom, trace, load);
+            if volume > 100 {
+                eprintln!("Error: Volume must be between 0 and 100");
+                return;
+            }
+
+            if audio_latency == 0 {
+                eprintln!("Error: Audio latency must be greater than 0");
+                return;
+            }
+
+            let palette = if palette == "auto" {
+                None
+            } else {
+                match palette.parse::<DmgPalette>() {
+                    Ok(palette) => Some(palette),
+                    Err(err) => {
+                        eprintln!("Error: {}", err);
+                        return;
+                    }
+                }
+            };
+
+            let audio_options = AudioOptions {
+                mute,
+                volume,
+                latency: audio_latency,
+                record: record_audio,
+            };
+
+            let serial_device: Option<Box<dyn SerialDevice>> = if let Some(port) = link_listen {
+                let link = TcpCable::listen(port).expect("Failed to accept link cable connection");
+                Some(Box::new(link))
+            } else if let Some(addr) = link_connect {
+                let link = TcpCable::connect(addr).expect("Failed to connect link cable");
+                Some(Box::new(link))
+            } else if let Some(dir) = printer {
+                let printer = Printer::new(dir).expect("Failed to create printer output directory");
+                Some(Box::new(printer))
+            } else {
+                None
+            };
+
+            let video_options = VideoOptions {
+                scale,
+                renderer,
+                integer_scale,
+                palette,
+                color_correction,
+                vram_viewer,
+            };
+
+            let options = EmulatorOptions {
+                speed,
+                boot_rom,
+                trace,
+                load,
+                serial_device,
+            };
+
+            gui(rom_file, options, video_options, audio_options);
+        }
+        Args::Link {
+            rom_file_1,
+            rom_file_2,
+            scale,
+            renderer,
+            integer_scale,
+            boot_rom,
+            mute,
+            volume,
+            audio_latency,
+        } => {
+            if volume > 100 {
+                eprintln!("Error: Volume must be between 0 and 100");
+                return;
+            }
+
+            if audio_latency == 0 {
+                eprintln!("Error: Audio latency must be greater than 0");
+                return;
+            }
+
+            let audio_options = AudioOptions {
+                mute,
+                volume,
+                latency: audio_latency,
+                record: None,
+            };
+
+            let video_options = VideoOptions {
+                scale,
+                renderer,
+                integer_scale,
+                // DMG palettes, color correction and the VRAM viewer are
+                // only supported by `run`
+                palette: None,
+                color_correction: ColorCorrection::None,
+                vram_viewer: false,
+            };
+
+            link_gui(
+                [rom_file_1, rom_file_2],
+                video_options,
+                boot_rom,
+                audio_options,
+            );
         }
         Args::Inspect { rom_file } => {
             for f in &rom_file {
@@ -0,0 +1,1616 @@
+//! Gameboy APU (sound) emulation
+//!
+//! # Overview
+//!
+//! The APU has four sound channels:
+//!
+//! 1. Square wave with frequency sweep and volume envelope (NR10-NR14)
+//! 2. Square wave with volume envelope (NR21-NR24)
+//! 3. Programmable wave, played back from wave RAM (NR30-NR34, 0xFF30-0xFF3F)
+//! 4. Noise, generated by a linear feedback shift register (NR41-NR44)
+//!
+//! Each channel produces a 4-bit digital value which is converted to an analog
+//! signal by the channel's DAC. The four analog signals are then panned to the
+//! left and right outputs via NR51, and scaled by the master volume in NR50.
+//!
+//! ## Frame sequencer
+//!
+//! Length counters, volume envelopes, and the channel 1 frequency sweep are
+//! clocked by the *frame sequencer*. The sequencer is driven by the falling
+//! edge of bit 4 of the DIV register (bit 5 in double-speed mode), which
+//! results in a 512 Hz clock:
+//!
+//! ```text
+//! Step   Length Ctr  Vol Env     Sweep
+//! ---------------------------------------
+//! 0      Clock       -           -
+//! 1      -           -           -
+//! 2      Clock       -           Clock
+//! 3      -           -           -
+//! 4      Clock       -           -
+//! 5      -           -           -
+//! 6      Clock       -           Clock
+//! 7      -           Clock       -
+//! ```
+//!
+//! ## Power
+//!
+//! Clearing bit 7 of NR52 powers off the APU. This clears every sound register
+//! and ignores all writes until the APU is powered back on. Wave RAM is not
+//! affected. On DMG, the length counters can still be written while the APU
+//! is off.
+//!
+//! ## Debugging
+//!
+//! Each channel can be muted individually, which removes it from the mix without
+//! affecting the emulated hardware state. For visualisation, per-channel *taps*
+//! can be enabled: these produce a separate mono sample stream of each channel's
+//! DAC output at the audio sample rate.
+use crate::blip::BlipBuf;
+use crate::cpu::Cpu;
+use crate::memory::{MemoryRead, MemoryWrite};
+
+/// Square wave duty cycle patterns (NRx1 bits 6-7)
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+    [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+    [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+    [0, 1, 1, 1, 1, 1, 1, 0], // 75%
+];
+
+/// Noise channel base divisors (NR43 bits 0-2)
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+/// Bits that always read back as 1 for each register in 0xFF10-0xFF26.
+///
+/// Write-only bits and unused registers read back as 1.
+const READ_MASKS: [u8; 23] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+];
+
+/// Length counter shared by all channels
+///
+/// When enabled, the counter is decremented at 256 Hz. Once it reaches 0,
+/// the channel is disabled.
+#[cfg_attr(feature = "save", derive(serde::Serialize), derive(serde::Deserialize))]
+struct LengthCounter {
+    counter: u16,
+    enabled: bool,
+
+    /// 64 for square and noise channels, 256 for the wave channel
+    max: u16,
+}
+
+impl LengthCounter {
+    fn new(max: u16) -> Self {
+        Self {
+            counter: 0,
+            enabled: false,
+            max,
+        }
+    }
+
+    /// Load the counter from the length bits in NRx1
+    fn load(&mut self, value: u8) {
+        self.counter = self.max - value as u16;
+    }
+
+    /// Reload the counter on channel trigger, if it has expired.
+    ///
+    /// `extra_clock` is set if the next frame sequencer step does not clock
+    /// the length counter.
+    fn trigger(&mut self, extra_clock: bool) {
+        if self.counter == 0 {
+            self.counter = self.max;
+
+            if self.enabled && extra_clock {
+                self.counter -= 1;
+            }
+        }
+    }
+
+    /// Update the enable flag from NRx4.
+    ///
+    /// Enabling the counter during the first half of a length period clocks
+    /// it once more. Returns `true` if this caused the counter to expire.
+    fn enable(&mut self, enabled: bool, extra_clock: bool) -> bool {
+        let was_enabled = self.enabled;
+        self.enabled = enabled;
+
+        if !was_enabled && enabled && extra_clock {
+            self.clock()
+        } else {
+            false
+        }
+    }
+
+    /// Returns `true` if the counter has expired on this clock.
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            self.counter == 0
+        } else {
+            false
+        }
+    }
+}
+
+/// The four APU sound channels
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum Channel {
+    Square1 = 0,
+    Square2,
+    Wave,
+    Noise,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 4] = [
+        Channel::Square1,
+        Channel::Square2,
+        Channel::Wave,
+        Channel::Noise,
+    ];
+}
+
+/// Snapshot of a channel's volume envelope
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EnvelopeState {
+    /// Initial volume (0-15)
+    pub initial: u8,
+    pub increase: bool,
+
+    /// Envelope period, in 64 Hz ticks (0 disables the envelope)
+    pub period: u8,
+}
+
+/// Snapshot of the state of a single channel, for debugging
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelState {
+    /// Channel is active (as reported in NR52)
+    pub enabled: bool,
+    pub dac_enabled: bool,
+
+    /// Raw frequency register value (NRx3/NRx4) for channels 1-3, or the
+    /// polynomial counter register (NR43) for channel 4
+    pub freq: u16,
+
+    /// Output frequency, in Hz
+    ///
+    /// For the noise channel, this is the rate at which the LFSR is clocked.
+    pub frequency: f32,
+
+    /// Current volume (0-15)
+    ///
+    /// For the wave channel, the NR32 volume code is mapped onto this range.
+    pub volume: u8,
+
+    /// Not present for the wave channel
+    pub envelope: Option<EnvelopeState>,
+
+    /// Duty cycle (0-3) and position in the duty cycle (0-7)
+    ///
+    /// Only present for the square channels.
+    pub duty: Option<(u8, u8)>,
+
+    /// Current digital output (0-15)
+    pub output: u8,
+}
+
+/// Volume envelope for the square and noise channels (NRx2)
+#[cfg_attr(feature = "save", derive(serde::Serialize), derive(serde::Deserialize))]
+struct Envelope {
+    initial: u8,
+    increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Self {
+            initial: 0,
+            increase: false,
+            period: 0,
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    fn write(&mut self, value: u8) {
+        self.initial = value >> 4;
+        self.increase = value & 1 << 3 != 0;
+        self.period = value & 0x07;
+    }
+
+    /// The channel DAC is enabled iff the upper 5 bits of NRx2 are non-zero
+    fn dac_enabled(&self) -> bool {
+        self.initial != 0 || self.increase
+    }
+
+    fn trigger(&mut self) {
+        self.timer = self.period;
+        self.volume = self.initial;
+    }
+
+    fn state(&self) -> EnvelopeState {
+        EnvelopeState {
+            initial: self.initial,
+            increase: self.increase,
+            period: self.period,
+        }
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+
+        if self.timer == 0 {
+            self.timer = self.period;
+
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+/// Frequency sweep unit for channel 1 (NR10)
+#[cfg_attr(feature = "save", derive(serde::Serialize), derive(serde::Deserialize))]
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    shadow: u16,
+    enabled: bool,
+
+    /// Set if a calculation in negate mode has occurred since the last trigger.
+    ///
+    /// Clearing the negate bit after this disables the channel.
+    negate_used: bool,
+}
+
+impl Sweep {
+    fn new() -> Self {
+        Self {
+            period: 0,
+            negate: false,
+            shift: 0,
+            timer: 0,
+            shadow: 0,
+            enabled: false,
+            negate_used: false,
+        }
+    }
+
+    /// Returns `true` if the write disables the channel.
+    fn write(&mut self, value: u8) -> bool {
+        self.period = (value >> 4) & 0x07;
+        self.shift = value & 0x07;
+
+        let negate = value & 1 << 3 != 0;
+        let disable = self.negate && !negate && self.negate_used;
+        self.negate = negate;
+
+        disable
+    }
+
+    fn reload_timer(&mut self) {
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    /// Compute the next frequency from the shadow register
+    fn calculate(&mut self) -> u16 {
+        let delta = self.shadow >> self.shift;
+
+        if self.negate {
+            self.negate_used = true;
+            self.shadow.wrapping_sub(delta)
+        } else {
+            self.shadow + delta
+        }
+    }
+}
+
+/// Square wave channel (channels 1 and 2)
+#[cfg_attr(feature = "save", derive(serde::Serialize), derive(serde::Deserialize))]
+struct SquareChannel {
+    enabled: bool,
+    duty: u8,
+    duty_pos: u8,
+    freq: u16,
+    timer: u32,
+    length: LengthCounter,
+    envelope: Envelope,
+
+    /// Only present for channel 1
+    sweep: Option<Sweep>,
+}
+
+impl SquareChannel {
+    fn new(sweep: bool) -> Self {
+        Self {
+            enabled: false,
+            duty: 0,
+            duty_pos: 0,
+            freq: 0,
+            timer: Self::period(0),
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            sweep: if sweep { Some(Sweep::new()) } else { None },
+        }
+    }
+
+    #[inline]
+    fn period(freq: u16) -> u32 {
+        (2048 - freq as u32) * 4
+    }
+
+    fn step(&mut self, mut cycles: u32) {
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = Self::period(self.freq);
+            self.duty_pos = (self.duty_pos + 1) & 0x07;
+        }
+
+        self.timer -= cycles;
+    }
+
+    /// Current digital output of the channel (0-15)
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+
+        DUTY_TABLE[self.duty as usize][self.duty_pos as usize] * self.envelope.volume
+    }
+
+    fn state(&self) -> ChannelState {
+        ChannelState {
+            enabled: self.enabled,
+            dac_enabled: self.envelope.dac_enabled(),
+            freq: self.freq,
+            frequency: Cpu::BASE_FREQ as f32 / (Self::period(self.freq) * 8) as f32,
+            volume: self.envelope.volume,
+            envelope: Some(self.envelope.state()),
+            duty: Some((self.duty, self.duty_pos)),
+            output: self.output(),
+        }
+    }
+
+    fn write_sweep(&mut self, value: u8) {
+        if let Some(sweep) = &mut self.sweep {
+            if sweep.write(value) {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn write_length(&mut self, value: u8) {
+        self.duty = value >> 6;
+        self.length.load(value & 0x3F);
+    }
+
+    fn write_envelope(&mut self, value: u8) {
+        self.envelope.write(value);
+
+        if !self.envelope.dac_enabled() {
+            self.enabled = false;
+        }
+    }
+
+    fn write_freq_lower(&mut self, value: u8) {
+        self.freq = self.freq & 0x700 | value as u16;
+    }
+
+    fn write_control(&mut self, value: u8, extra_clock: bool) {
+        self.freq = self.freq & 0xFF | (value as u16 & 0x07) << 8;
+
+        if self.length.enable(value & 1 << 6 != 0, extra_clock) {
+            self.enabled = false;
+        }
+
+        if value & 1 << 7 != 0 {
+            self.trigger(extra_clock);
+        }
+    }
+
+    fn trigger(&mut self, extra_clock: bool) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger(extra_clock);
+        self.timer = Self::period(self.freq);
+        self.envelope.trigger();
+
+        if let Some(sweep) = &mut self.sweep {
+            sweep.shadow = self.freq;
+            sweep.negate_used = false;
+            sweep.reload_timer();
+            sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+
+            // Overflow check is performed immediately if shift is non-zero
+            if sweep.shift != 0 && sweep.calculate() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        let sweep = match &mut self.sweep {
+            Some(sweep) => sweep,
+            None => return,
+        };
+
+        if sweep.timer > 0 {
+            sweep.timer -= 1;
+        }
+
+        if sweep.timer != 0 {
+            return;
+        }
+
+        sweep.reload_timer();
+
+        if !sweep.enabled || sweep.period == 0 {
+            return;
+        }
+
+        let freq = sweep.calculate();
+
+        if freq > 2047 {
+            self.enabled = false;
+        } else if sweep.shift != 0 {
+            sweep.shadow = freq;
+            self.freq = freq;
+
+            // Run the overflow check again with the new frequency
+            if sweep.calculate() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+}
+
+/// Programmable wave channel (channel 3)
+#[cfg_attr(feature = "save", derive(serde::Serialize), derive(serde::Deserialize))]
+struct WaveChannel {
+    enabled: bool,
+    dac_enabled: bool,
+
+    /// Volume code (NR32 bits 5-6)
+    volume: u8,
+    freq: u16,
+    timer: u32,
+    length: LengthCounter,
+
+    /// Wave RAM (0xFF30-0xFF3F)
+    ///
+    /// Holds 32 4-bit samples, upper nibble first.
+    ram: [u8; 16],
+
+    /// Index of the current sample in wave RAM
+    position: u8,
+
+    /// Last sample read from wave RAM
+    sample: u8,
+}
+
+impl WaveChannel {
+    fn new() -> Self {
+        Self {
+            enabled: false,
+            dac_enabled: false,
+            volume: 0,
+            freq: 0,
+            timer: Self::period(0),
+            length: LengthCounter::new(256),
+            ram: [0; 16],
+            position: 0,
+            sample: 0,
+        }
+    }
+
+    #[inline]
+    fn period(freq: u16) -> u32 {
+        (2048 - freq as u32) * 2
+    }
+
+    fn step(&mut self, mut cycles: u32) {
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = Self::period(self.freq);
+
+            if self.enabled {
+                self.position = (self.position + 1) & 0x1F;
+                let byte = self.ram[self.position as usize / 2];
+                self.sample = if self.position & 1 == 0 {
+                    byte >> 4
+                } else {
+                    byte & 0x0F
+                };
+            }
+        }
+
+        self.timer -= cycles;
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+
+        match self.volume {
+            0 => 0,
+            1 => self.sample,
+            2 => self.sample >> 1,
+            3 => self.sample >> 2,
+            _ => unreachable!(),
+        }
+    }
+
+    fn state(&self) -> ChannelState {
+        ChannelState {
+            enabled: self.enabled,
+            dac_enabled: self.dac_enabled,
+            freq: self.freq,
+            frequency: Cpu::BASE_FREQ as f32 / (Self::period(self.freq) * 32) as f32,
+            volume: [0, 15, 7, 3][self.volume as usize],
+            envelope: None,
+            duty: None,
+            output: self.output(),
+        }
+    }
+
+    fn write_dac(&mut self, value: u8) {
+        self.dac_enabled = value & 1 << 7 != 0;
+
+        if !self.dac_enabled {
+            self.enabled = false;
+        }
+    }
+
+    fn write_freq_lower(&mut self, value: u8) {
+        self.freq = self.freq & 0x700 | value as u16;
+    }
+
+    fn write_control(&mut self, value: u8, extra_clock: bool) {
+        self.freq = self.freq & 0xFF | (value as u16 & 0x07) << 8;
+
+        if self.length.enable(value & 1 << 6 != 0, extra_clock) {
+            self.enabled = false;
+        }
+
+        if value & 1 << 7 != 0 {
+            self.enabled = self.dac_enabled;
+            self.length.trigger(extra_clock);
+
+            // The first sample is delayed by a few cycles after trigger
+            self.timer = Self::period(self.freq) + 6;
+            self.position = 0;
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    /// Index into wave RAM that the CPU accesses for a given address.
+    ///
+    /// While the channel is playing, the CPU can only access the byte that
+    /// is currently being played back.
+    fn ram_index(&self, addr: u16) -> usize {
+        if self.enabled {
+            self.position as usize / 2
+        } else {
+            (addr - 0xFF30) as usize
+        }
+    }
+}
+
+/// Noise channel (channel 4)
+#[cfg_attr(feature = "save", derive(serde::Serialize), derive(serde::Deserialize))]
+struct NoiseChannel {
+    enabled: bool,
+
+    /// NR43
+    clock_shift: u8,
+    width_mode: bool,
+    divisor_code: u8,
+
+    timer: u32,
+    lfsr: u16,
+    length: LengthCounter,
+    envelope: Envelope,
+}
+
+impl NoiseChannel {
+    fn new() -> Self {
+        Self {
+            enabled: false,
+            clock_shift: 0,
+            width_mode: false,
+            divisor_code: 0,
+            timer: NOISE_DIVISORS[0],
+            lfsr: 0x7FFF,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+        }
+    }
+
+    #[inline]
+    fn period(&self) -> u32 {
+        NOISE_DIVISORS[self.divisor_code as usize] << self.clock_shift
+    }
+
+    fn step(&mut self, mut cycles: u32) {
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+
+            // The LFSR is not clocked at all for shifts 14 and 15
+            if self.clock_shift >= 14 {
+                continue;
+            }
+
+            let bit = (self.lfsr & 0x01) ^ ((self.lfsr >> 1) & 0x01);
+            self.lfsr = (self.lfsr >> 1) | bit << 14;
+
+            if self.width_mode {
+                // 7-bit mode: the XOR result is also placed in bit 6
+                self.lfsr = self.lfsr & !(1 << 6) | bit << 6;
+            }
+        }
+
+        self.timer -= cycles;
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled || self.lfsr & 0x01 != 0 {
+            0
+        } else {
+            self.envelope.volume
+        }
+    }
+
+    fn state(&self) -> ChannelState {
+        let nr43 = self.clock_shift << 4 | (self.width_mode as u8) << 3 | self.divisor_code;
+
+        ChannelState {
+            enabled: self.enabled,
+            dac_enabled: self.envelope.dac_enabled(),
+            freq: nr43 as u16,
+            frequency: Cpu::BASE_FREQ as f32 / self.period() as f32,
+            volume: self.envelope.volume,
+            envelope: Some(self.envelope.state()),
+            duty: None,
+            output: self.output(),
+        }
+    }
+
+    fn write_length(&mut self, value: u8) {
+        self.length.load(value & 0x3F);
+    }
+
+    fn write_envelope(&mut self, value: u8) {
+        self.envelope.write(value);
+
+        if !self.envelope.dac_enabled() {
+            self.enabled = false;
+        }
+    }
+
+    fn write_polynomial(&mut self, value: u8) {
+        self.clock_shift = value >> 4;
+        self.width_mode = value & 1 << 3 != 0;
+        self.divisor_code = value & 0x07;
+    }
+
+    fn write_control(&mut self, value: u8, extra_clock: bool) {
+        if self.length.enable(value & 1 << 6 != 0, extra_clock) {
+            self.enabled = false;
+        }
+
+        if value & 1 << 7 != 0 {
+            self.enabled = self.envelope.dac_enabled();
+            self.length.trigger(extra_clock);
+            self.timer = self.period();
+            self.envelope.trigger();
+            self.lfsr = 0x7FFF;
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+}
+
+/// Audio sample formats that can be produced by the APU
+pub trait Sample: Copy {
+    /// Convert from a normalized sample in the range [-1.0, 1.0]
+    fn from_f32(sample: f32) -> Self;
+}
+
+impl Sample for f32 {
+    #[inline]
+    fn from_f32(sample: f32) -> Self {
+        sample
+    }
+}
+
+impl Sample for i16 {
+    #[inline]
+    fn from_f32(sample: f32) -> Self {
+        (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+    }
+}
+
+/// Mono output of a single channel DAC, used for debugging
+struct ChannelTap {
+    buf: BlipBuf,
+    last: i16,
+}
+
+/// Resamples the APU output to a fixed sample rate.
+///
+/// Amplitude changes are fed into a band-limited buffer per output channel
+/// as they occur. A high-pass filter then removes the DC offset introduced
+/// by the channel DACs, as is done on real hardware.
+struct AudioOutput {
+    left: BlipBuf,
+    right: BlipBuf,
+
+    /// Per-channel outputs, if enabled
+    taps: Option<Vec<ChannelTap>>,
+
+    /// Clocks elapsed since the last frame was ended
+    time: u32,
+
+    /// Last (left, right) amplitudes fed into the buffers
+    last: (i16, i16),
+
+    /// High-pass filter state for the (left, right) outputs
+    capacitor: (f32, f32),
+    charge_factor: f32,
+
+    sample_rate: u32,
+    scratch: (Vec<i32>, Vec<i32>),
+}
+
+impl AudioOutput {
+    /// Maximum number of clocks in a single buffer frame.
+    ///
+    /// Frames are ended early if samples are not read often enough.
+    const MAX_FRAME_CLOCKS: u32 = Cpu::BASE_FREQ / 16;
+
+    fn new(sample_rate: u32, taps: bool) -> Self {
+        let mut output = Self {
+            left: BlipBuf::new(Cpu::BASE_FREQ, sample_rate),
+            right: BlipBuf::new(Cpu::BASE_FREQ, sample_rate),
+            taps: None,
+            time: 0,
+            last: (0, 0),
+            capacitor: (0.0, 0.0),
+            charge_factor: Self::charge_factor(sample_rate),
+            sample_rate,
+            scratch: (Vec::new(), Vec::new()),
+        };
+
+        output.set_taps(taps);
+        output
+    }
+
+    fn set_taps(&mut self, enabled: bool) {
+        if !enabled {
+            self.taps = None;
+        } else if self.taps.is_none() {
+            // Start the taps at the current position in the frame
+            let taps = (0..4)
+                .map(|_| {
+                    let mut buf = BlipBuf::new(Cpu::BASE_FREQ, self.sample_rate);
+                    buf.end_frame(self.time);
+                    ChannelTap { buf, last: 0 }
+                })
+                .collect();
+
+            self.taps = Some(taps);
+        }
+    }
+
+    #[inline]
+    fn charge_factor(sample_rate: u32) -> f32 {
+        0.999958f32.powf(Cpu::BASE_FREQ as f32 / sample_rate as f32)
+    }
+
+    /// Change the sample rate without dropping any buffered samples.
+    fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.end_frame();
+        self.left.set_rates(Cpu::BASE_FREQ, sample_rate);
+        self.right.set_rates(Cpu::BASE_FREQ, sample_rate);
+        for tap in self.taps.iter_mut().flatten() {
+            tap.buf.set_rates(Cpu::BASE_FREQ, sample_rate);
+        }
+        self.charge_factor = Self::charge_factor(sample_rate);
+        self.sample_rate = sample_rate;
+    }
+
+    /// Advance by `cycles` clocks, ending at the given (left, right) output
+    /// and per-channel outputs.
+    fn update(&mut self, cycles: u32, output: (i16, i16), channels: [i16; 4]) {
+        self.time += cycles;
+
+        for (tap, value) in self.taps.iter_mut().flatten().zip(channels.iter()) {
+            if *value != tap.last {
+                tap.buf
+                    .add_delta(self.time, *value as i32 - tap.last as i32);
+                tap.last = *value;
+            }
+        }
+
+        if output.0 != self.last.0 {
+            self.left
+                .add_delta(self.time, output.0 as i32 - self.last.0 as i32);
+        }
+
+        if output.1 != self.last.1 {
+            self.right
+                .add_delta(self.time, output.1 as i32 - self.last.1 as i32);
+        }
+
+        self.last = output;
+
+        if self.time >= Self::MAX_FRAME_CLOCKS {
+            self.end_frame();
+
+            // Drop the oldest samples if more than one second is buffered
+            let avail = self.left.samples_avail();
+            let max = self.sample_rate as usize;
+            if avail > max {
+                let mut discard = vec![0; avail - max];
+                self.left.read_samples(&mut discard);
+                self.right.read_samples(&mut discard);
+            }
+
+            for tap in self.taps.iter_mut().flatten() {
+                let avail = tap.buf.samples_avail();
+                if avail > max {
+                    let mut discard = vec![0; avail - max];
+                    tap.buf.read_samples(&mut discard);
+                }
+            }
+        }
+    }
+
+    fn end_frame(&mut self) {
+        self.left.end_frame(self.time);
+        self.right.end_frame(self.time);
+        for tap in self.taps.iter_mut().flatten() {
+            tap.buf.end_frame(self.time);
+        }
+        self.time = 0;
+    }
+
+    /// Read all available samples into `out` as interleaved stereo.
+    ///
+    /// Returns the number of stereo frames read.
+    fn read_samples<T: Sample>(&mut self, out: &mut Vec<T>) -> usize {
+        self.end_frame();
+
+        let count = self.left.samples_avail();
+        let (left, right) = &mut self.scratch;
+        left.resize(count, 0);
+        right.resize(count, 0);
+        self.left.read_samples(left);
+        self.right.read_samples(right);
+
+        out.reserve(count * 2);
+
+        for (l, r) in left.iter().zip(right.iter()) {
+            let l = *l as f32 / 32768.0;
+            let r = *r as f32 / 32768.0;
+
+            let out_l = l - self.capacitor.0;
+            let out_r = r - self.capacitor.1;
+            self.capacitor.0 = l - out_l * self.charge_factor;
+            self.capacitor.1 = r - out_r * self.charge_factor;
+
+            out.push(T::from_f32(out_l));
+            out.push(T::from_f32(out_r));
+        }
+
+        count
+    }
+
+    /// Read all available samples of a single channel into `out`.
+    ///
+    /// Returns the number of samples read.
+    fn read_channel_samples<T: Sample>(&mut self, channel: Channel, out: &mut Vec<T>) -> usize {
+        self.end_frame();
+
+        let tap = match &mut self.taps {
+            Some(taps) => &mut taps[channel as usize],
+            None => return 0,
+        };
+
+        let count = tap.buf.samples_avail();
+        let scratch = &mut self.scratch.0;
+        scratch.resize(count, 0);
+        tap.buf.read_samples(scratch);
+
+        out.extend(scratch.iter().map(|s| T::from_f32(*s as f32 / 32768.0)));
+
+        count
+    }
+}
+
+#[cfg_attr(feature = "save", derive(serde::Serialize), derive(serde::Deserialize))]
+pub struct Apu {
+    ch1: SquareChannel,
+    ch2: SquareChannel,
+    ch3: WaveChannel,
+    ch4: NoiseChannel,
+
+    /// Raw values written to 0xFF10-0xFF26
+    ///
+    /// Used to handle register reads. Write-only bits are masked on read.
+    regs: [u8; 23],
+
+    /// APU power (NR52 bit 7)
+    enabled: bool,
+
+    /// Next step of the frame sequencer (0-7)
+    frame_seq_step: u8,
+
+    /// Last value of the DIV bit that drives the frame sequencer
+    prev_div_bit: bool,
+
+    /// Resampled audio output, if a sample rate has been set
+    #[cfg_attr(feature = "save", serde(skip))]
+    audio: Option<AudioOutput>,
+
+    /// Bitmask of channels removed from the mix (bit 0: channel 1)
+    #[cfg_attr(feature = "save", serde(skip))]
+    muted: u8,
+
+    /// Generate per-channel samples alongside the audio output
+    #[cfg_attr(feature = "save", serde(skip))]
+    taps: bool,
+
+    cgb: bool,
+}
+
+impl Apu {
+    pub const BASE_ADDR: u16 = 0xFF10;
+    pub const LAST_ADDR: u16 = 0xFF26;
+    pub const WAVE_RAM_BASE_ADDR: u16 = 0xFF30;
+    pub const WAVE_RAM_LAST_ADDR: u16 = 0xFF3F;
+
+    /// CGB-only channel output registers
+    pub const PCM12_ADDR: u16 = 0xFF76;
+    pub const PCM34_ADDR: u16 = 0xFF77;
+
+    // Register addresses
+    const NR10_ADDR: u16 = 0xFF10;
+    const NR11_ADDR: u16 = 0xFF11;
+    const NR12_ADDR: u16 = 0xFF12;
+    const NR13_ADDR: u16 = 0xFF13;
+    const NR14_ADDR: u16 = 0xFF14;
+    const NR21_ADDR: u16 = 0xFF16;
+    const NR22_ADDR: u16 = 0xFF17;
+    const NR23_ADDR: u16 = 0xFF18;
+    const NR24_ADDR: u16 = 0xFF19;
+    const NR30_ADDR: u16 = 0xFF1A;
+    const NR31_ADDR: u16 = 0xFF1B;
+    const NR32_ADDR: u16 = 0xFF1C;
+    const NR33_ADDR: u16 = 0xFF1D;
+    const NR34_ADDR: u16 = 0xFF1E;
+    const NR41_ADDR: u16 = 0xFF20;
+    const NR42_ADDR: u16 = 0xFF21;
+    const NR43_ADDR: u16 = 0xFF22;
+    const NR44_ADDR: u16 = 0xFF23;
+    const NR50_ADDR: u16 = 0xFF24;
+    const NR51_ADDR: u16 = 0xFF25;
+    const NR52_ADDR: u16 = 0xFF26;
+
+    /// Scale factor for the per-channel DAC outputs (max: 15 * 2048 = 30720)
+    const DAC_SCALE: i16 = 2048;
+
+    pub fn new(cgb: bool) -> Self {
+        let mut apu = Self {
+            ch1: SquareChannel::new(true),
+            ch2: SquareChannel::new(false),
+            ch3: WaveChannel::new(),
+            ch4: NoiseChannel::new(),
+            regs: [0; 23],
+            enabled: true,
+            frame_seq_step: 0,
+            prev_div_bit: false,
+            audio: None,
+            muted: 0,
+            taps: false,
+            cgb,
+        };
+
+        // Master volume and panning as left by the boot ROM
+        apu.write(Self::NR50_ADDR, 0x77u8);
+        apu.write(Self::NR51_ADDR, 0xF3u8);
+
+        apu
+    }
+
+    /// Output silence for the given number of cycles without clocking the channels.
+    ///
+    /// This is used while the system clock is stopped (i.e., in STOP mode).
+    pub fn idle(&mut self, cycles: u16) {
+        if let Some(audio) = &mut self.audio {
+            audio.update(cycles as u32, (0, 0), [0; 4]);
+        }
+    }
+
+    /// Execute a single step of the APU.
+    ///
+    /// `div` is the current value of the DIV register, which is used to drive
+    /// the frame sequencer.
+    pub fn step(&mut self, cycles: u16, speed: bool, div: u8) {
+        // The frame sequencer ticks on the falling edge of DIV bit 4. In
+        // double-speed mode, DIV ticks twice as fast, so bit 5 is used instead.
+        let bit = if speed { 5 } else { 4 };
+        let div_bit = div & 1 << bit != 0;
+
+        if self.enabled && self.prev_div_bit && !div_bit {
+            self.clock_frame_sequencer();
+        }
+
+        self.prev_div_bit = div_bit;
+
+        // The channels are always clocked at the base 4 MiHz rate, which is
+        // what `cycles` is already scaled to.
+        let mut cycles = cycles as u32;
+
+        if !self.enabled {
+            if let Some(audio) = &mut self.audio {
+                audio.update(cycles, (0, 0), [0; 4]);
+            }
+
+            return;
+        }
+
+        if self.audio.is_none() {
+            self.ch1.step(cycles);
+            self.ch2.step(cycles);
+            self.ch3.step(cycles);
+            self.ch4.step(cycles);
+            return;
+        }
+
+        // If audio output is enabled, advance the channels up to the next point
+        // where any of their outputs can change. This ensures that amplitude
+        // changes are fed into the output buffers at the exact clock they occur.
+        while cycles > 0 {
+            let n = cycles
+                .min(self.ch1.timer)
+                .min(self.ch2.timer)
+                .min(self.ch3.timer)
+                .min(self.ch4.timer);
+
+            self.ch1.step(n);
+            self.ch2.step(n);
+            self.ch3.step(n);
+            self.ch4.step(n);
+
+            let dacs = self.dac_outputs();
+            let output = self.mix(&dacs);
+            self.audio.as_mut().unwrap().update(n, output, dacs);
+
+            cycles -= n;
+        }
+    }
+
+    /// Set the sample rate of the audio output, in Hz.
+    ///
+    /// A rate of 0 disables audio output. If audio output is already enabled,
+    /// buffered samples are kept, so this can be called repeatedly to make
+    /// small adjustments to the rate.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        match &mut self.audio {
+            _ if sample_rate == 0 => self.audio = None,
+            Some(audio) => {
+                if audio.sample_rate != sample_rate {
+                    audio.set_sample_rate(sample_rate);
+                }
+            }
+            None => self.audio = Some(AudioOutput::new(sample_rate, self.taps)),
+        }
+    }
+
+    /// Returns the sample rate of the audio output, if enabled.
+    pub fn sample_rate(&self) -> Option<u32> {
+        self.audio.as_ref().map(|audio| audio.sample_rate)
+    }
+
+    /// Read all audio samples generated since the last call into `out`.
+    ///
+    /// Samples are interleaved stereo (left, right). Returns the number of
+    /// stereo frames read.
+    pub fn read_samples<T: Sample>(&mut self, out: &mut Vec<T>) -> usize {
+        match &mut self.audio {
+            Some(audio) => audio.read_samples(out),
+            None => 0,
+        }
+    }
+
+    /// Remove a channel from the mix, or add it back.
+    ///
+    /// This only affects the audio output: the channel keeps running and its
+    /// status is still reported in NR52.
+    pub fn set_channel_muted(&mut self, channel: Channel, muted: bool) {
+        if muted {
+            self.muted |= 1 << channel as u8;
+        } else {
+            self.muted &= !(1 << channel as u8);
+        }
+    }
+
+    pub fn channel_muted(&self, channel: Channel) -> bool {
+        self.muted & 1 << channel as u8 != 0
+    }
+
+    /// Returns a snapshot of the current state of a channel.
+    pub fn channel_state(&self, channel: Channel) -> ChannelState {
+        match channel {
+            Channel::Square1 => self.ch1.state(),
+            Channel::Square2 => self.ch2.state(),
+            Channel::Wave => self.ch3.state(),
+            Channel::Noise => self.ch4.state(),
+        }
+    }
+
+    /// Enable or disable per-channel sample output.
+    ///
+    /// Channel samples are only generated while audio output is enabled, and
+    /// use the same sample rate.
+    pub fn set_channel_taps(&mut self, enabled: bool) {
+        self.taps = enabled;
+
+        if let Some(audio) = &mut self.audio {
+            audio.set_taps(enabled);
+        }
+    }
+
+    pub fn channel_taps(&self) -> bool {
+        self.taps
+    }
+
+    /// Read all samples of a single channel generated since the last call into
+    /// `out`.
+    ///
+    /// Samples are mono and are not affected by panning, master volume, or
+    /// muting. Returns the number of samples read.
+    pub fn read_channel_samples<T: Sample>(&mut self, channel: Channel, out: &mut Vec<T>) -> usize {
+        match &mut self.audio {
+            Some(audio) => audio.read_channel_samples(channel, out),
+            None => 0,
+        }
+    }
+
+    fn clock_frame_sequencer(&mut self) {
+        match self.frame_seq_step {
+            0 | 4 => self.clock_length(),
+            2 | 6 => {
+                self.clock_length();
+                self.ch1.clock_sweep();
+            }
+            7 => {
+                self.ch1.envelope.clock();
+                self.ch2.envelope.clock();
+                self.ch4.envelope.clock();
+            }
+            _ => (),
+        }
+
+        self.frame_seq_step = (self.frame_seq_step + 1) & 0x07;
+    }
+
+    fn clock_length(&mut self) {
+        self.ch1.clock_length();
+        self.ch2.clock_length();
+        self.ch3.clock_length();
+        self.ch4.clock_length();
+    }
+
+    /// Returns `true` if the next frame sequencer step does not clock the
+    /// length counters.
+    #[inline]
+    fn length_extra_clock(&self) -> bool {
+        self.frame_seq_step & 1 == 1
+    }
+
+    /// Returns `true` if the APU is powered on (NR52 bit 7)
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Digital outputs of channels 1 (lower nibble) and 2 (upper nibble).
+    ///
+    /// Read from PCM12 (0xFF76) on CGB.
+    pub fn pcm12(&self) -> u8 {
+        self.ch2.output() << 4 | self.ch1.output()
+    }
+
+    /// Digital outputs of channels 3 (lower nibble) and 4 (upper nibble).
+    ///
+    /// Read from PCM34 (0xFF77) on CGB.
+    pub fn pcm34(&self) -> u8 {
+        self.ch4.output() << 4 | self.ch3.output()
+    }
+
+    /// Current output of the APU as a pair of signed (left, right) amplitudes.
+    ///
+    /// Each channel DAC maps its digital output (0-15) onto a signed range centered
+    /// at 0. The channels are then panned and mixed based on NR51 and scaled by
+    /// the master volume in NR50.
+    pub fn output(&self) -> (i16, i16) {
+        if !self.enabled {
+            return (0, 0);
+        }
+
+        self.mix(&self.dac_outputs())
+    }
+
+    /// Output of each channel DAC, scaled to the full `i16` range.
+    ///
+    /// A disabled DAC outputs 0.
+    fn dac_outputs(&self) -> [i16; 4] {
+        let dacs = [
+            (self.ch1.output(), self.ch1.envelope.dac_enabled()),
+            (self.ch2.output(), self.ch2.envelope.dac_enabled()),
+            (self.ch3.output(), self.ch3.dac_enabled),
+            (self.ch4.output(), self.ch4.envelope.dac_enabled()),
+        ];
+
+        let mut outputs = [0; 4];
+
+        for (out, (output, dac_enabled)) in outputs.iter_mut().zip(dacs.iter()) {
+            if *dac_enabled {
+                // Range: -15 to 15
+                *out = (*output as i16 * 2 - 15) * Self::DAC_SCALE;
+            }
+        }
+
+        outputs
+    }
+
+    /// Pan and mix the DAC outputs into a (left, right) pair.
+    fn mix(&self, dacs: &[i16; 4]) -> (i16, i16) {
+        let nr50 = self.regs[(Self::NR50_ADDR - Self::BASE_ADDR) as usize];
+        let nr51 = self.regs[(Self::NR51_ADDR - Self::BASE_ADDR) as usize];
+
+        let (mut left, mut right) = (0i32, 0i32);
+
+        for (i, dac) in dacs.iter().enumerate() {
+            if self.muted & 1 << i != 0 {
+                continue;
+            }
+
+            let analog = (*dac / Self::DAC_SCALE) as i32;
+
+            if nr51 & 1 << (i + 4) != 0 {
+                left += analog;
+            }
+
+            if nr51 & 1 << i != 0 {
+                right += analog;
+            }
+        }
+
+        // Master volume is in the range 1-8
+        let left_volume = ((nr50 >> 4) & 0x07) as i32 + 1;
+        let right_volume = (nr50 & 0x07) as i32 + 1;
+
+        // Max amplitude: 4 channels * 15 * 8 * 64 = 30720
+        let left = left * left_volume * 64;
+        let right = right * right_volume * 64;
+
+        (left as i16, right as i16)
+    }
+
+    /// Power off the APU and clear all registers.
+    fn power_off(&mut self) {
+        // On DMG, length counters are not affected by power
+        let lengths = [
+            self.ch1.length.counter,
+            self.ch2.length.counter,
+            self.ch3.length.counter,
+            self.ch4.length.counter,
+        ];
+
+        for addr in Self::NR10_ADDR..Self::NR52_ADDR {
+            self.write_register(addr, 0);
+        }
+
+        if !self.cgb {
+            self.ch1.length.counter = lengths[0];
+            self.ch2.length.counter = lengths[1];
+            self.ch3.length.counter = lengths[2];
+            self.ch4.length.counter = lengths[3];
+        }
+
+        self.ch1.enabled = false;
+        self.ch2.enabled = false;
+        self.ch3.enabled = false;
+        self.ch4.enabled = false;
+
+        self.enabled = false;
+    }
+
+    /// Power on the APU
+    fn power_on(&mut self) {
+        self.enabled = true;
+        self.frame_seq_step = 0;
+        self.ch1.duty_pos = 0;
+        self.ch2.duty_pos = 0;
+        self.ch3.sample = 0;
+    }
+
+    /// Write to a sound register in the range 0xFF10-0xFF25
+    fn write_register(&mut self, addr: u16, value: u8) {
+        let extra_clock = self.length_extra_clock();
+
+        match addr {
+            Self::NR10_ADDR => self.ch1.write_sweep(value),
+            Self::NR11_ADDR => self.ch1.write_length(value),
+            Self::NR12_ADDR => self.ch1.write_envelope(value),
+            Self::NR13_ADDR => self.ch1.write_freq_lower(value),
+            Self::NR14_ADDR => self.ch1.write_control(value, extra_clock),
+            Self::NR21_ADDR => self.ch2.write_length(value),
+            Self::NR22_ADDR => self.ch2.write_envelope(value),
+            Self::NR23_ADDR => self.ch2.write_freq_lower(value),
+            Self::NR24_ADDR => self.ch2.write_control(value, extra_clock),
+            Self::NR30_ADDR => self.ch3.write_dac(value),
+            Self::NR31_ADDR => self.ch3.length.load(value),
+            Self::NR32_ADDR => self.ch3.volume = (value >> 5) & 0x03,
+            Self::NR33_ADDR => self.ch3.write_freq_lower(value),
+            Self::NR34_ADDR => self.ch3.write_control(value, extra_clock),
+            Self::NR41_ADDR => self.ch4.write_length(value),
+            Self::NR42_ADDR => self.ch4.write_envelope(value),
+            Self::NR43_ADDR => self.ch4.write_polynomial(value),
+            Self::NR44_ADDR => self.ch4.write_control(value, extra_clock),
+            Self::NR50_ADDR | Self::NR51_ADDR => (),
+            _ => {
+                // Unused registers (0xFF15 and 0xFF1F)
+            }
+        }
+
+        let idx = (addr - Self::BASE_ADDR) as usize;
+        self.regs[idx] = value;
+    }
+}
+
+impl MemoryRead<u16, u8> for Apu {
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            Self::NR52_ADDR => {
+                let mut value = READ_MASKS[22];
+
+                if self.enabled {
+                    value |= 1 << 7;
+                }
+
+                // Lower 4 bits report which channels are currently on
+                let channels = [
+                    self.ch1.enabled,
+                    self.ch2.enabled,
+                    self.ch3.enabled,
+                    self.ch4.enabled,
+                ];
+
+                for (i, enabled) in channels.iter().enumerate() {
+                    if *enabled {
+                        value |= 1 << i;
+                    }
+                }
+
+                value
+            }
+            Self::BASE_ADDR..=Self::LAST_ADDR => {
+                let idx = (addr - Self::BASE_ADDR) as usize;
+                self.regs[idx] | READ_MASKS[idx]
+            }
+            Self::WAVE_RAM_BASE_ADDR..=Self::WAVE_RAM_LAST_ADDR => {
+                // On DMG, wave RAM can only be read while the channel is
+                // playing within a very small window of cycles. We don't
+                // emulate this, so treat it as always closed.
+                if self.ch3.enabled && !self.cgb {
+                    0xFF
+                } else {
+                    self.ch3.ram[self.ch3.ram_index(addr)]
+                }
+            }
+            _ => unreachable!("Unexpected read from APU addr 0x{:X}", addr),
+        }
+    }
+}
+
+impl MemoryWrite<u16, u8> for Apu {
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            Self::NR52_ADDR => {
+                let enabled = value & 1 << 7 != 0;
+
+                if self.enabled && !enabled {
+                    self.power_off();
+                } else if !self.enabled && enabled {
+                    self.power_on();
+                }
+            }
+            Self::BASE_ADDR..=Self::LAST_ADDR => {
+                if self.enabled {
+                    self.write_register(addr, value);
+                } else if !self.cgb {
+                    // On DMG, length counters can be written while powered off
+                    match addr {
+                        Self::NR11_ADDR => self.ch1.length.load(value & 0x3F),
+                        Self::NR21_ADDR => self.ch2.length.load(value & 0x3F),
+                        Self::NR31_ADDR => self.ch3.length.load(value),
+                        Self::NR41_ADDR => self.ch4.length.load(value & 0x3F),
+                        _ => (),
+                    }
+                }
+            }
+            Self::WAVE_RAM_BASE_ADDR..=Self::WAVE_RAM_LAST_ADDR => {
+                if self.ch3.enabled && !self.cgb {
+                    return;
+                }
+
+                let idx = self.ch3.ram_index(addr);
+                self.ch3.ram[idx] = value;
+            }
+            _ => unreachable!("Unexpected write to APU addr 0x{:X}", addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn register_read_masks() {
+        let mut apu = Apu::new(true);
+
+        apu.write(Apu::NR11_ADDR, 0xFFu8);
+        apu.write(Apu::NR13_ADDR, 0x12u8);
+        apu.write(Apu::NR30_ADDR, 0x00u8);
+
+        let nr11: u8 = apu.read(Apu::NR11_ADDR);
+        let nr13: u8 = apu.read(Apu::NR13_ADDR);
+        let nr30: u8 = apu.read(Apu::NR30_ADDR);
+        let unused: u8 = apu.read(0xFF15);
+
+        assert_eq!(nr11, 0xFF);
+        assert_eq!(nr13, 0xFF);
+        assert_eq!(nr30, 0x7F);
+        assert_eq!(unused, 0xFF);
+    }
+
+    #[test]
+    fn power_off_clears_registers() {
+        let mut apu = Apu::new(true);
+
+        apu.write(Apu::NR12_ADDR, 0xF0u8);
+        apu.write(Apu::NR14_ADDR, 0x80u8);
+        let nr52: u8 = apu.read(Apu::NR52_ADDR);
+        assert_eq!(nr52, 0xF1);
+
+        // Power off, then attempt to write while off
+        apu.write(Apu::NR52_ADDR, 0x00u8);
+        apu.write(Apu::NR12_ADDR, 0xF0u8);
+
+        let nr12: u8 = apu.read(Apu::NR12_ADDR);
+        let nr50: u8 = apu.read(Apu::NR50_ADDR);
+        let nr52: u8 = apu.read(Apu::NR52_ADDR);
+        assert_eq!(nr12, 0x00);
+        assert_eq!(nr50, 0x00);
+        assert_eq!(nr52, 0x70);
+    }
+
+    #[test]
+    fn length_counter_disables_channel() {
+        let mut apu = Apu::new(true);
+
+        // Length of 2, length enabled, trigger
+        apu.write(Apu::NR21_ADDR, 62u8);
+        apu.write(Apu::NR22_ADDR, 0xF0u8);
+        apu.write(Apu::NR24_ADDR, 0xC0u8);
+        assert!(apu.ch2.enabled);
+
+        // Each falling edge of DIV bit 4 clocks the frame sequencer
+        for _ in 0..4 {
+            apu.step(4, false, 1 << 4);
+            apu.step(4, false, 0);
+        }
+
+        assert!(!apu.ch2.enabled);
+    }
+
+    #[test]
+    fn sweep_overflow_disables_channel() {
+        let mut apu = Apu::new(true);
+
+        // Sweep period 1, addition, shift 1 with a frequency of 0x7FF
+        apu.write(Apu::NR10_ADDR, 0x11u8);
+        apu.write(Apu::NR12_ADDR, 0xF0u8);
+        apu.write(Apu::NR13_ADDR, 0xFFu8);
+        apu.write(Apu::NR14_ADDR, 0x87u8);
+
+        // Overflow check on trigger
+        assert!(!apu.ch1.enabled);
+    }
+
+    #[test]
+    fn muted_channel_removed_from_mix() {
+        let mut apu = Apu::new(true);
+
+        // Channel 2 at full volume, frequency 1024 (128 Hz)
+        apu.write(Apu::NR21_ADDR, 0x80u8);
+        apu.write(Apu::NR22_ADDR, 0xF0u8);
+        apu.write(Apu::NR23_ADDR, 0x00u8);
+        apu.write(Apu::NR24_ADDR, 0x84u8);
+
+        let state = apu.channel_state(Channel::Square2);
+        assert!(state.enabled);
+        assert_eq!(state.volume, 15);
+        assert_eq!(state.duty, Some((2, 0)));
+        assert_eq!(state.frequency, 128.0);
+
+        apu.set_channel_muted(Channel::Square2, true);
+        apu.set_channel_muted(Channel::Square1, true);
+        apu.set_channel_muted(Channel::Wave, true);
+        apu.set_channel_muted(Channel::Noise, true);
+        assert_eq!(apu.output(), (0, 0));
+
+        apu.set_channel_muted(Channel::Square2, false);
+        assert_ne!(apu.output(), (0, 0));
+    }
+
+    #[test]
+    fn pcm_registers() {
+        let mut apu = Apu::new(true);
+
+        // Channel 2 at volume 9, duty 75%
+        apu.write(Apu::NR21_ADDR, 0xC0u8);
+        apu.write(Apu::NR22_ADDR, 0x90u8);
+        apu.write(Apu::NR24_ADDR, 0x80u8);
+
+        // Advance to the second step of the duty cycle, which is high
+        apu.step(8192, false, 0);
+
+        assert_eq!(apu.pcm12(), 0x90);
+        assert_eq!(apu.pcm34(), 0x00);
+
+        apu.write(Apu::NR52_ADDR, 0x00u8);
+        assert_eq!(apu.pcm12(), 0x00);
+    }
+}
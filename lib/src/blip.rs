@@ -0,0 +1,196 @@
+//! Band-limited synthesis buffer
+//!
+//! The APU output only changes at discrete points in time, so it can be
+//! described as a series of amplitude *deltas* at the 4 MiHz APU clock. Each
+//! delta is added to the buffer as a band-limited step (a windowed sinc
+//! impulse that is later integrated), which resamples the signal to the output
+//! rate without the aliasing caused by picking the nearest sample.
+//!
+//! This is based on the approach used by Shay Green's `blip_buf` library.
+
+/// Bits of precision for the fractional part of a sample position
+const FRAC_BITS: u32 = 32;
+
+/// Number of sub-sample phases in the kernel table
+const PHASE_BITS: u32 = 5;
+const PHASES: usize = 1 << PHASE_BITS;
+
+/// Number of output samples each step is spread across
+const KERNEL_WIDTH: usize = 16;
+
+/// Fixed-point unit for kernel coefficients
+const KERNEL_UNIT: i64 = 1 << 15;
+
+pub struct BlipBuf {
+    /// Output samples per clock, as a 32.32 fixed-point number
+    factor: u64,
+
+    /// Position of the current frame start, relative to the first sample
+    /// in `buffer`, as a 32.32 fixed-point number
+    offset: u64,
+
+    /// Pending deltas, one per output sample
+    buffer: Vec<i32>,
+
+    /// Running sum of the deltas that have been read out
+    integrator: i32,
+
+    /// Band-limited step kernels for each phase
+    kernel: Vec<[i32; KERNEL_WIDTH]>,
+}
+
+impl BlipBuf {
+    /// Create a buffer that resamples from `clock_rate` to `sample_rate`.
+    pub fn new(clock_rate: u32, sample_rate: u32) -> Self {
+        Self {
+            factor: Self::factor(clock_rate, sample_rate),
+            offset: 0,
+            buffer: vec![0; KERNEL_WIDTH],
+            integrator: 0,
+            kernel: Self::build_kernel(),
+        }
+    }
+
+    #[inline]
+    fn factor(clock_rate: u32, sample_rate: u32) -> u64 {
+        ((sample_rate as u64) << FRAC_BITS) / clock_rate as u64
+    }
+
+    /// Change the resampling ratio.
+    ///
+    /// This only affects deltas added after the current frame, so it should be
+    /// called right after `end_frame`.
+    pub fn set_rates(&mut self, clock_rate: u32, sample_rate: u32) {
+        self.factor = Self::factor(clock_rate, sample_rate);
+    }
+
+    /// Build a windowed sinc kernel for each phase.
+    ///
+    /// Each row sums to exactly `KERNEL_UNIT`, so a step always integrates to
+    /// its full height and the output does not drift.
+    fn build_kernel() -> Vec<[i32; KERNEL_WIDTH]> {
+        // Cutoff slightly below Nyquist to leave room for the transition band
+        const CUTOFF: f64 = 0.45;
+
+        let half_width = (KERNEL_WIDTH / 2) as f64;
+        let mut kernel = Vec::with_capacity(PHASES);
+
+        for phase in 0..PHASES {
+            let frac = phase as f64 / PHASES as f64;
+            let mut taps = [0f64; KERNEL_WIDTH];
+
+            for (i, tap) in taps.iter_mut().enumerate() {
+                let x = i as f64 - half_width + 1.0 - frac;
+                let sinc = if x == 0.0 {
+                    2.0 * CUTOFF
+                } else {
+                    let t = std::f64::consts::PI * 2.0 * CUTOFF * x;
+                    t.sin() / (std::f64::consts::PI * x)
+                };
+
+                // Blackman window centered on the step
+                let w = (x + half_width) / KERNEL_WIDTH as f64;
+                let window = 0.42 - 0.5 * (2.0 * std::f64::consts::PI * w).cos()
+                    + 0.08 * (4.0 * std::f64::consts::PI * w).cos();
+
+                *tap = sinc * window;
+            }
+
+            let sum: f64 = taps.iter().sum();
+            let mut row = [0i32; KERNEL_WIDTH];
+            let mut total = 0;
+
+            for (i, tap) in taps.iter().enumerate() {
+                row[i] = (tap / sum * KERNEL_UNIT as f64).round() as i32;
+                total += row[i];
+            }
+
+            // Fold any rounding error into the center tap
+            row[KERNEL_WIDTH / 2 - 1] += KERNEL_UNIT as i32 - total;
+
+            kernel.push(row);
+        }
+
+        kernel
+    }
+
+    /// Add an amplitude change at `time` clocks from the start of the frame.
+    pub fn add_delta(&mut self, time: u32, delta: i32) {
+        let pos = time as u64 * self.factor + self.offset;
+        let index = (pos >> FRAC_BITS) as usize;
+        let phase = (pos >> (FRAC_BITS - PHASE_BITS)) as usize & (PHASES - 1);
+
+        if self.buffer.len() < index + KERNEL_WIDTH {
+            self.buffer.resize(index + KERNEL_WIDTH, 0);
+        }
+
+        let row = &self.kernel[phase];
+        let mut added = 0;
+
+        for (i, &k) in row.iter().enumerate() {
+            let value = ((delta as i64 * k as i64) / KERNEL_UNIT) as i32;
+            self.buffer[index + i] += value;
+            added += value;
+        }
+
+        // Keep the total step height exact
+        self.buffer[index + KERNEL_WIDTH / 2 - 1] += delta - added;
+    }
+
+    /// End the current frame after `clocks` clocks.
+    ///
+    /// All samples before this point become available for reading, and
+    /// subsequent deltas are relative to the end of this frame.
+    pub fn end_frame(&mut self, clocks: u32) {
+        self.offset += clocks as u64 * self.factor;
+
+        let needed = self.samples_avail() + KERNEL_WIDTH;
+        if self.buffer.len() < needed {
+            self.buffer.resize(needed, 0);
+        }
+    }
+
+    /// Number of samples that can be read
+    pub fn samples_avail(&self) -> usize {
+        (self.offset >> FRAC_BITS) as usize
+    }
+
+    /// Read up to `out.len()` samples and remove them from the buffer.
+    ///
+    /// Returns the number of samples read.
+    pub fn read_samples(&mut self, out: &mut [i32]) -> usize {
+        let count = out.len().min(self.samples_avail());
+
+        for (i, sample) in out.iter_mut().take(count).enumerate() {
+            self.integrator += self.buffer[i];
+            *sample = self.integrator;
+        }
+
+        self.buffer.drain(..count);
+        self.buffer.resize(self.buffer.len().max(KERNEL_WIDTH), 0);
+        self.offset -= (count as u64) << FRAC_BITS;
+
+        count
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn step_settles_to_delta() {
+        let mut blip = BlipBuf::new(4_194_304, 44_100);
+
+        blip.add_delta(1000, 10_000);
+        blip.end_frame(4_194_304 / 10);
+
+        let mut out = vec![0; blip.samples_avail()];
+        let count = blip.read_samples(&mut out);
+        assert!(count == 4409 || count == 4410);
+
+        // Output starts at 0 and settles to exactly the step height
+        assert_eq!(out[0], 0);
+        assert_eq!(out[count - 1], 10_000);
+    }
+}
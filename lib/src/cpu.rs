@@ -70,9 +70,28 @@ pub struct Cpu {
     pub stopped: bool,
     pub speed: bool,
 
+    /// Set when HALT is executed with IME disabled and an interrupt pending.
+    ///
+    /// The CPU does not halt, but fails to increment PC after reading the next
+    /// opcode, so that the byte after HALT is read twice.
+    halt_bug: bool,
+
+    /// Set when an illegal opcode is executed. The CPU stops responding
+    /// until it is reset.
+    pub locked: bool,
+
     /// Global interrupt enable flag (Interrupt Master Enable)
     ime: bool,
 
+    /// Set by EI. IME is only enabled after the instruction following EI.
+    ime_pending: bool,
+
+    /// Clock cycles elapsed on the memory bus during the current step
+    cycles: u16,
+
+    /// M-cycles elapsed during the current instruction
+    mcycles: u8,
+
     /// Trace all instructions executed to a file
     #[cfg_attr(feature = "save", serde(skip))]
     trace: Option<BufWriter<File>>,
@@ -98,9 +117,14 @@ impl Cpu {
             dma: DmaController::new(cgb),
             cgb,
             ime: false,
+            ime_pending: false,
+            cycles: 0,
+            mcycles: 0,
             halted: false,
             stopped: false,
             speed: false,
+            halt_bug: false,
+            locked: false,
             trace: None,
         }
     }
@@ -135,9 +159,14 @@ impl Cpu {
             dma,
             cgb,
             ime: false,
+            ime_pending: false,
+            cycles: 0,
+            mcycles: 0,
             halted: false,
             stopped: false,
             speed: false,
+            halt_bug: false,
+            locked: false,
             trace,
         })
     }
@@ -161,25 +190,80 @@ impl Cpu {
         self.memory.reset();
         self.dma = DmaController::new(self.cgb);
         self.ime = false;
+        self.ime_pending = false;
         self.halted = false;
         self.stopped = false;
         self.speed = false;
+        self.halt_bug = false;
+        self.locked = false;
     }
 
     /// Executes the next instruction and returns the number of cycles it
     /// took to complete.
+    ///
+    /// The rest of the system (PPU, timer, DMA, etc.) is advanced one M-cycle
+    /// at a time as the instruction executes, so that each memory access happens
+    /// at the correct cycle within the instruction.
+    ///
+    /// If an interrupt is pending, this step dispatches it instead of executing
+    /// an instruction. Dispatch takes 20 cycles and returns a `Nop`; the first
+    /// instruction of the ISR is executed on the next step.
     pub fn step(&mut self) -> (u16, Instruction) {
+        self.cycles = 0;
+
+        // After an illegal opcode, the CPU never executes anything again
+        if self.locked {
+            self.tick();
+            return (self.cycles, Instruction::Nop);
+        }
+
+        // In STOP mode, the system clock is stopped until a button is pressed.
+        // Only the audio output keeps running (silently).
+        if self.stopped {
+            if self.memory.joypad().read() & 0x0F == 0x0F {
+                self.memory.io_mut().apu_mut().idle(4);
+                return (4, Instruction::Nop);
+            }
+
+            self.stopped = false;
+        }
+
         // Check for pending interrupts before fetching the next instruction.
         // If an interrupt is serviced, PC will jump to the ISR address.
-        let int_cycles = self.service_interrupts();
+        if self.service_interrupts() {
+            return (self.cycles, Instruction::Nop);
+        }
+
+        // EI takes effect after the instruction that follows it. Note that
+        // a DI right after EI will clear IME again.
+        if self.ime_pending {
+            self.ime_pending = false;
+            self.ime = true;
+        }
 
         // If the CPU is halted, bail out
         if self.halted {
-            return (4, Instruction::Nop);
+            self.tick();
+            return (self.cycles, Instruction::Nop);
         }
 
         // Fetch and decode the next instruction at PC
-        let (inst, size, cycles) = self.fetch(None);
+        self.mcycles = 0;
+        let (inst, size, cycles) = if self.halt_bug {
+            // The byte after HALT is read twice. To handle this, execute the
+            // instruction as if it started one byte earlier, which also makes
+            // sure that CALL and RST push the correct return address.
+            let pc = self.registers.PC;
+            let decoded = self.fetch_cycle(pc, pc);
+
+            self.halt_bug = false;
+            self.registers.PC = pc.wrapping_sub(1);
+
+            decoded
+        } else {
+            let pc = self.registers.PC;
+            self.fetch_cycle(pc, pc.wrapping_add(1))
+        };
 
         if let Some(_) = &mut self.trace {
             self.trace(&inst);
@@ -187,30 +271,115 @@ impl Cpu {
 
         // Execute the instruction on this CPU
         let (jump, taken) = self.execute(inst);
-        let mut cycles = if !jump || jump && !taken {
+        let cycles = if !jump || jump && !taken {
             // For regular instructions and jumps that are *not* taken,
             // update the PC based on the size of this instruction
             self.registers.PC += size as u16;
-            cycles.not_taken() as u16
+            cycles.not_taken()
         } else {
             // For jumps that are taken, the PC is updated within `execute()`
-            cycles.taken() as u16
+            cycles.taken()
         };
 
-        if self.stopped {
-            // If we've entered STOP mode for a speed switch, block the CPU for a bit.
-            // Also, do not run through DMA.
-            cycles += 8200;
-        } else {
-            cycles += int_cycles as u16;
-            cycles += self.dma(cycles);
+        // Any remaining cycles are internal to the CPU (e.g., 16-bit arithmetic)
+        while self.mcycles < cycles / 4 {
+            self.tick();
+        }
+
+        if let Instruction::Stop = inst {
+            if !self.stopped {
+                // STOP was used for a speed switch, which blocks the CPU for a bit.
+                // DMA does not run during this time. The bus is still stepped an
+                // M-cycle at a time, so that timer and PPU edges are not skipped.
+                for _ in 0..8200 / 4 {
+                    self.step_bus(4);
+                }
+            }
         }
 
+        (self.cycles, inst)
+    }
+
+    /// Advance the rest of the system by a single M-cycle (4 clock cycles).
+    #[inline]
+    fn tick(&mut self) {
+        self.mcycles = self.mcycles.wrapping_add(1);
+        self.advance(4);
+    }
+
+    /// Advance DMA and the memory bus by the given number of CPU clock cycles.
+    fn advance(&mut self, cycles: u16) {
+        // HDMA halts the CPU while it runs, so any cycles it takes are added
+        // to the current step.
+        let cycles = cycles + self.dma(cycles);
+        self.step_bus(cycles);
+    }
+
+    /// Advance the memory bus by the given number of CPU clock cycles.
+    ///
+    /// In double-speed mode, this corresponds to half as many cycles on the bus.
+    fn step_bus(&mut self, mut cycles: u16) {
         if self.speed {
             cycles /= 2;
         }
 
-        (cycles, inst)
+        let mut interrupts = Vec::new();
+
+        // Internally, this executes a step for each of:
+        //
+        // 1. PPU
+        // 2. Timer
+        // 3. APU
+        // 4. Serial
+        // 5. RTC (if present)
+        self.memory.step(cycles, self.speed, &mut interrupts);
+
+        // Trigger any pending interrupts
+        for interrupt in interrupts {
+            self.trigger_interrupt(interrupt);
+        }
+
+        self.cycles += cycles;
+    }
+
+    /// Fetch and decode the instruction with the opcode at `addr`, and the
+    /// operands starting at `operand_addr`.
+    ///
+    /// Each byte of the instruction is read in its own M-cycle.
+    fn fetch_cycle(&mut self, addr: u16, operand_addr: u16) -> (Instruction, u8, Cycles) {
+        let mut data = [self.read_cycle(addr), 0, 0];
+
+        // The size of an instruction only depends on its opcode
+        let (_, size, cycles) = Instruction::decode(data);
+
+        // STOP is 2 bytes long, but only takes a single M-cycle
+        let fetch_cycles = size.min(cycles.not_taken() / 4);
+
+        for i in 1..size {
+            let addr = operand_addr.wrapping_add(i as u16 - 1);
+
+            data[i as usize] = if i < fetch_cycles {
+                self.read_cycle(addr)
+            } else {
+                self.memory.read(addr)
+            };
+        }
+
+        Instruction::decode(data)
+    }
+
+    /// Read a byte from memory, taking a single M-cycle.
+    #[inline]
+    fn read_cycle(&mut self, addr: u16) -> u8 {
+        self.tick();
+        self.memory.read(addr)
+    }
+
+    /// Write a byte to memory, taking a single M-cycle.
+    #[inline]
+    fn write_cycle(&mut self, addr: u16, value: u8) {
+        self.tick();
+        self.memory.write(addr, value);
     }
 
     /// Switch CPU speed
@@ -224,8 +393,6 @@ impl Cpu {
             self.memory.io_mut().prep_speed_switch = 0;
             self.speed = false;
         }
-
-        self.stopped = true;
     }
 
     #[inline]
@@ -282,58 +449,76 @@ impl Cpu {
         result
     }
 
-    /// Figure out which interrupts are pending and service the one with the
+    /// Figure out which interrupts are pending and dispatch the one with the
     /// highest priority.
     ///
-    /// Servicing an interrupt takes 20 clock cycles according to Pandocs.
+    /// Dispatching an interrupt takes 5 M-cycles (20 clock cycles): 2 internal
+    /// cycles, 2 cycles to push PC, and 1 cycle to jump to the ISR.
+    ///
+    /// The interrupt to dispatch is only decided after the upper byte of PC has
+    /// been pushed. If that push overwrites IE (i.e., SP is 0x0000) and disables
+    /// the pending interrupt, dispatch is cancelled and PC is set to 0x0000.
+    ///
+    /// Returns `true` if an interrupt was dispatched.
     ///
     /// See: pg. 27 of GB Programming Manual
-    fn service_interrupts(&mut self) -> u8 {
+    fn service_interrupts(&mut self) -> bool {
         let int_enable = self.memory.read(0xFFFF);
         let int_flags = self.memory.read(0xFF0F);
 
         // If no interrupts are pending, bail out now
-        if int_enable & int_flags == 0 {
-            return 0;
+        if int_enable & int_flags & 0x1F == 0 {
+            return false;
         }
 
         // If the CPU is currently halted and there is a pending interrupt,
         // leave HALT state, *even if IME is disabled*.
-        if self.halted {
-            self.halted = (int_enable & int_flags) == 0;
-        }
+        self.halted = false;
 
         // If the IME is disabled, do not process any interrupts
         if !self.ime {
-            return 0;
+            return false;
         }
 
-        // Iterate over each interrupt in priority order and service
-        // the first one
-        for int in 0..5 {
-            let enabled = (int_enable & 1 << int) != 0;
-            let pending = (int_flags & 1 << int) != 0;
+        // Disable interrupts
+        self.ime = false;
 
-            if enabled && pending {
-                // Disable interrupts
-                self.ime = false;
+        let pc = self.registers.PC;
+        self.mcycles = 0;
 
-                // Clear the pending flag
-                self.memory.write(0xFF0F, int_flags & !(1 << int));
+        self.tick();
+        self.tick();
 
-                // Push current PC to the stack
-                self.push(self.registers.PC);
+        // Push the upper byte of PC to the stack
+        self.registers.SP = self.registers.SP.wrapping_sub(1);
+        self.write_cycle(self.registers.SP, (pc >> 8) as u8);
 
-                // Compute the ISR address to jump to
-                let isr = (int << 3) + 0x40;
+        // Figure out which interrupt to service, if any
+        let int_enable: u8 = self.memory.read(0xFFFF);
 
-                self.registers.PC = isr;
+        // Push the lower byte of PC
+        self.registers.SP = self.registers.SP.wrapping_sub(1);
+        self.write_cycle(self.registers.SP, pc as u8);
 
-                break;
-            }
+        let int_flags: u8 = self.memory.read(0xFF0F);
+        let pending = int_enable & int_flags & 0x1F;
+
+        if pending == 0 {
+            // The interrupt was cancelled
+            self.registers.PC = 0x0000;
+        } else {
+            // Service the highest priority interrupt (lowest bit), and
+            // clear its pending flag
+            let int = pending.trailing_zeros() as u16;
+            self.memory.write(0xFF0F, int_flags & !(1 << int));
+
+            // Compute the ISR address to jump to
+            self.registers.PC = (int << 3) + 0x40;
         }
 
-        20
+        self.tick();
+
+        true
     }
 
     /// Trigger a particular interrupt
@@ -359,22 +544,40 @@ impl Cpu {
         match instruction {
             Nop => (),
             Halt => {
-                self.halted = true;
+                let int_enable: u8 = self.memory.read(0xFFFF);
+                let int_flags: u8 = self.memory.read(0xFF0F);
+
+                if !self.ime && int_enable & int_flags & 0x1F != 0 {
+                    // HALT bug: with IME disabled and an interrupt already pending,
+                    // the CPU does not halt.
+                    self.halt_bug = true;
+                } else {
+                    self.halted = true;
+                }
             }
             Stop => {
                 if self.cgb && self.memory.io().prep_speed_switch & 0x1 != 0 {
                     // Switch speed
                     self.speed_switch();
+                } else {
+                    // Enter low-power mode until a button is pressed. The LCD
+                    // is off during this time.
+                    self.stopped = true;
                 }
 
                 // Reset timer DIV register
                 self.memory.timer().write(0xFF04, 0);
             }
+            Illegal { opcode } => {
+                log::warn!("Illegal opcode {:#04X}: CPU locked up", opcode);
+                self.locked = true;
+            }
             Di => {
                 self.ime = false;
+                self.ime_pending = false;
             }
             Ei => {
-                self.ime = true;
+                self.ime_pending = true;
             }
 
             // Load
@@ -390,25 +593,28 @@ impl Cpu {
                 }
                 (Arg::Reg8(dst), Arg::Mem(src)) => {
                     let addr = self.registers.read(src);
-                    let value = self.memory.read(addr);
+                    let value = self.read_cycle(addr);
                     self.registers.write(dst, value);
                 }
                 (Arg::Mem(dst), Arg::Reg8(src)) => {
                     let addr = self.registers.read(dst);
-                    self.memory.write(addr, self.registers.read(src));
+                    self.write_cycle(addr, self.registers.read(src));
                 }
                 (Arg::Mem(dst), Arg::Imm8(src)) => {
                     let addr = self.registers.read(dst);
-                    self.memory.write(addr, src);
+                    self.write_cycle(addr, src);
                 }
                 (Arg::MemImm(dst), Arg::Reg8(src)) => {
-                    self.memory.write(dst, self.registers.read(src));
+                    self.write_cycle(dst, self.registers.read(src));
                 }
                 (Arg::MemImm(dst), Arg::Reg16(src)) => {
-                    self.memory.write(dst, self.registers.read(src));
+                    let value: u16 = self.registers.read(src);
+                    let [lower, upper] = value.to_le_bytes();
+                    self.write_cycle(dst, lower);
+                    self.write_cycle(dst.wrapping_add(1), upper);
                 }
                 (Arg::Reg8(dst), Arg::MemImm(src)) => {
-                    let value = self.memory.read(src);
+                    let value = self.read_cycle(src);
                     self.registers.write(dst, value);
                 }
                 (Arg::Reg16(Reg16::SP), Arg::Reg16(Reg16::HL)) => {
@@ -419,41 +625,42 @@ impl Cpu {
             },
             LdMemCA => {
                 let addr = 0xFF00 + self.registers.read(Reg8::C) as u16;
-                self.memory.write(addr, self.registers.read(Reg8::A));
+                self.write_cycle(addr, self.registers.read(Reg8::A));
             }
             LdAMemC => {
                 let addr = 0xFF00 + self.registers.read(Reg8::C) as u16;
-                self.registers.write(Reg8::A, self.memory.read(addr));
+                let value = self.read_cycle(addr);
+                self.registers.write(Reg8::A, value);
             }
             LdiAMemHl => {
                 let addr = self.registers.read(Reg16::HL);
-                let value = self.memory.read(addr);
+                let value = self.read_cycle(addr);
                 self.registers.write(Reg8::A, value);
                 self.registers.write(Reg16::HL, addr.wrapping_add(1));
             }
             LdiMemHlA => {
                 let addr = self.registers.read(Reg16::HL);
-                self.memory.write(addr, self.registers.read(Reg8::A));
+                self.write_cycle(addr, self.registers.read(Reg8::A));
                 self.registers.write(Reg16::HL, addr.wrapping_add(1));
             }
             LddAMemHl => {
                 let addr = self.registers.read(Reg16::HL);
-                let value = self.memory.read(addr);
+                let value = self.read_cycle(addr);
                 self.registers.write(Reg8::A, value);
                 self.registers.write(Reg16::HL, addr.wrapping_sub(1));
             }
             LddMemHlA => {
                 let addr = self.registers.read(Reg16::HL);
-                self.memory.write(addr, self.registers.read(Reg8::A));
+                self.write_cycle(addr, self.registers.read(Reg8::A));
                 self.registers.write(Reg16::HL, addr.wrapping_sub(1));
             }
             LdhA { offset } => {
-                let value = self.memory.read(0xFF00 + offset as u16);
+                let value = self.read_cycle(0xFF00 + offset as u16);
                 self.registers.write(Reg8::A, value);
             }
             Ldh { offset } => {
                 let a = self.registers.read(Reg8::A);
-                self.memory.write(0xFF00 + offset as u16, a);
+                self.write_cycle(0xFF00 + offset as u16, a);
             }
             LdHlSpImm8i { offset } | AddSpImm8i { offset } => {
                 let offset = offset as u16;
@@ -508,6 +715,11 @@ impl Cpu {
 
                 jump = true;
 
+                // Conditional returns take an extra cycle to check the condition
+                if cond != Cond::None {
+                    self.tick();
+                }
+
                 if ok {
                     // Pop address from the stack iff the condition is met
                     self.registers.PC = self.pop();
@@ -602,9 +814,9 @@ impl Cpu {
                     }
                     Arg::MemHl => {
                         let addr = self.registers.read(Reg16::HL);
-                        let value = self.memory.read(addr);
+                        let value = self.read_cycle(addr);
                         let value = (value << 4) | (value >> 4);
-                        self.memory.write(addr, value);
+                        self.write_cycle(addr, value);
                         value
                     }
                     _ => unreachable!("Unexpected dst: {}", dst),
@@ -622,7 +834,7 @@ impl Cpu {
                     Arg::Reg8(dst) => self.registers.read(dst),
                     Arg::MemHl => {
                         let addr = self.registers.read(Reg16::HL);
-                        self.memory.read(addr)
+                        self.read_cycle(addr)
                     }
                     _ => unreachable!("Unexpected dst: {}", dst),
                 };
@@ -672,7 +884,7 @@ impl Cpu {
             Arg::Reg8(dst) => self.registers.read(dst),
             Arg::MemHl => {
                 let addr = self.registers.read(Reg16::HL);
-                self.memory.read(addr)
+                self.read_cycle(addr)
             }
             _ => unreachable!("Unexpected dst: {}", dst),
         };
@@ -711,7 +923,7 @@ impl Cpu {
             Arg::Reg8(dst) => self.registers.write(dst, value),
             Arg::MemHl => {
                 let addr = self.registers.read(Reg16::HL);
-                self.memory.write(addr, value);
+                self.write_cycle(addr, value);
             }
             _ => unreachable!("Unexpected dst: {}", dst),
         }
@@ -731,7 +943,7 @@ impl Cpu {
             Arg::Reg8(dst) => self.registers.read(dst),
             Arg::MemHl => {
                 let addr = self.registers.read(Reg16::HL);
-                self.memory.read(addr)
+                self.read_cycle(addr)
             }
             _ => unreachable!("Unexpected dst: {}", dst),
         };
@@ -759,7 +971,7 @@ impl Cpu {
             Arg::Reg8(dst) => self.registers.write(dst, value),
             Arg::MemHl => {
                 let addr = self.registers.read(Reg16::HL);
-                self.memory.write(addr, value);
+                self.write_cycle(addr, value);
             }
             _ => unreachable!("Unexpected dst: {}", dst),
         }
@@ -774,8 +986,8 @@ impl Cpu {
     /// Helper that pops 2 bytes off the stack
     fn pop(&mut self) -> u16 {
         // Read upper and lower bytes from stack.
-        let lower = self.memory.read(self.registers.SP);
-        let upper = self.memory.read(self.registers.SP + 1);
+        let lower = self.read_cycle(self.registers.SP);
+        let upper = self.read_cycle(self.registers.SP + 1);
         let value = (upper as u16) << 8 | lower as u16;
 
         // Increment SP
@@ -785,15 +997,20 @@ impl Cpu {
     }
 
     /// Helper that pushes 2 bytes to the stack
+    ///
+    /// This takes 3 M-cycles: SP is decremented during an internal cycle
+    /// before the two writes.
     fn push(&mut self, value: u16) {
         let lower = value as u8;
         let upper = (value >> 8) as u8;
 
+        self.tick();
+
         // Write upper and lower bytes seperately to the stack.
         // We cannot use the `MemoryWrite` trait because it assumes
         // that memory addresses increase instead of decrease.
-        self.memory.write(self.registers.SP - 1, upper);
-        self.memory.write(self.registers.SP - 2, lower);
+        self.write_cycle(self.registers.SP - 1, upper);
+        self.write_cycle(self.registers.SP - 2, lower);
 
         // Decrement SP
         self.registers.SP -= 2;
@@ -807,7 +1024,7 @@ impl Cpu {
             Arg::Imm8(src) => src,
             Arg::MemHl => {
                 let addr = self.registers.read(Reg16::HL);
-                let val = self.memory.read(addr);
+                let val = self.read_cycle(addr);
                 val
             }
             _ => unreachable!("Unexpected src: {}", src),
@@ -833,7 +1050,7 @@ impl Cpu {
             Arg::Imm8(src) => src,
             Arg::MemHl => {
                 let addr = self.registers.read(Reg16::HL);
-                let val = self.memory.read(addr);
+                let val = self.read_cycle(addr);
                 val
             }
             _ => unreachable!("Unexpected src: {}", src),
@@ -885,7 +1102,7 @@ impl Cpu {
             Arg::Imm8(src) => src,
             Arg::MemHl => {
                 let addr = self.registers.read(Reg16::HL);
-                let val = self.memory.read(addr);
+                let val = self.read_cycle(addr);
                 val
             }
             _ => unreachable!("Unexpected src: {}", src),
@@ -914,7 +1131,7 @@ impl Cpu {
             Arg::Imm8(src) => src,
             Arg::MemHl => {
                 let addr = self.registers.read(Reg16::HL);
-                let val = self.memory.read(addr);
+                let val = self.read_cycle(addr);
                 val
             }
             _ => unreachable!("Unexpected src: {}", src),
@@ -948,7 +1165,7 @@ impl Cpu {
             Arg::Imm8(src) => src,
             Arg::MemHl => {
                 let addr = self.registers.read(Reg16::HL);
-                let val = self.memory.read(addr);
+                let val = self.read_cycle(addr);
                 val
             }
             _ => unreachable!("Unexpected src: {}", src),
@@ -995,10 +1212,10 @@ impl Cpu {
             }
             Arg::MemHl => {
                 let addr = self.registers.read(Reg16::HL);
-                let curr = self.memory.read(addr);
+                let curr = self.read_cycle(addr);
                 let result = curr.wrapping_add(1);
                 half_carry = curr.half_carry(1);
-                self.memory.write(addr, result);
+                self.write_cycle(addr, result);
                 result as u16
             }
             _ => unreachable!("Unexpected dst: {}", dst),
@@ -1036,13 +1253,13 @@ impl Cpu {
             }
             Arg::MemHl => {
                 let addr = self.registers.read(Reg16::HL);
-                let curr = self.memory.read(addr);
+                let curr = self.read_cycle(addr);
 
                 // If lower nibble == 0, set the half-carry bit
                 half_carry = curr & 0x0F == 0;
 
                 let result = curr.wrapping_sub(1);
-                self.memory.write(addr, result);
+                self.write_cycle(addr, result);
 
                 result as u16
             }
@@ -1062,7 +1279,7 @@ impl Cpu {
             Arg::Reg8(dst) => self.registers.read(dst),
             Arg::MemHl => {
                 let addr = self.registers.read(Reg16::HL);
-                self.memory.read(addr)
+                self.read_cycle(addr)
             }
             _ => unreachable!("Unexpected dst: {}", dst),
         };
@@ -1079,7 +1296,7 @@ impl Cpu {
             Arg::Reg8(dst) => self.registers.write(dst, result),
             Arg::MemHl => {
                 let addr = self.registers.read(Reg16::HL);
-                self.memory.write(addr, result);
+                self.write_cycle(addr, result);
             }
             _ => unreachable!("Unexpected dst: {}", dst),
         }
@@ -1166,10 +1383,19 @@ mod test {
         cpu.trigger_interrupt(Interrupt::Vblank);
         cpu.trigger_interrupt(Interrupt::LcdStat);
 
+        // IME is only enabled after the instruction following EI
+        cpu.step();
+        assert!(cpu.ime);
+        assert_eq!(cpu.registers.PC, 0x1001);
+
+        // Step again -> this should dispatch the VBLANK interrupt
+        assert_eq!(cpu.step().0, 20);
+        assert!(!cpu.ime);
+        assert_eq!(cpu.registers.PC, 0x40);
+
         // Execute a CPU step and verify that the ADD
         // in the VBLANK ISR was executed
         cpu.step();
-        assert!(!cpu.ime);
         assert_eq!(cpu.registers.PC, 0x41);
         assert_eq!(cpu.registers.read(Reg8::A), 0x41);
 
@@ -1177,9 +1403,10 @@ mod test {
         // RETI should restore original PC and re-enable interrupts
         cpu.step();
         assert!(cpu.ime);
-        assert_eq!(cpu.registers.PC, 0x1000);
+        assert_eq!(cpu.registers.PC, 0x1001);
 
-        // Step again -> this should trigger LcdStat and execute a NOP
+        // Dispatch LcdStat, then execute the NOP in its ISR
+        cpu.step();
         cpu.step();
         assert!(!cpu.ime);
         assert_eq!(cpu.registers.PC, 0x49);
@@ -1187,7 +1414,174 @@ mod test {
         // Last step -> verify PC is restored
         cpu.step();
         assert!(!cpu.ime);
-        assert_eq!(cpu.registers.PC, 0x1000);
+        assert_eq!(cpu.registers.PC, 0x1001);
+    }
+
+    #[test]
+    fn ei_di_sequence() {
+        let mut cpu = get_cpu();
+        cpu.registers.PC = 0x1000;
+
+        // EI; DI; EI; NOP
+        let controller = cpu.memory.controller_mut();
+        controller.rom.write(0x1000, 0xFBu8);
+        controller.rom.write(0x1001, 0xF3u8);
+        controller.rom.write(0x1002, 0xFBu8);
+        controller.rom.write(0x1003, 0x00u8);
+
+        cpu.memory.write(0xFFFF, 0x01u8);
+        cpu.trigger_interrupt(Interrupt::Vblank);
+
+        // DI right after EI means that the interrupt is never serviced
+        cpu.step();
+        cpu.step();
+        assert!(!cpu.ime);
+        assert_eq!(cpu.registers.PC, 0x1002);
+
+        // The NOP after EI executes before the interrupt is dispatched
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.registers.PC, 0x1004);
+        assert_eq!(cpu.step().0, 20);
+        assert_eq!(cpu.registers.PC, 0x40);
+    }
+
+    #[test]
+    fn ie_push() {
+        // Pushing the upper byte of PC to IE cancels the dispatch if the
+        // pending interrupt is no longer enabled
+        for (pc, expected) in [(0x0200, 0x0000), (0x0100, 0x0040)].iter() {
+            let mut cpu = get_cpu();
+            cpu.ime = true;
+            cpu.registers.PC = *pc;
+            cpu.registers.SP = 0x0000;
+
+            cpu.memory.write(0xFFFF, 0x01u8);
+            cpu.trigger_interrupt(Interrupt::Vblank);
+
+            assert_eq!(cpu.step().0, 20);
+            assert_eq!(cpu.registers.PC, *expected);
+            assert_eq!(cpu.registers.SP, 0xFFFE);
+
+            // A cancelled interrupt is not acknowledged
+            let int_flags: u8 = cpu.memory.read(0xFF0F);
+            assert_eq!(int_flags & 0x01 != 0, *expected == 0x0000);
+        }
+    }
+
+    #[test]
+    fn memory_access_timing() {
+        // DIV increments every 256 cycles. The read in `LD A, (HL)` happens on
+        // the second M-cycle of the instruction, so DIV should already have
+        // ticked when the instruction starts 8 cycles before the increment.
+        for (nops, expected) in [(61, 0), (62, 1)].iter() {
+            let mut cpu = get_cpu();
+            cpu.registers.PC = 0x1000;
+            cpu.registers.write(Reg16::HL, 0xFF04);
+
+            let controller = cpu.memory.controller_mut();
+            for i in 0..*nops {
+                controller.rom.write(0x1000 + i, 0x00u8);
+            }
+            controller.rom.write(0x1000 + nops, 0x7Eu8);
+
+            // Reset DIV
+            cpu.memory.write(0xFF04, 0u8);
+
+            let mut cycles = 0;
+            for _ in 0..*nops {
+                cycles += cpu.step().0;
+            }
+            assert_eq!(cycles, *nops * 4);
+
+            assert_eq!(cpu.step().0, 8);
+            assert_eq!(cpu.registers.read(Reg8::A), *expected);
+        }
+    }
+
+    #[test]
+    fn operand_fetch_timing() {
+        // The operand of `LD A, n8` is read on the second M-cycle of the
+        // instruction. Running it from SCX (0xFF43) means that the operand is
+        // read from LY (0xFF44), which increments every 114 M-cycles.
+        for (nops, expected) in [(111, 0), (112, 1)].iter() {
+            let mut cpu = get_cpu();
+            cpu.registers.PC = 0x1000;
+
+            let controller = cpu.memory.controller_mut();
+            for i in 0..0x100 {
+                controller.rom.write(0x1000 + i, 0x00u8);
+            }
+
+            cpu.memory.write(0xFF43, 0x3Eu8);
+
+            // Wait for the start of the next scanline
+            let ly: u8 = cpu.memory.read(0xFF44);
+            while cpu.memory.read(0xFF44) == ly {
+                cpu.step();
+            }
+
+            let ly: u8 = cpu.memory.read(0xFF44);
+            cpu.registers.PC = 0x1000;
+
+            for _ in 0..*nops {
+                cpu.step();
+            }
+
+            cpu.registers.PC = 0xFF43;
+            assert_eq!(cpu.step().0, 8);
+            assert_eq!(cpu.registers.read(Reg8::A), ly + expected);
+        }
+    }
+
+    #[test]
+    fn halt_bug() {
+        let mut cpu = get_cpu();
+        cpu.registers.PC = 0x1000;
+        cpu.registers.write(Reg8::A, 0);
+
+        // HALT; INC A; NOP
+        let controller = cpu.memory.controller_mut();
+        controller.rom.write(0x1000, 0x76u8);
+        controller.rom.write(0x1001, 0x3Cu8);
+        controller.rom.write(0x1002, 0x00u8);
+
+        // Pending interrupt with IME disabled
+        cpu.memory.write(0xFFFF, 0x01u8);
+        cpu.trigger_interrupt(Interrupt::Vblank);
+
+        // The CPU should not halt, and INC A should be executed twice
+        cpu.step();
+        assert!(!cpu.halted);
+
+        cpu.step();
+        assert_eq!(cpu.registers.PC, 0x1001);
+        cpu.step();
+        assert_eq!(cpu.registers.PC, 0x1002);
+        assert_eq!(cpu.registers.read(Reg8::A), 2);
+    }
+
+    #[test]
+    fn illegal_opcode() {
+        let mut cpu = get_cpu();
+        cpu.registers.PC = 0x1000;
+        cpu.memory.controller_mut().rom.write(0x1000, 0xDDu8);
+
+        cpu.step();
+        assert!(cpu.locked);
+
+        // The CPU never executes anything again, even if an interrupt is pending
+        cpu.ime = true;
+        cpu.memory.write(0xFFFF, 0x01u8);
+        cpu.trigger_interrupt(Interrupt::Vblank);
+
+        for _ in 0..10 {
+            assert_eq!(cpu.step().0, 4);
+        }
+        assert_eq!(cpu.registers.PC, 0x1001);
+
+        cpu.reset();
+        assert!(!cpu.locked);
     }
 
     #[test]
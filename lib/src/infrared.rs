@@ -0,0 +1,210 @@
+//! CGB infrared port
+//!
+//! The infrared port consists of an LED and a light sensor, controlled via
+//! the RP register (0xFF56). There is no hardware support for transfers, so
+//! games toggle the LED and poll the sensor with precise timing instead.
+//!
+//! `pair()` creates two transceivers that point at each other, which allows
+//! two Gameboys running in the same process to communicate over infrared
+//! (e.g., for Mystery Gift in Pokemon Gold/Silver).
+use std::cell::Cell;
+use std::rc::Rc;
+
+/// A device that faces a Gameboy's infrared port (e.g., another Gameboy).
+pub trait InfraredDevice {
+    /// Called when the Gameboy turns its LED on or off.
+    fn emit(&mut self, on: bool);
+
+    /// Returns `true` if the device is currently sending light towards the
+    /// Gameboy.
+    fn signal(&self) -> bool;
+}
+
+/// Infrared communications port (CGB only)
+///
+/// The sensor can only be read while reading is enabled (i.e., bits 6-7 of
+/// RP are set). If no device is attached, no light is ever received.
+#[cfg_attr(feature = "save", derive(serde::Serialize), derive(serde::Deserialize))]
+pub struct Infrared {
+    /// Infrared communications port (0xFF56)
+    ///
+    /// Bits 6-7: Data read enable (3: enable)
+    /// Bit 1: Read data (0: receiving, 1: normal) (read-only)
+    /// Bit 0: LED (0: off, 1: on)
+    rp: u8,
+
+    /// Device facing the infrared port, if any
+    #[cfg_attr(feature = "save", serde(skip))]
+    device: Option<Box<dyn InfraredDevice>>,
+
+    cgb: bool,
+}
+
+impl Infrared {
+    pub const ADDR: u16 = 0xFF56;
+
+    const LED_MASK: u8 = 1 << 0;
+    const READ_MASK: u8 = 1 << 1;
+    const READ_ENABLE_MASK: u8 = 0b11 << 6;
+
+    pub fn new(cgb: bool) -> Self {
+        Self {
+            rp: 0,
+            device: None,
+            cgb,
+        }
+    }
+
+    /// Attach a device to the infrared port, replacing any existing device.
+    pub fn attach(
+        &mut self,
+        mut device: Box<dyn InfraredDevice>,
+    ) -> Option<Box<dyn InfraredDevice>> {
+        device.emit(self.led());
+
+        let previous = self.detach();
+        self.device = Some(device);
+
+        previous
+    }
+
+    /// Detach the device attached to the infrared port, if any.
+    pub fn detach(&mut self) -> Option<Box<dyn InfraredDevice>> {
+        let mut device = self.device.take()?;
+
+        // The device no longer sees the LED
+        device.emit(false);
+
+        Some(device)
+    }
+
+    /// Returns `true` if the LED is on
+    #[inline]
+    pub fn led(&self) -> bool {
+        self.rp & Self::LED_MASK != 0
+    }
+
+    /// Returns `true` if light is being received and reading is enabled
+    fn receiving(&self) -> bool {
+        self.rp & Self::READ_ENABLE_MASK == Self::READ_ENABLE_MASK
+            && matches!(&self.device, Some(device) if device.signal())
+    }
+
+    pub fn read(&self) -> u8 {
+        if !self.cgb {
+            return 0xFF;
+        }
+
+        // Unused bits read as 1
+        let value = self.rp | 0x3C;
+
+        if self.receiving() {
+            value
+        } else {
+            value | Self::READ_MASK
+        }
+    }
+
+    pub fn write(&mut self, value: u8) {
+        if !self.cgb {
+            return;
+        }
+
+        let led = self.led();
+        self.rp = value & (Self::READ_ENABLE_MASK | Self::LED_MASK);
+
+        if led != self.led() {
+            let on = self.led();
+
+            if let Some(device) = self.device.as_mut() {
+                device.emit(on);
+            }
+        }
+    }
+}
+
+/// One of two infrared transceivers that point at each other. This is
+/// attached to a Gameboy's infrared port.
+pub struct Transceiver {
+    /// State of the LED on each side
+    leds: Rc<[Cell<bool>; 2]>,
+    side: usize,
+}
+
+/// Create two transceivers that point at each other.
+pub fn pair() -> (Transceiver, Transceiver) {
+    let leds = Rc::new([Cell::new(false), Cell::new(false)]);
+
+    let first = Transceiver {
+        leds: leds.clone(),
+        side: 0,
+    };
+    let second = Transceiver { leds, side: 1 };
+
+    (first, second)
+}
+
+impl InfraredDevice for Transceiver {
+    fn emit(&mut self, on: bool) {
+        self.leds[self.side].set(on);
+    }
+
+    fn signal(&self) -> bool {
+        self.leds[1 - self.side].get()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn read_enable() {
+        let (first, mut second) = pair();
+        let mut infrared = Infrared::new(true);
+
+        infrared.attach(Box::new(first));
+        second.emit(true);
+
+        // Reading is disabled, so nothing is received
+        assert_eq!(infrared.read(), 0x3E);
+
+        infrared.write(0xC0);
+        assert_eq!(infrared.read(), 0xFC);
+
+        second.emit(false);
+        assert_eq!(infrared.read(), 0xFE);
+
+        // Bit 1 is read-only
+        infrared.write(0x02);
+        assert_eq!(infrared.read(), 0x3E);
+
+        // Not available on DMG
+        let mut infrared = Infrared::new(false);
+        infrared.write(0xC1);
+        assert_eq!(infrared.read(), 0xFF);
+    }
+
+    #[test]
+    fn transceiver_pair() {
+        let (first, second) = pair();
+        let (mut a, mut b) = (Infrared::new(true), Infrared::new(true));
+
+        a.attach(Box::new(first));
+        b.attach(Box::new(second));
+
+        a.write(0xC0);
+        b.write(0xC1);
+        assert_eq!(a.read() & 0x02, 0);
+        assert_eq!(b.read() & 0x02, 0x02);
+
+        b.write(0xC0);
+        a.write(0xC1);
+        assert_eq!(a.read() & 0x02, 0x02);
+        assert_eq!(b.read() & 0x02, 0);
+
+        // Detaching the device turns off the light it sees
+        assert!(a.detach().is_some());
+        assert_eq!(b.read() & 0x02, 0x02);
+    }
+}
@@ -367,9 +367,16 @@ pub enum Instruction {
     /// Power down the CPU until an interrupt occurs
     Halt,
 
-    /// Halt CPU & LCD until an interrupt occurs
+    /// Halt CPU & LCD until a button is pressed
+    ///
+    /// On CGB, this is also used to switch CPU speed.
     Stop,
 
+    /// Illegal opcode. Locks up the CPU until it is reset.
+    Illegal {
+        opcode: u8,
+    },
+
     /// Disables interrupts **after** this instruction completes
     Di,
 
@@ -2107,7 +2114,7 @@ impl Instruction {
             0xFB => (Ei, 1, 4.into()),
 
             0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB | 0xEC | 0xED | 0xF4 | 0xFC | 0xFD => {
-                panic!("Invalid instruction: {}", data[0]);
+                (Illegal { opcode: data[0] }, 1, 4.into())
             }
         };
 
@@ -2635,6 +2642,7 @@ impl std::fmt::Display for Instruction {
             Nop => write!(f, "nop"),
             Halt => write!(f, "halt"),
             Stop => write!(f, "stop"),
+            Illegal { opcode } => write!(f, "illegal {:#04X}", opcode),
             Di => write!(f, "di"),
             Ei => write!(f, "ei"),
             Rst { offset } => write!(f, "rst {:#06X}", offset),
@@ -1,25 +1,38 @@
+pub mod apu;
+mod blip;
 pub mod cartridge;
 mod cpu;
 mod dma;
 pub mod error;
+pub mod infrared;
 mod instructions;
 pub mod joypad;
+pub mod link;
 mod memory;
+pub mod palette;
 pub mod ppu;
+pub mod printer;
 mod registers;
 mod rtc;
+pub mod serial;
 mod timer;
+pub mod wav;
 
 #[cfg(feature = "debug")]
 pub mod debug;
 
+use apu::{Channel, ChannelState, Sample};
 use cartridge::{Cartridge, Controller};
 pub use cpu::Cpu;
 use cpu::Interrupt;
 pub use error::{Error, Result};
+use infrared::InfraredDevice;
 use joypad::JoypadEvent;
-use memory::MemoryWrite;
-use ppu::FrameBuffer;
+use palette::DmgPalette;
+use ppu::{
+    ColorCorrection, FrameBuffer, LayerMask, PixelFormat, TileMap, TilePalette, VramImage,
+};
+use serial::SerialDevice;
 
 #[derive(serde::Deserialize, serde::Serialize)]
 pub struct GameboyState<'a> {
@@ -43,6 +56,9 @@ impl Gameboy {
     /// Frame duration, in ns
     pub const FRAME_DURATION: u64 = ((1f64 / Self::FRAME_FREQUENCY) * 1e9) as u64;
 
+    /// Number of CPU cycles in a single frame
+    const FRAME_CYCLES: u32 = 70224;
+
     /// Initialize the emulator from a `Cartridge`.
     pub fn init(cartridge: Cartridge, trace: bool) -> Result<Self> {
         let cpu = Cpu::from_cartridge(cartridge, trace)?;
@@ -65,8 +81,6 @@ impl Gameboy {
     ///
     /// Returns the number of cycles consumed by the CPU.
     pub fn step(&mut self) -> u32 {
-        let speed = self.cpu.speed;
-
         #[cfg(feature = "debug")]
         // If the debugger is triggered, step into the REPL.
         if self.debugger.triggered(&self.cpu) {
@@ -75,41 +89,32 @@ impl Gameboy {
 
         // Execute a step of the CPU
         //
-        // This handles interrupt processing and DMA internally.
+        // This handles interrupt processing, DMA, and updates to the memory bus
+        // internally. The memory bus is advanced on every memory access.
         let (cycles_taken, _inst) = self.cpu.step();
 
-        let mut interrupts = Vec::new();
-
-        // Update the memory bus
-        //
-        // Internally, this executes a step for each of:
-        //
-        // 1. PPU
-        // 2. Timer
-        // 3. Serial
-        // 4. RTC (if present)
-        self.cpu.memory.step(cycles_taken, speed, &mut interrupts);
-
-        // Trigger any pending interrupts
-        for interrupt in interrupts {
-            self.cpu.trigger_interrupt(interrupt);
-        }
-
-        if self.cpu.stopped {
-            // Reset DIV on speed switch
-            self.cpu.memory.write(0xFF04u16, 0u8);
-            self.cpu.stopped = false;
-        }
-
         cycles_taken as u32
     }
 
     /// Run the Gameboy until a frame is ready (i.e., start of VBLANK).
     ///
+    /// If the CPU is in STOP mode, the LCD is not running, so a blank frame
+    /// is returned after a frame's worth of cycles.
+    ///
     /// Returns a pointer to the frame buffer.
     pub fn frame(&mut self, joypad_events: Option<&[JoypadEvent]>) -> &FrameBuffer {
+        let mut stopped_cycles = 0;
+
         while !self.cpu.memory.ppu().is_frame_ready() {
-            self.step();
+            let cycles = self.step();
+
+            if self.cpu.stopped {
+                stopped_cycles += cycles;
+
+                if stopped_cycles >= Self::FRAME_CYCLES {
+                    self.cpu.memory.ppu_mut().blank_frame();
+                }
+            }
         }
 
         self.update_joypad(joypad_events);
@@ -130,8 +135,41 @@ impl Gameboy {
     }
 
     /// Insert a new cartridge and reset the emulator
+    ///
+    /// Any devices attached to the serial and infrared ports stay attached.
+    /// Display settings (DMG palette, color correction, pixel format, and layer
+    /// mask) and audio output settings (sample rate, channel mutes, and channel
+    /// taps) are kept.
     pub fn insert(&mut self, cartridge: Cartridge) -> Result<()> {
+        let serial = self.detach_serial_device();
+        let infrared = self.detach_infrared_device();
+        let palette = self.dmg_palette();
+        let correction = self.color_correction();
+        let format = self.pixel_format();
+        let layer_mask = self.layer_mask();
+        let sample_rate = self.sample_rate();
+        let taps = self.channel_taps();
+        let muted: Vec<Channel> = Channel::ALL
+            .iter()
+            .copied()
+            .filter(|c| self.channel_muted(*c))
+            .collect();
+
         self.cpu = Cpu::from_cartridge(cartridge, false)?;
+
+        self.reattach_devices(serial, infrared);
+        self.set_dmg_palette(palette);
+        self.set_color_correction(correction);
+        self.set_pixel_format(format);
+        self.set_layer_mask(layer_mask);
+        self.set_channel_taps(taps);
+        if let Some(sample_rate) = sample_rate {
+            self.set_sample_rate(sample_rate);
+        }
+        for channel in muted {
+            self.set_channel_muted(channel, true);
+        }
+
         Ok(())
     }
 
@@ -155,9 +193,81 @@ impl Gameboy {
     }
 
     /// Reset the emulator
+    ///
+    /// Any devices attached to the serial and infrared ports stay attached, and
+    /// display and audio output settings are kept.
     pub fn reset(&mut self) {
+        let serial = self.detach_serial_device();
+        let infrared = self.detach_infrared_device();
+
         // Reset the CPU
         self.cpu.reset();
+
+        self.reattach_devices(serial, infrared);
+    }
+
+    /// Returns the colors used to render DMG games
+    pub fn dmg_palette(&self) -> DmgPalette {
+        self.cpu.memory.ppu().dmg_palette()
+    }
+
+    /// Set the colors used to render DMG games (e.g., the palette picked by
+    /// a CGB for the cartridge, see `DmgPalette::for_cartridge`).
+    ///
+    /// Has no effect on CGB games. The palette is not part of the save state.
+    pub fn set_dmg_palette(&mut self, palette: DmgPalette) {
+        self.cpu.memory.ppu_mut().set_dmg_palette(palette);
+    }
+
+    /// Returns the color correction applied to CGB colors
+    pub fn color_correction(&self) -> ColorCorrection {
+        self.cpu.memory.ppu().color_correction()
+    }
+
+    /// Set the color correction applied to CGB colors.
+    ///
+    /// This is not part of the save state.
+    pub fn set_color_correction(&mut self, correction: ColorCorrection) {
+        self.cpu.memory.ppu_mut().set_color_correction(correction);
+    }
+
+    /// Returns the pixel format of the frame buffer
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.cpu.memory.ppu().pixel_format()
+    }
+
+    /// Set the pixel format that frames are rendered in (e.g., to match an
+    /// SDL texture or a canvas `ImageData`).
+    ///
+    /// The frame buffer (including its format) is part of the save state, so
+    /// set the format again after `Gameboy::load` if the state may have been
+    /// saved with a different one.
+    pub fn set_pixel_format(&mut self, format: PixelFormat) {
+        self.cpu.memory.ppu_mut().set_pixel_format(format);
+    }
+
+    /// Returns the layers that are drawn
+    pub fn layer_mask(&self) -> LayerMask {
+        self.cpu.memory.ppu().layer_mask()
+    }
+
+    /// Show or hide the BG, window, and sprite layers, or individual sprites.
+    /// This is meant for debugging graphics issues.
+    ///
+    /// This is not part of the save state.
+    pub fn set_layer_mask(&mut self, mask: LayerMask) {
+        self.cpu.memory.ppu_mut().set_layer_mask(mask);
+    }
+
+    /// Render every tile in VRAM using the given palette (for debugging).
+    pub fn render_tiles(&self, palette: TilePalette) -> VramImage {
+        self.cpu.memory.ppu().render_tiles(palette)
+    }
+
+    /// Render a full 256x256 tile map, with the LCD viewport outlined if it is
+    /// the current BG map (for debugging).
+    pub fn render_tile_map(&self, map: TileMap) -> VramImage {
+        self.cpu.memory.ppu().render_tile_map(map)
     }
 
     pub fn cpu(&mut self) -> &mut Cpu {
@@ -246,6 +356,133 @@ impl Gameboy {
         Ok(())
     }
 
+    /// Set the audio output sample rate, in Hz (e.g., 44100 or 48000).
+    ///
+    /// Audio samples are only generated once a sample rate has been set. A rate
+    /// of 0 disables audio output. Changing the rate while audio output is enabled
+    /// keeps any buffered samples, which allows frontends to make small adjustments
+    /// to the rate to keep their audio buffers from under- or overflowing.
+    ///
+    /// Note that this setting is not part of the save state, so it needs to be
+    /// set again after `Gameboy::load`.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.cpu
+            .memory
+            .io_mut()
+            .apu_mut()
+            .set_sample_rate(sample_rate);
+    }
+
+    /// Returns the current audio output sample rate, if set.
+    pub fn sample_rate(&self) -> Option<u32> {
+        self.cpu.memory.io().apu().sample_rate()
+    }
+
+    /// Drain all audio samples produced since the last call into `out`.
+    ///
+    /// Samples are interleaved stereo (left, right), either as `f32` in the
+    /// range [-1.0, 1.0] or as `i16`. This can be called after any number of
+    /// calls to `Gameboy::frame` or `Gameboy::step`.
+    ///
+    /// Returns the number of stereo frames appended to `out`.
+    pub fn audio_samples<T: Sample>(&mut self, out: &mut Vec<T>) -> usize {
+        self.cpu.memory.io_mut().apu_mut().read_samples(out)
+    }
+
+    /// Mute or unmute a single APU channel in the audio output.
+    pub fn set_channel_muted(&mut self, channel: Channel, muted: bool) {
+        self.cpu
+            .memory
+            .io_mut()
+            .apu_mut()
+            .set_channel_muted(channel, muted);
+    }
+
+    pub fn channel_muted(&self, channel: Channel) -> bool {
+        self.cpu.memory.io().apu().channel_muted(channel)
+    }
+
+    /// Returns the current frequency, volume, envelope, and duty state of an
+    /// APU channel.
+    pub fn channel_state(&self, channel: Channel) -> ChannelState {
+        self.cpu.memory.io().apu().channel_state(channel)
+    }
+
+    /// Enable or disable per-channel audio samples.
+    ///
+    /// When enabled, each channel produces a separate stream of mono samples
+    /// at the audio sample rate, which can be read via `Gameboy::channel_samples`.
+    /// This requires audio output to be enabled (see `Gameboy::set_sample_rate`).
+    pub fn set_channel_taps(&mut self, enabled: bool) {
+        self.cpu.memory.io_mut().apu_mut().set_channel_taps(enabled);
+    }
+
+    pub fn channel_taps(&self) -> bool {
+        self.cpu.memory.io().apu().channel_taps()
+    }
+
+    /// Drain all samples produced by a single channel since the last call into
+    /// `out`.
+    ///
+    /// Returns the number of samples appended to `out`.
+    pub fn channel_samples<T: Sample>(&mut self, channel: Channel, out: &mut Vec<T>) -> usize {
+        self.cpu
+            .memory
+            .io_mut()
+            .apu_mut()
+            .read_channel_samples(channel, out)
+    }
+
+    /// Attach a device (e.g., a printer or another Gameboy) to the serial port.
+    ///
+    /// Returns the previously attached device, if any.
+    ///
+    /// Note that the device is not part of the save state, so it needs to be
+    /// attached again after `Gameboy::load`.
+    pub fn attach_serial_device(
+        &mut self,
+        device: Box<dyn SerialDevice>,
+    ) -> Option<Box<dyn SerialDevice>> {
+        self.cpu.memory.io_mut().serial_mut().attach(device)
+    }
+
+    /// Detach the device attached to the serial port, if any.
+    pub fn detach_serial_device(&mut self) -> Option<Box<dyn SerialDevice>> {
+        self.cpu.memory.io_mut().serial_mut().detach()
+    }
+
+    /// Attach a device (e.g., another Gameboy) to the infrared port.
+    ///
+    /// Returns the previously attached device, if any.
+    ///
+    /// Note that the device is not part of the save state, so it needs to be
+    /// attached again after `Gameboy::load`.
+    pub fn attach_infrared_device(
+        &mut self,
+        device: Box<dyn InfraredDevice>,
+    ) -> Option<Box<dyn InfraredDevice>> {
+        self.cpu.memory.io_mut().infrared_mut().attach(device)
+    }
+
+    /// Detach the device attached to the infrared port, if any.
+    pub fn detach_infrared_device(&mut self) -> Option<Box<dyn InfraredDevice>> {
+        self.cpu.memory.io_mut().infrared_mut().detach()
+    }
+
+    fn reattach_devices(
+        &mut self,
+        serial: Option<Box<dyn SerialDevice>>,
+        infrared: Option<Box<dyn InfraredDevice>>,
+    ) {
+        if let Some(device) = serial {
+            self.attach_serial_device(device);
+        }
+
+        if let Some(device) = infrared {
+            self.attach_infrared_device(device);
+        }
+    }
+
     /// Returns a String containing the serial output of this Gameboy _so far_.
     ///
     /// In other words, this output is cumulative and contains every character
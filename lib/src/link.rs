@@ -0,0 +1,698 @@
+//! Link cable between two Gameboys running in the same process
+//!
+//! `Link` owns two `Gameboy` instances connected by a `cable` and steps them
+//! in lockstep, so that neither machine gets more than a single instruction
+//! ahead of the other. This means that serial transfers happen at the same
+//! point in emulated time on both sides, just like with a real cable.
+//!
+//! The infrared ports of the two Gameboys are pointed at each other as well,
+//! so CGB games can also communicate over infrared (e.g., Mystery Gift).
+//!
+//! The cable ends can also be attached to Gameboys directly, e.g. from a test
+//! harness that wants to drive the machines itself.
+//!
+//! `TcpCable` connects two Gameboys running in separate processes (or on
+//! separate machines) over TCP.
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::time::{Duration, Instant};
+
+use crate::error::Result;
+use crate::infrared;
+use crate::joypad::JoypadEvent;
+use crate::ppu::FrameBuffer;
+use crate::serial::SerialDevice;
+use crate::Gameboy;
+
+/// State shared between the two ends of a cable
+#[derive(Default)]
+struct Cable {
+    /// Outgoing byte of each end that is waiting for the other end to
+    /// start a transfer (i.e., external clock)
+    waiting: [Option<u8>; 2],
+
+    /// Byte sent to each end by a transfer from the other end
+    delivered: [Option<u8>; 2],
+
+    /// Whether each end is clocking a transfer to the other end
+    clocking: [bool; 2],
+
+    /// Bits clocked to each end that it has not shifted in yet
+    clocks: [u8; 2],
+}
+
+/// One end of a link cable. This is attached to a Gameboy's serial port.
+pub struct CableEnd {
+    cable: Rc<RefCell<Cable>>,
+    side: usize,
+}
+
+/// Create a link cable, and return both of its ends.
+pub fn cable() -> (CableEnd, CableEnd) {
+    let cable = Rc::new(RefCell::new(Cable::default()));
+
+    let first = CableEnd {
+        cable: cable.clone(),
+        side: 0,
+    };
+    let second = CableEnd { cable, side: 1 };
+
+    (first, second)
+}
+
+impl SerialDevice for CableEnd {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        let mut cable = self.cable.borrow_mut();
+        let other = 1 - self.side;
+
+        // If the other side is not waiting for a transfer, it does not
+        // shift anything out
+        match cable.waiting[other].take() {
+            Some(incoming) => {
+                cable.delivered[other] = Some(byte);
+                cable.clocking[self.side] = true;
+                cable.clocks[other] = 0;
+                incoming
+            }
+            None => {
+                cable.clocking[self.side] = false;
+                0xFF
+            }
+        }
+    }
+
+    fn clock(&mut self) {
+        let mut cable = self.cable.borrow_mut();
+        let other = 1 - self.side;
+
+        if cable.clocking[self.side] {
+            cable.clocks[other] += 1;
+        }
+    }
+
+    fn external_transfer(&mut self, byte: u8) -> Option<u8> {
+        let mut cable = self.cable.borrow_mut();
+
+        match cable.delivered[self.side].take() {
+            Some(incoming) => {
+                cable.waiting[self.side] = None;
+                Some(incoming)
+            }
+            None => {
+                // Keep the outgoing byte up-to-date for the other side
+                cable.waiting[self.side] = Some(byte);
+                None
+            }
+        }
+    }
+
+    fn external_clock(&mut self) -> u8 {
+        std::mem::take(&mut self.cable.borrow_mut().clocks[self.side])
+    }
+
+    fn cancel_external_transfer(&mut self) {
+        let mut cable = self.cable.borrow_mut();
+        let other = 1 - self.side;
+
+        cable.waiting[self.side] = None;
+        cable.delivered[self.side] = None;
+        cable.clocks[self.side] = 0;
+        cable.clocking[other] = false;
+    }
+}
+
+/// Messages exchanged by the two ends of a `TcpCable`
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Message {
+    /// Sender is waiting for a transfer clocked by the other side, with
+    /// the given outgoing byte. `seq` identifies the announcement.
+    Wait { seq: u8, byte: u8 },
+
+    /// Sender started a transfer with the given outgoing byte, in reply to
+    /// the announcement `seq`. This completes the transfer that the receiver
+    /// is waiting on.
+    Data { seq: u8, byte: u8 },
+
+    /// Sender is no longer waiting for a transfer, so the byte it announced
+    /// with `Wait` must not be used
+    Cancel,
+}
+
+impl Message {
+    const WAIT: u8 = 0;
+    const DATA: u8 = 1;
+    const CANCEL: u8 = 2;
+
+    fn encode(self) -> [u8; 3] {
+        match self {
+            Self::Wait { seq, byte } => [Self::WAIT, seq, byte],
+            Self::Data { seq, byte } => [Self::DATA, seq, byte],
+            Self::Cancel => [Self::CANCEL, 0, 0],
+        }
+    }
+
+    fn decode(data: [u8; 3]) -> Option<Self> {
+        let [code, seq, byte] = data;
+
+        match code {
+            Self::WAIT => Some(Self::Wait { seq, byte }),
+            Self::DATA => Some(Self::Data { seq, byte }),
+            Self::CANCEL => Some(Self::Cancel),
+            _ => None,
+        }
+    }
+}
+
+/// Link cable over a TCP connection
+///
+/// Whenever the local Gameboy waits for a transfer on the external clock, its
+/// outgoing byte is announced to the other side. When the other side starts a
+/// transfer, it exchanges bytes with the last announced byte right away, so
+/// network latency does not affect transfer timing.
+///
+/// If the local Gameboy starts a transfer before the other side has announced
+/// its byte, it is most likely just ahead of the other side. In that case, the
+/// local Gameboy is stalled until the other side catches up (up to a timeout).
+/// This only happens while a link session is active, i.e. the other side has
+/// recently waited for a transfer.
+///
+/// If the local Gameboy stops waiting before the other side starts a transfer,
+/// the announced byte is withdrawn. Each announcement has a sequence number
+/// that the other side echoes when it starts a transfer, so that a transfer
+/// started in reply to a withdrawn or outdated announcement is dropped.
+pub struct TcpCable {
+    stream: TcpStream,
+    messages: Receiver<Message>,
+
+    /// Sequence number and outgoing byte of the other side, if it is waiting
+    /// for a transfer
+    remote_waiting: Option<(u8, u8)>,
+
+    /// Last time the other side waited for a transfer
+    remote_active: Option<Instant>,
+
+    /// Byte received from a transfer started by the other side
+    delivered: Option<u8>,
+
+    /// Outgoing byte last announced to the other side
+    announced: Option<u8>,
+
+    /// Sequence number of the last announcement
+    seq: u8,
+
+    connected: bool,
+}
+
+impl TcpCable {
+    /// Maximum time to stall waiting for the other side to catch up
+    const STALL_TIMEOUT: Duration = Duration::from_millis(100);
+
+    /// The link session is considered inactive if the other side has not
+    /// waited for a transfer for this long
+    const SESSION_TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// Wait for the other side to connect on the given port.
+    pub fn listen(port: u16) -> Result<Self> {
+        let listener = TcpListener::bind(("0.0.0.0", port))?;
+
+        log::info!("Waiting for link cable connection on port {}", port);
+
+        let (stream, addr) = listener.accept()?;
+
+        log::info!("Link cable connected to {}", addr);
+
+        Self::from_stream(stream)
+    }
+
+    /// Connect to the other side at the given address.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+
+        log::info!("Link cable connected to {}", stream.peer_addr()?);
+
+        Self::from_stream(stream)
+    }
+
+    /// Use an existing TCP connection as a link cable.
+    pub fn from_stream(stream: TcpStream) -> Result<Self> {
+        stream.set_nodelay(true)?;
+
+        // Messages are read on a separate thread, so that the emulator can
+        // poll for them without blocking
+        let (tx, rx) = mpsc::channel();
+        let mut reader = stream.try_clone()?;
+
+        std::thread::spawn(move || {
+            let mut data = [0u8; 3];
+
+            while reader.read_exact(&mut data).is_ok() {
+                match Message::decode(data) {
+                    Some(message) => {
+                        if tx.send(message).is_err() {
+                            break;
+                        }
+                    }
+                    None => {
+                        log::error!("Invalid link cable message: {:?}", data);
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            stream,
+            messages: rx,
+            remote_waiting: None,
+            remote_active: None,
+            delivered: None,
+            announced: None,
+            seq: 0,
+            connected: true,
+        })
+    }
+
+    fn send(&mut self, message: Message) {
+        if !self.connected {
+            return;
+        }
+
+        if let Err(err) = self.stream.write_all(&message.encode()) {
+            log::error!("Link cable disconnected: {}", err);
+            self.connected = false;
+        }
+    }
+
+    fn handle(&mut self, message: Message) {
+        match message {
+            Message::Wait { seq, byte } => {
+                self.remote_waiting = Some((seq, byte));
+                self.remote_active = Some(Instant::now());
+            }
+            Message::Data { seq, byte } => {
+                // Ignore transfers that reply to an announcement that the local
+                // side has since withdrawn or replaced
+                if self.announced.is_some() && seq == self.seq {
+                    self.delivered = Some(byte);
+                }
+            }
+            Message::Cancel => {
+                self.remote_waiting = None;
+            }
+        }
+    }
+
+    /// Handle all messages received from the other side so far.
+    fn poll(&mut self) {
+        loop {
+            match self.messages.try_recv() {
+                Ok(message) => self.handle(message),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    if self.connected {
+                        log::error!("Link cable disconnected");
+                        self.connected = false;
+                    }
+
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Block until the other side waits for a transfer, or until the timeout.
+    fn stall(&mut self) {
+        let deadline = Instant::now() + Self::STALL_TIMEOUT;
+
+        while self.remote_waiting.is_none() {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+
+            match self.messages.recv_timeout(timeout) {
+                Ok(message) => self.handle(message),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.connected = false;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl SerialDevice for TcpCable {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        self.poll();
+
+        let active = matches!(self.remote_active, Some(t) if t.elapsed() < Self::SESSION_TIMEOUT);
+
+        if self.remote_waiting.is_none() && active && self.connected {
+            self.stall();
+        }
+
+        match self.remote_waiting.take() {
+            Some((seq, incoming)) => {
+                self.send(Message::Data { seq, byte });
+                incoming
+            }
+            None => 0xFF,
+        }
+    }
+
+    fn external_transfer(&mut self, byte: u8) -> Option<u8> {
+        self.poll();
+
+        if let Some(incoming) = self.delivered.take() {
+            self.announced = None;
+            return Some(incoming);
+        }
+
+        if self.announced != Some(byte) {
+            self.seq = self.seq.wrapping_add(1);
+            self.announced = Some(byte);
+            self.send(Message::Wait {
+                seq: self.seq,
+                byte,
+            });
+        }
+
+        None
+    }
+
+    fn cancel_external_transfer(&mut self) {
+        self.delivered = None;
+
+        if self.announced.take().is_some() {
+            self.send(Message::Cancel);
+        }
+    }
+}
+
+/// Two Gameboys connected by a link cable, with their infrared ports facing
+/// each other
+pub struct Link {
+    gameboys: [Gameboy; 2],
+
+    /// Cycles run by the first Gameboy minus cycles run by the second
+    offset: i64,
+
+    /// Last complete frame of each Gameboy
+    frames: [FrameBuffer; 2],
+}
+
+impl Link {
+    /// Connect two Gameboys with a link cable, and point their infrared ports
+    /// at each other.
+    ///
+    /// This replaces any device attached to either serial or infrared port.
+    pub fn new(mut first: Gameboy, mut second: Gameboy) -> Self {
+        let (a, b) = cable();
+
+        first.attach_serial_device(Box::new(a));
+        second.attach_serial_device(Box::new(b));
+
+        let (a, b) = infrared::pair();
+
+        first.attach_infrared_device(Box::new(a));
+        second.attach_infrared_device(Box::new(b));
+
+        Self {
+            gameboys: [first, second],
+            offset: 0,
+            frames: [FrameBuffer::new(), FrameBuffer::new()],
+        }
+    }
+
+    /// Run a single step on the Gameboy that is behind.
+    ///
+    /// Returns the index of the Gameboy that was stepped, along with the
+    /// number of cycles it consumed.
+    pub fn step(&mut self) -> (usize, u32) {
+        let idx = if self.offset <= 0 { 0 } else { 1 };
+        let cycles = self.gameboys[idx].step();
+
+        if idx == 0 {
+            self.offset += cycles as i64;
+        } else {
+            self.offset -= cycles as i64;
+        }
+
+        (idx, cycles)
+    }
+
+    /// Run both Gameboys until the first one has a frame ready.
+    ///
+    /// The second Gameboy runs for the same number of cycles, so it produces
+    /// a frame at (roughly) the same rate. Returns the last complete frame of
+    /// each Gameboy.
+    pub fn frame(&mut self, joypad_events: [Option<&[JoypadEvent]>; 2]) -> [&FrameBuffer; 2] {
+        let mut stopped_cycles = [0; 2];
+
+        loop {
+            let (idx, cycles) = self.step();
+            let cpu = &mut self.gameboys[idx].cpu;
+
+            // The LCD is off in STOP mode, so show a blank frame instead
+            if cpu.stopped {
+                stopped_cycles[idx] += cycles;
+
+                if stopped_cycles[idx] >= Gameboy::FRAME_CYCLES {
+                    stopped_cycles[idx] = 0;
+                    cpu.memory.ppu_mut().blank_frame();
+                }
+            }
+
+            if let Some(frame) = cpu.memory.ppu_mut().frame_buffer() {
+                self.frames[idx].copy_from(frame);
+
+                if idx == 0 {
+                    break;
+                }
+            }
+        }
+
+        for (gameboy, events) in self.gameboys.iter_mut().zip(joypad_events.iter()) {
+            gameboy.update_joypad(*events);
+        }
+
+        [&self.frames[0], &self.frames[1]]
+    }
+
+    /// Returns both Gameboys.
+    pub fn gameboys(&mut self) -> &mut [Gameboy; 2] {
+        &mut self.gameboys
+    }
+
+    /// Disconnect the link cable and infrared ports, and return both Gameboys.
+    pub fn into_gameboys(self) -> [Gameboy; 2] {
+        let [mut first, mut second] = self.gameboys;
+
+        for gameboy in [&mut first, &mut second].iter_mut() {
+            gameboy.detach_serial_device();
+            gameboy.detach_infrared_device();
+        }
+
+        [first, second]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::serial::Serial;
+
+    /// Step both serial ports in lockstep until one of them completes a
+    /// transfer, and return the number of cycles and the interrupts raised
+    fn run_serial(ports: &mut [Serial; 2], counter: &mut u16) -> (u32, [bool; 2]) {
+        for cycles in (4..8192).step_by(4) {
+            *counter = counter.wrapping_add(4);
+            let interrupts = [ports[0].step(*counter), ports[1].step(*counter)];
+
+            if interrupts[0] || interrupts[1] {
+                return (cycles, interrupts);
+            }
+        }
+
+        panic!("Serial transfer did not complete");
+    }
+
+    #[test]
+    fn cable_clock_sync() {
+        let (a, b) = cable();
+        let mut ports = [Serial::new(false), Serial::new(false)];
+        let mut counter = 0;
+
+        ports[0].attach(Box::new(a));
+        ports[1].attach(Box::new(b));
+
+        // The slave waits for a transfer first
+        ports[1].write(Serial::SB_ADDR, 0x34);
+        ports[1].write(Serial::SC_ADDR, 0x80);
+        ports[1].step(counter);
+
+        ports[0].write(Serial::SB_ADDR, 0x12);
+        ports[0].write(Serial::SC_ADDR, 0x81);
+
+        // Both sides complete the transfer on the same cycle, 8 bits at 8192 Hz
+        // after the master started it
+        let (cycles, interrupts) = run_serial(&mut ports, &mut counter);
+        assert_eq!(cycles, 4096);
+        assert_eq!(interrupts, [true, true]);
+
+        assert_eq!(ports[0].read(Serial::SB_ADDR), 0x34);
+        assert_eq!(ports[1].read(Serial::SB_ADDR), 0x12);
+    }
+
+    #[test]
+    fn cable_transfer() {
+        let (mut master, mut slave) = cable();
+
+        // The slave is not ready yet
+        assert_eq!(master.transfer(0x12), 0xFF);
+        assert_eq!(slave.external_transfer(0x34), None);
+
+        // The slave is now waiting, so the master exchanges bytes with it
+        assert_eq!(master.transfer(0x56), 0x34);
+        assert_eq!(slave.external_transfer(0x34), Some(0x56));
+
+        // A completed transfer is not repeated
+        assert_eq!(slave.external_transfer(0x78), None);
+        assert_eq!(master.transfer(0x9A), 0x78);
+    }
+
+    #[test]
+    fn cable_cancel() {
+        let (a, b) = cable();
+        let mut ports = [Serial::new(false), Serial::new(false)];
+        let mut counter = 0;
+
+        ports[0].attach(Box::new(a));
+        ports[1].attach(Box::new(b));
+
+        // The slave waits for a transfer, but gives up before the master starts one
+        ports[1].write(Serial::SB_ADDR, 0x34);
+        ports[1].write(Serial::SC_ADDR, 0x80);
+        ports[1].step(counter);
+        ports[1].write(Serial::SC_ADDR, 0x00);
+
+        ports[0].write(Serial::SB_ADDR, 0x12);
+        ports[0].write(Serial::SC_ADDR, 0x81);
+        assert_eq!(run_serial(&mut ports, &mut counter), (4096, [true, false]));
+        assert_eq!(ports[0].read(Serial::SB_ADDR), 0xFF);
+
+        // The master's byte is not picked up by a later transfer either
+        ports[1].write(Serial::SC_ADDR, 0x80);
+
+        for _ in 0..1024 {
+            counter = counter.wrapping_add(4);
+            assert!(!ports[1].step(counter));
+        }
+
+        assert_eq!(ports[1].read(Serial::SB_ADDR), 0x34);
+    }
+
+    /// Poll until `done` returns `true`, or panic if the other side takes too long
+    fn wait_for(mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        while !done() {
+            assert!(
+                Instant::now() < deadline,
+                "Timed out waiting for the other side"
+            );
+        }
+    }
+
+    #[test]
+    fn tcp_cable_transfer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut master = TcpCable::connect(addr).unwrap();
+        let mut slave = TcpCable::from_stream(listener.accept().unwrap().0).unwrap();
+
+        // The slave has never waited, so the master does not stall
+        assert_eq!(master.transfer(0x12), 0xFF);
+
+        // Once the slave's byte arrives, the master exchanges bytes with it
+        assert_eq!(slave.external_transfer(0x34), None);
+        wait_for(|| {
+            master.poll();
+            master.remote_waiting.is_some()
+        });
+        assert_eq!(master.transfer(0x56), 0x34);
+
+        let mut incoming = None;
+        wait_for(|| {
+            incoming = slave.external_transfer(0x34);
+            incoming.is_some()
+        });
+        assert_eq!(incoming, Some(0x56));
+
+        // The link session is now active, so the master stalls waiting for
+        // the slave before giving up
+        let start = Instant::now();
+        assert_eq!(master.transfer(0x78), 0xFF);
+        assert!(start.elapsed() >= TcpCable::STALL_TIMEOUT);
+
+        // A byte that the slave withdraws is not used
+        assert_eq!(slave.external_transfer(0x9A), None);
+        wait_for(|| {
+            master.poll();
+            master.remote_waiting.is_some()
+        });
+
+        slave.cancel_external_transfer();
+        wait_for(|| {
+            master.poll();
+            master.remote_waiting.is_none()
+        });
+        assert_eq!(master.transfer(0xBC), 0xFF);
+    }
+
+    #[test]
+    fn tcp_cable_reannounce() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut master = TcpCable::connect(addr).unwrap();
+        let mut slave = TcpCable::from_stream(listener.accept().unwrap().0).unwrap();
+
+        assert_eq!(slave.external_transfer(0x12), None);
+        wait_for(|| {
+            master.poll();
+            master.remote_waiting.is_some()
+        });
+
+        // The slave withdraws its byte and announces a new one, while the
+        // master starts a transfer in reply to the first one
+        slave.cancel_external_transfer();
+        assert_eq!(slave.external_transfer(0x34), None);
+        master.send(Message::Data { seq: 1, byte: 0x56 });
+
+        // Any later message from the master arrives after the reply
+        assert_eq!(master.external_transfer(0x9A), None);
+        wait_for(|| {
+            slave.poll();
+            slave.remote_waiting.is_some()
+        });
+        master.cancel_external_transfer();
+
+        // The reply is dropped, and the slave keeps waiting with its new byte
+        assert_eq!(slave.external_transfer(0x34), None);
+        wait_for(|| {
+            master.poll();
+            master.remote_waiting == Some((2, 0x34))
+        });
+        assert_eq!(master.transfer(0x78), 0x34);
+
+        let mut incoming = None;
+        wait_for(|| {
+            incoming = slave.external_transfer(0x34);
+            incoming.is_some()
+        });
+        assert_eq!(incoming, Some(0x78));
+    }
+}
@@ -1,8 +1,11 @@
+use crate::apu::{Apu, Channel};
 use crate::cartridge::{BootRom, Cartridge, Controller, Ram as CartridgeRam, Rom};
 use crate::cpu::Interrupt;
 use crate::error::Result;
 use crate::joypad::Joypad;
 use crate::ppu::{Ppu, Vram};
+use crate::infrared::Infrared;
+use crate::serial::Serial;
 use crate::timer::Timer;
 
 /// Generic traits that provide access to some memory.
@@ -108,9 +111,8 @@ pub struct Io {
     /// Joypad register: 0xFF00
     joypad: Joypad,
 
-    // Serial port (SB) and control (SC) (0xFF01, 0xFF02)
-    serial: [u8; 2],
-    serial_buffer: Vec<char>,
+    /// Serial port (SB) and control (SC) (0xFF01, 0xFF02)
+    serial: Serial,
 
     /// Timer: 0xFF04 - 0xFF07
     timer: Timer,
@@ -118,11 +120,9 @@ pub struct Io {
     /// Interrupt flags (IF) 0xFF0F
     pub int_flags: u8,
 
-    /// Range: 0xFF10 - 0xFF26
-    sound: [u8; 23],
-
-    /// Range: 0xFF30 - 0xFF3F
-    waveform_ram: [u8; 16],
+    /// Sound registers: 0xFF10 - 0xFF26
+    /// Wave RAM: 0xFF30 - 0xFF3F
+    apu: Apu,
 
     // KEY1: 0xFF4D
     pub prep_speed_switch: u8,
@@ -135,32 +135,30 @@ pub struct Io {
     pub hdma_active: bool,
     pub hdma_stopped: bool,
 
-    /// Infrared comm. register (0xFF56)
-    rp: u8,
+    /// Infrared comm. port (0xFF56)
+    infrared: Infrared,
+
+    cgb: bool,
 }
 
 impl Io {
     pub const BASE_ADDR: u16 = 0xFF00;
     pub const LAST_ADDR: u16 = 0xFF7F;
 
-    pub const SC_ADDR: u16 = 0xFF02;
-    pub const SC_REQUEST_MASK: u8 = 1 << 7;
-
-    pub fn new() -> Self {
+    pub fn new(cgb: bool) -> Self {
         Self {
             joypad: Joypad::new(),
-            serial: [0; 2],
-            serial_buffer: Vec::new(),
+            serial: Serial::new(cgb),
             timer: Timer::new(),
             int_flags: 0,
-            sound: [0; 23],
-            waveform_ram: [0; 16],
+            apu: Apu::new(cgb),
             prep_speed_switch: 0,
             disable_boot_rom: 0,
             hdma: [0; 5],
             hdma_active: false,
             hdma_stopped: false,
-            rp: 0,
+            infrared: Infrared::new(cgb),
+            cgb,
         }
     }
 
@@ -184,16 +182,14 @@ impl Io {
         &mut self.timer
     }
 
-    /// Determine if a serial interrupt needs to be triggered.
-    pub fn serial_interrupt(&mut self) -> bool {
-        let sc = self.serial[1];
+    /// Return a reference to the APU
+    pub fn apu(&self) -> &Apu {
+        &self.apu
+    }
 
-        if sc & Self::SC_REQUEST_MASK != 0 {
-            self.serial[1] = sc & !Io::SC_REQUEST_MASK;
-            true
-        } else {
-            false
-        }
+    /// Return a mutable reference to the APU
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        &mut self.apu
     }
 
     /// Write to the HDMA start register without triggering HDMA start.
@@ -202,12 +198,27 @@ impl Io {
         self.hdma[4] = value;
     }
 
+    /// Return a reference to the serial port
+    pub fn serial(&self) -> &Serial {
+        &self.serial
+    }
+
+    /// Return a mutable reference to the serial port
+    pub fn serial_mut(&mut self) -> &mut Serial {
+        &mut self.serial
+    }
+
+    /// Return a mutable reference to the infrared port
+    pub fn infrared_mut(&mut self) -> &mut Infrared {
+        &mut self.infrared
+    }
+
     /// Returns a handle to the serial buffer
     ///
     /// This buffer contains every character logged to the serial port.
     /// Mainly used in tests.
     pub fn serial_buffer(&self) -> &[char] {
-        &self.serial_buffer
+        self.serial.buffer()
     }
 }
 
@@ -216,23 +227,11 @@ impl MemoryRead<u16, u8> for Io {
     fn read(&self, addr: u16) -> u8 {
         match addr {
             0xFF00 => self.joypad.read(),
-            0xFF01 => {
-                // Serial read
-                self.serial[0]
-            }
-            0xFF02 => {
-                // Serial control
-                self.serial[1]
-            }
+            Serial::SB_ADDR | Serial::SC_ADDR => self.serial.read(addr),
             0xFF04..=0xFF07 => self.timer.read(addr),
             0xFF0F => self.int_flags,
-            0xFF10..=0xFF26 => {
-                let idx = (addr - 0xFF10) as usize;
-                self.sound[idx]
-            }
-            0xFF30..=0xFF3F => {
-                let idx = (addr - 0xFF30) as usize;
-                self.waveform_ram[idx]
+            Apu::BASE_ADDR..=Apu::LAST_ADDR | Apu::WAVE_RAM_BASE_ADDR..=Apu::WAVE_RAM_LAST_ADDR => {
+                self.apu.read(addr)
             }
             0xFF4D => self.prep_speed_switch,
             0xFF50 => self.disable_boot_rom,
@@ -241,7 +240,9 @@ impl MemoryRead<u16, u8> for Io {
                 let idx = (addr - 0xFF51) as usize;
                 self.hdma[idx]
             }
-            0xFF56 => self.rp,
+            Infrared::ADDR => self.infrared.read(),
+            Apu::PCM12_ADDR if self.cgb => self.apu.pcm12(),
+            Apu::PCM34_ADDR if self.cgb => self.apu.pcm34(),
             0xFF03
             | 0xFF08..=0xFF0E
             | 0xFF27..=0xFF2F
@@ -263,31 +264,15 @@ impl MemoryWrite<u16, u8> for Io {
     fn write(&mut self, addr: u16, value: u8) {
         match addr {
             0xFF00 => self.joypad.write(value),
-            0xFF01 => {
-                // Serial write
-                self.serial[0] = value;
-            }
-            0xFF02 => {
-                // Serial control
-                self.serial[1] = value;
-                if value == 0x81 {
-                    let c = self.serial[0] as char;
-                    self.serial_buffer.push(c);
-                }
-            }
+            Serial::SB_ADDR | Serial::SC_ADDR => self.serial.write(addr, value),
             0xFF04..=0xFF07 => {
                 self.timer.write(addr, value);
             }
             0xFF0F => {
                 self.int_flags = value;
             }
-            0xFF10..=0xFF26 => {
-                let idx = (addr - 0xFF10) as usize;
-                self.sound[idx] = value;
-            }
-            0xFF30..=0xFF3F => {
-                let idx = (addr - 0xFF30) as usize;
-                self.waveform_ram[idx] = value;
+            Apu::BASE_ADDR..=Apu::LAST_ADDR | Apu::WAVE_RAM_BASE_ADDR..=Apu::WAVE_RAM_LAST_ADDR => {
+                self.apu.write(addr, value);
             }
             0xFF4D => {
                 self.prep_speed_switch = value;
@@ -308,9 +293,7 @@ impl MemoryWrite<u16, u8> for Io {
                 let idx = (addr - 0xFF51) as usize;
                 self.hdma[idx] = value;
             }
-            0xFF56 => {
-                self.rp = value;
-            }
+            Infrared::ADDR => self.infrared.write(value),
             0xFF03
             | 0xFF08..=0xFF0E
             | 0xFF27..=0xFF2F
@@ -388,7 +371,7 @@ impl MemoryBus {
             controller: Controller::new(),
             ppu: Ppu::new(cgb, false),
             ram: Ram::new(cgb),
-            io: Io::new(),
+            io: Io::new(cgb),
             high_ram: Box::new([0u8; 0x80]),
             int_enable: 0,
             cgb: true,
@@ -405,7 +388,7 @@ impl MemoryBus {
             controller,
             ppu: Ppu::new(cgb, boot_rom),
             ram: Ram::new(cgb),
-            io: Io::new(),
+            io: Io::new(cgb),
             high_ram: Box::new([0u8; 0x80]),
             int_enable: 0,
             cgb,
@@ -420,17 +403,21 @@ impl MemoryBus {
         self.ppu.step(cycles, speed, interrupts);
 
         // Update the internal timer and trigger an interrupt, if needed
-        // Note that the timer may tick multiple times for a single instruction
         if self.timer().step(cycles, speed) {
             interrupts.push(Interrupt::Timer);
         }
 
-        // Check if a serial interrupt needs to be triggered
+        // Update the APU
         //
-        // TODO: This does not happen every cycle, right?
-        if self.io.serial_interrupt() {
-            // TODO: Implement correct timing for serial interrupts
-            //interrupts.push(Interrupt::Serial);
+        // The APU frame sequencer is driven by DIV, so this must happen after
+        // the timer step.
+        let div = self.io.timer.div();
+        self.io.apu.step(cycles, speed, div);
+
+        // Update the serial port, which is clocked by the timer's system counter
+        let counter = self.io.timer.counter();
+        if self.io.serial.step(counter) {
+            interrupts.push(Interrupt::Serial);
         }
 
         // Update the RTC, if present
@@ -446,9 +433,36 @@ impl MemoryBus {
 
         self.controller.reset();
 
+        // Display settings survive the reset
+        let palette = self.ppu.dmg_palette();
+        let correction = self.ppu.color_correction();
+        let format = self.ppu.pixel_format();
+        let layer_mask = self.ppu.layer_mask();
         self.ppu = Ppu::new(cgb, boot_rom);
+        self.ppu.set_dmg_palette(palette);
+        self.ppu.set_color_correction(correction);
+        self.ppu.set_pixel_format(format);
+        self.ppu.set_layer_mask(layer_mask);
         self.ram = Ram::new(cgb);
-        self.io = Io::new();
+
+        // As do audio output settings
+        let apu = self.io.apu();
+        let sample_rate = apu.sample_rate();
+        let taps = apu.channel_taps();
+        let muted: Vec<Channel> = Channel::ALL
+            .iter()
+            .copied()
+            .filter(|c| apu.channel_muted(*c))
+            .collect();
+        self.io = Io::new(cgb);
+        let apu = self.io.apu_mut();
+        apu.set_channel_taps(taps);
+        if let Some(sample_rate) = sample_rate {
+            apu.set_sample_rate(sample_rate);
+        }
+        for channel in muted {
+            apu.set_channel_muted(channel, true);
+        }
         self.high_ram = Box::new([0u8; 0x80]);
         self.int_enable = 0;
     }
@@ -645,4 +659,22 @@ mod test {
         let value: u8 = ram.read(Ram::BASE_ADDR + 0x1234u16);
         assert_eq!(value, 0x66);
     }
+
+    #[test]
+    fn reset_keeps_output_settings() {
+        let mut memory = MemoryBus::new(true);
+
+        let apu = memory.io_mut().apu_mut();
+        apu.set_channel_taps(true);
+        apu.set_sample_rate(44100);
+        apu.set_channel_muted(Channel::Wave, true);
+
+        memory.reset();
+
+        let apu = memory.io().apu();
+        assert_eq!(apu.sample_rate(), Some(44100));
+        assert!(apu.channel_taps());
+        assert!(apu.channel_muted(Channel::Wave));
+        assert!(!apu.channel_muted(Channel::Square1));
+    }
 }
@@ -0,0 +1,484 @@
+//! DMG color palettes
+//!
+//! DMG games only ever pick between four shades. A DMG renders these in green,
+//! while a CGB running a DMG game colorizes them with three separate palettes:
+//! one for the BG and window, and one for each of the OBJ palettes (OBP0 and
+//! OBP1).
+//!
+//! The CGB boot ROM picks the palettes by looking up a checksum of the game's
+//! title in a table of Nintendo games. Some games share a checksum, in which
+//! case the 4th letter of the title is used to tell them apart. The user can
+//! also override the palettes by holding a button combination while the boot
+//! logo is shown. Both are available here, along with the classic DMG green
+//! and plain grayscale.
+use std::str::FromStr;
+
+use crate::cartridge::Cartridge;
+use crate::error::{Error, Result};
+use crate::ppu::GameboyRgb;
+
+/// Colors used by the CGB boot ROM (RGB555), in groups of 4
+#[rustfmt::skip]
+static BOOT_COLORS: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000, // Brown
+    0x639F, 0x4279, 0x15B0, 0x04CB, // Dark brown (BG)
+    0x7FFF, 0x6E31, 0x454A, 0x0000, // Dark blue (BG)
+    0x7FFF, 0x1BEF, 0x0200, 0x0000, // Green (OBJ)
+    0x7FFF, 0x421F, 0x1CF2, 0x0000, // Red
+    0x7FFF, 0x5294, 0x294A, 0x0000, // Gray
+    0x7FFF, 0x03FF, 0x012F, 0x0000, // Yellow (BG)
+    0x7FFF, 0x03EF, 0x01D6, 0x0000,
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000,
+    0x7E74, 0x03FF, 0x0180, 0x0000,
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B,
+    0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000, // Pastel
+    0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0,
+    0x03ED, 0x7FFF, 0x255F, 0x0000,
+    0x036A, 0x021F, 0x03FF, 0x7FFF,
+    0x7FFF, 0x01DF, 0x0112, 0x0000,
+    0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000, // Green
+    0x299F, 0x001A, 0x000C, 0x0000,
+    0x7FFF, 0x027F, 0x001F, 0x0000,
+    0x7FFF, 0x03E0, 0x0206, 0x0120,
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00,
+    0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000, // Orange
+    0x03FF, 0x001F, 0x000C, 0x0000,
+    0x7FFF, 0x033F, 0x0193, 0x0000,
+    0x0000, 0x4200, 0x037F, 0x7FFF, // Reverse
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000, // Blue
+    0x7FFF, 0x1BEF, 0x6180, 0x0000, // Dark green (BG)
+];
+
+/// Palette combinations used by the CGB boot ROM, given as the index of the
+/// first color of each palette in `BOOT_COLORS`: (OBJ0, OBJ1, BG).
+///
+/// A few combinations start in the middle of a group of colors. The boot ROM
+/// does the same, so these are kept as-is.
+#[rustfmt::skip]
+static COMBINATIONS: [[u8; 3]; 51] = [
+    [16, 16, 116], [72, 72, 72], [80, 80, 80], [96, 96, 96], [36, 36, 36],
+    [0, 0, 0], [108, 108, 108], [20, 20, 20], [48, 48, 48], [104, 104, 104],
+    [64, 32, 32], [16, 112, 112], [16, 8, 8], [12, 16, 16], [16, 116, 116],
+    [112, 16, 112], [8, 68, 8], [64, 64, 32], [16, 16, 28], [16, 16, 72],
+    [16, 16, 80], [76, 76, 36], [15, 15, 44], [68, 68, 8], [16, 16, 8],
+    [16, 16, 12], [112, 112, 0], [12, 12, 0], [0, 0, 4], [72, 88, 72],
+    [80, 88, 80], [96, 88, 96], [64, 88, 32], [68, 16, 52], [111, 0, 56],
+    [111, 16, 60], [76, 88, 36], [64, 112, 40], [16, 92, 112], [68, 88, 8],
+    [16, 0, 8], [16, 112, 12], [112, 12, 0], [12, 112, 16], [84, 112, 16],
+    [12, 112, 0], [100, 12, 112], [0, 112, 32], [16, 12, 112], [112, 12, 24],
+    [16, 112, 116],
+];
+
+/// Title checksums (the sum of the bytes at 0x134-0x143) of the Nintendo
+/// games known to the CGB boot ROM.
+#[rustfmt::skip]
+static TITLE_CHECKSUMS: [u8; 78] = [
+    0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9,
+    0x3E, 0x70, 0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99,
+    0x34, 0x6F, 0x15, 0xFF, 0x97, 0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2,
+    0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE, 0x0C, 0x29, 0xE8, 0xB7, 0x86,
+    0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F, 0xE8,
+    // Shared by up to 3 games each (see `FOURTH_LETTERS`)
+    0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D,
+    0xF4,
+];
+
+/// Index of the first checksum in `TITLE_CHECKSUMS` that is shared by several games
+const FIRST_SHARED_CHECKSUM: usize = 64;
+
+/// Number of checksums that are shared by several games
+const SHARED_CHECKSUMS: usize = TITLE_CHECKSUMS.len() - FIRST_SHARED_CHECKSUM;
+
+/// 4th letter of the title of each game with a shared checksum. The games for
+/// the shared checksum at index `i` are found at `i`, `i + 14`, and `i + 28`.
+static FOURTH_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+/// Palette combination for each game in `TITLE_CHECKSUMS`, followed by one
+/// for each game in `FOURTH_LETTERS`
+#[rustfmt::skip]
+static GAME_COMBINATIONS: [u8; 93] = [
+    4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44, 21, 32, 31, 20, 5, 33,
+    13, 14, 5, 29, 5, 18, 9, 3, 2, 26, 25, 25, 41, 42, 26, 45, 42, 45, 36, 38,
+    26, 42, 30, 41, 34, 34, 5, 42, 6, 5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42,
+    5, 0, 39,
+    // Games with a shared checksum
+    36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39, 24, 31, 50, 17, 46, 6, 27, 0, 47,
+    41, 41, 0, 0, 34, 23, 18, 29, 28,
+];
+
+/// Palettes that can be selected by holding a button combination during the
+/// CGB boot animation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Preset {
+    /// Up
+    Brown,
+    /// Up + A
+    Red,
+    /// Up + B
+    DarkBrown,
+    /// Down
+    Pastel,
+    /// Down + A
+    Orange,
+    /// Down + B
+    Yellow,
+    /// Left
+    Blue,
+    /// Left + A
+    DarkBlue,
+    /// Left + B
+    Gray,
+    /// Right
+    Green,
+    /// Right + A (default for games not in the title checksum table)
+    DarkGreen,
+    /// Right + B
+    Reverse,
+}
+
+impl Preset {
+    pub const ALL: [Preset; 12] = [
+        Self::Brown,
+        Self::Red,
+        Self::DarkBrown,
+        Self::Pastel,
+        Self::Orange,
+        Self::Yellow,
+        Self::Blue,
+        Self::DarkBlue,
+        Self::Gray,
+        Self::Green,
+        Self::DarkGreen,
+        Self::Reverse,
+    ];
+
+    /// Name of this preset, as accepted by `DmgPalette::from_str`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Brown => "brown",
+            Self::Red => "red",
+            Self::DarkBrown => "dark-brown",
+            Self::Pastel => "pastel",
+            Self::Orange => "orange",
+            Self::Yellow => "yellow",
+            Self::Blue => "blue",
+            Self::DarkBlue => "dark-blue",
+            Self::Gray => "gray",
+            Self::Green => "green",
+            Self::DarkGreen => "dark-green",
+            Self::Reverse => "reverse",
+        }
+    }
+
+    /// Returns the index of this preset in `COMBINATIONS`
+    fn combination(&self) -> u8 {
+        match self {
+            Self::Brown => 5,
+            Self::Red => 13,
+            Self::DarkBrown => 28,
+            Self::Pastel => 8,
+            Self::Orange => 3,
+            Self::Yellow => 49,
+            Self::Blue => 48,
+            Self::DarkBlue => 40,
+            Self::Gray => 7,
+            Self::Green => 1,
+            Self::DarkGreen => 0,
+            Self::Reverse => 6,
+        }
+    }
+}
+
+/// Colors used to render DMG games
+///
+/// Each palette maps the 4 shades selected by BGP, OBP0, or OBP1 (from
+/// lightest to darkest) to a color.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DmgPalette {
+    /// BG and window
+    pub bg: [GameboyRgb; 4],
+
+    /// Sprites using OBP0
+    pub obj0: [GameboyRgb; 4],
+
+    /// Sprites using OBP1
+    pub obj1: [GameboyRgb; 4],
+}
+
+impl DmgPalette {
+    /// Classic green DMG palette
+    pub fn dmg() -> Self {
+        let colors = [
+            GameboyRgb::new(0xE0, 0xF8, 0xD0), // White
+            GameboyRgb::new(0x88, 0xC0, 0x70), // Light gray
+            GameboyRgb::new(0x34, 0x68, 0x56), // Dark gray
+            GameboyRgb::new(0x08, 0x18, 0x20), // Black
+        ];
+
+        Self::uniform(colors)
+    }
+
+    /// Evenly spaced shades of gray
+    pub fn grayscale() -> Self {
+        let colors = [
+            GameboyRgb::new(0xFF, 0xFF, 0xFF),
+            GameboyRgb::new(0xAA, 0xAA, 0xAA),
+            GameboyRgb::new(0x55, 0x55, 0x55),
+            GameboyRgb::new(0x00, 0x00, 0x00),
+        ];
+
+        Self::uniform(colors)
+    }
+
+    /// One of the palettes that can be selected during the CGB boot animation
+    pub fn preset(preset: Preset) -> Self {
+        Self::combination(preset.combination())
+    }
+
+    /// Custom palette, given as 12 RGB888 colors (e.g., `0xFFAD63`): 4 for the
+    /// BG, then 4 for each of OBJ0 and OBJ1.
+    pub fn custom(colors: [u32; 12]) -> Self {
+        let mut rgb = [GameboyRgb::white(); 12];
+
+        for (color, value) in rgb.iter_mut().zip(colors.iter()) {
+            *color = GameboyRgb::new((value >> 16) as u8, (value >> 8) as u8, *value as u8);
+        }
+
+        let mut palette = Self::uniform([GameboyRgb::white(); 4]);
+        palette.bg.copy_from_slice(&rgb[0..4]);
+        palette.obj0.copy_from_slice(&rgb[4..8]);
+        palette.obj1.copy_from_slice(&rgb[8..12]);
+
+        palette
+    }
+
+    /// Returns the palette that the CGB boot ROM picks for this cartridge.
+    ///
+    /// Only games published by Nintendo are looked up by title checksum. All
+    /// other games get the default palette.
+    pub fn for_cartridge(cartridge: &Cartridge) -> Self {
+        let data = &cartridge.data;
+
+        let nintendo = match data[0x14B] {
+            0x01 => true,
+            0x33 => &data[0x144..=0x145] == b"01",
+            _ => false,
+        };
+
+        let game = if nintendo {
+            let checksum = data[0x134..=0x143]
+                .iter()
+                .fold(0u8, |sum, b| sum.wrapping_add(*b));
+
+            Self::find_game(checksum, data[0x137])
+        } else {
+            None
+        };
+
+        match game {
+            Some(game) => Self::combination(GAME_COMBINATIONS[game]),
+            None => Self::preset(Preset::DarkGreen),
+        }
+    }
+
+    /// Find a game by title checksum, and return its index in `GAME_COMBINATIONS`.
+    ///
+    /// Games that share a checksum are told apart by the 4th letter of the title.
+    fn find_game(checksum: u8, fourth_letter: u8) -> Option<usize> {
+        let index = TITLE_CHECKSUMS.iter().position(|c| *c == checksum)?;
+
+        if index < FIRST_SHARED_CHECKSUM {
+            return Some(index);
+        }
+
+        (index - FIRST_SHARED_CHECKSUM..FOURTH_LETTERS.len())
+            .step_by(SHARED_CHECKSUMS)
+            .find(|i| FOURTH_LETTERS[*i] == fourth_letter)
+            .map(|i| FIRST_SHARED_CHECKSUM + i)
+    }
+
+    fn uniform(colors: [GameboyRgb; 4]) -> Self {
+        Self {
+            bg: colors,
+            obj0: colors,
+            obj1: colors,
+        }
+    }
+
+    /// Palettes for one of the combinations in `COMBINATIONS`
+    fn combination(index: u8) -> Self {
+        let [obj0, obj1, bg] = COMBINATIONS[index as usize];
+
+        let convert = |first: u8| {
+            let mut rgb = [GameboyRgb::white(); 4];
+            let colors = &BOOT_COLORS[first as usize..];
+
+            for (color, value) in rgb.iter_mut().zip(colors.iter()) {
+                *color = GameboyRgb::from_rgb555(*value);
+            }
+
+            rgb
+        };
+
+        Self {
+            bg: convert(bg),
+            obj0: convert(obj0),
+            obj1: convert(obj1),
+        }
+    }
+}
+
+impl Default for DmgPalette {
+    fn default() -> Self {
+        Self::dmg()
+    }
+}
+
+impl FromStr for DmgPalette {
+    type Err = Error;
+
+    /// Parses a palette name (`dmg`, `grayscale`, or a preset name), or a
+    /// custom palette given as comma-separated RGB888 hex colors. 4 colors
+    /// are used for all 3 palettes, while 12 colors set each of them.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "dmg" => return Ok(Self::dmg()),
+            "grayscale" => return Ok(Self::grayscale()),
+            _ => (),
+        }
+
+        if let Some(preset) = Preset::ALL.iter().find(|preset| preset.name() == s) {
+            return Ok(Self::preset(*preset));
+        }
+
+        let colors = s
+            .split(',')
+            .map(|color| {
+                let color = color.trim().trim_start_matches('#');
+
+                match u32::from_str_radix(color, 16) {
+                    Ok(value) if color.len() == 6 => Ok(value),
+                    _ => Err(Error::InvalidValue(format!("invalid color: {}", color))),
+                }
+            })
+            .collect::<Result<Vec<u32>>>()?;
+
+        let mut custom = [0u32; 12];
+
+        match colors.len() {
+            4 => {
+                for chunk in custom.chunks_mut(4) {
+                    chunk.copy_from_slice(&colors);
+                }
+            }
+            12 => custom.copy_from_slice(&colors),
+            _ => {
+                return Err(Error::InvalidValue(format!(
+                    "unknown palette: {} (expected a palette name, or 4 or 12 colors)",
+                    s
+                )))
+            }
+        }
+
+        Ok(Self::custom(custom))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn palette(title: &[u8], old_licensee: u8) -> DmgPalette {
+        DmgPalette::for_cartridge(&cartridge(title, old_licensee))
+    }
+
+    fn cartridge(title: &[u8], old_licensee: u8) -> Cartridge {
+        let mut data = vec![0u8; 0x8000];
+        data[0x134..0x134 + title.len()].copy_from_slice(title);
+        data[0x14B] = old_licensee;
+
+        Cartridge::from_bytes(data, false)
+    }
+
+    #[test]
+    fn title_checksum() {
+        let tetris = DmgPalette::preset(Preset::Orange);
+        let default = DmgPalette::preset(Preset::DarkGreen);
+
+        assert_eq!(palette(b"TETRIS", 0x01), tetris);
+
+        // Only Nintendo games are looked up
+        assert_eq!(palette(b"TETRIS", 0x08), default);
+        assert_eq!(palette(b"UNKNOWN", 0x01), default);
+
+        let mut new_licensee = cartridge(b"TETRIS", 0x33);
+        new_licensee.data[0x144..=0x145].copy_from_slice(b"01");
+        assert_eq!(DmgPalette::for_cartridge(&new_licensee), tetris);
+    }
+
+    fn rgb(colors: [u16; 4]) -> [GameboyRgb; 4] {
+        let mut rgb = [GameboyRgb::white(); 4];
+
+        for (color, value) in rgb.iter_mut().zip(colors.iter()) {
+            *color = GameboyRgb::from_rgb555(*value);
+        }
+
+        rgb
+    }
+
+    #[test]
+    fn shared_checksum() {
+        let red = rgb([0x7FFF, 0x421F, 0x1CF2, 0x0000]);
+        let green = rgb([0x7FFF, 0x1BEF, 0x0200, 0x0000]);
+        let blue = rgb([0x7FFF, 0x7E8C, 0x7C00, 0x0000]);
+
+        // Both titles add up to 0x61
+        let pokemon = palette(b"POKEMON BLUE", 0x01);
+        assert_eq!((pokemon.bg, pokemon.obj0, pokemon.obj1), (blue, red, blue));
+
+        let vegas = palette(b"VEGAS STAKES", 0x01);
+        assert_eq!((vegas.bg, vegas.obj0, vegas.obj1), (green, red, blue));
+
+        // A 3rd game with a shared checksum (0x46)
+        let metroid = palette(b"METROID2", 0x01);
+        assert_eq!((metroid.bg, metroid.obj1), (blue, green));
+
+        // This one uses palettes that start in the middle of a group of colors
+        let mario = palette(b"SUPER MARIOLAND", 0x01);
+        assert_eq!(mario.obj0, rgb([0x0000, 0x7FFF, 0x421F, 0x1CF2]));
+
+        // Same checksum, but no game with this 4th letter
+        assert_eq!(
+            palette(b"POKFMON BLUD", 0x01),
+            DmgPalette::preset(Preset::DarkGreen)
+        );
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!("dmg".parse::<DmgPalette>().unwrap(), DmgPalette::default());
+        assert_eq!(
+            "dark-blue".parse::<DmgPalette>().unwrap(),
+            DmgPalette::preset(Preset::DarkBlue)
+        );
+
+        let palette: DmgPalette = "FFFFFF,#AAAAAA,555555,000000".parse().unwrap();
+        assert_eq!(palette, DmgPalette::grayscale());
+
+        let palette: DmgPalette = "FFFFFF,AAAAAA,555555,000000,\
+                                   FFFFFF,FF8484,943939,000000,\
+                                   E0F8D0,88C070,346856,081820"
+            .parse()
+            .unwrap();
+        assert_eq!(palette.bg, DmgPalette::grayscale().bg);
+        assert_eq!(palette.obj0[1], GameboyRgb::new(0xFF, 0x84, 0x84));
+        assert_eq!(palette.obj1, DmgPalette::dmg().obj1);
+
+        assert!("purple".parse::<DmgPalette>().is_err());
+        assert!("FFFFFF,000000".parse::<DmgPalette>().is_err());
+        assert!("FFFFFF,AAAAAA,555555,00000G".parse::<DmgPalette>().is_err());
+    }
+}
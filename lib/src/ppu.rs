@@ -45,13 +45,26 @@
 //! This is when VRAM data can be accessed.
 //!
 //! The combination of these two periods nets us ~60 fps.
+//!
+//! ## Pixel FIFO
+//!
+//! Pixels are drawn one dot at a time during mode 3. A fetcher reads BG/window tile
+//! data into a pixel FIFO, and each dot, a pixel is shifted out of the FIFO, mixed
+//! with any sprite pixel, and pushed to the LCD. This means that the length of mode 3
+//! (and HBLANK) varies with fine scrolling, the window, and sprites on the scanline.
+//! It also means that register writes in the middle of a scanline (e.g., to SCX or
+//! BGP) take effect at the correct pixel.
+use std::collections::VecDeque;
+
 use crate::cpu::Interrupt;
+use crate::error::{Error, Result};
 use crate::memory::{MemoryRead, MemoryWrite};
+use crate::palette::DmgPalette;
 
 pub const LCD_WIDTH: usize = 160;
 pub const LCD_HEIGHT: usize = 144;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "save", derive(serde::Serialize), derive(serde::Deserialize))]
 pub struct GameboyRgb {
     pub red: u8,
@@ -60,6 +73,21 @@ pub struct GameboyRgb {
 }
 
 impl GameboyRgb {
+    pub const fn new(red: u8, green: u8, blue: u8) -> Self {
+        Self { red, green, blue }
+    }
+
+    /// Convert a CGB color (RGB555, red in the low bits) to regular RGB.
+    pub fn from_rgb555(color: u16) -> Self {
+        let red = (color & 0x001F) as u8;
+        let green = ((color & 0x03E0) >> 5) as u8;
+        let blue = ((color & 0x7C00) >> 10) as u8;
+
+        let mut rgb = Self { red, green, blue };
+        rgb.scale_to_rgb();
+        rgb
+    }
+
     pub fn white() -> Self {
         Self {
             red: 0xFF,
@@ -78,55 +106,269 @@ impl GameboyRgb {
     }
 }
 
-// Basic DMG/monochrome color palette
-static DMG_PALETTE: [GameboyRgb; 4] = [
-    // White
-    GameboyRgb {
-        red: 0xE0,
-        green: 0xF8,
-        blue: 0xD0,
-    },
-    // Light gray
-    GameboyRgb {
-        red: 0x88,
-        green: 0xC0,
-        blue: 0x70,
-    },
-    // Dark gray
-    GameboyRgb {
-        red: 0x34,
-        green: 0x68,
-        blue: 0x56,
-    },
-    // Black
-    GameboyRgb {
-        red: 0x08,
-        green: 0x18,
-        blue: 0x20,
-    },
-];
+/// Color correction applied to CGB colors.
+///
+/// CGB colors are 5 bits per channel. Scaling them linearly to 0-255 gives far
+/// more saturated colors than the real LCD, which mixes the channels and has a
+/// different response curve. The LCD modes emulate this with a color matrix
+/// applied in linear light, followed by gamma correction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorCorrection {
+    /// Scale colors linearly
+    None,
+    /// Emulate the GBC LCD
+    GbcLcd,
+    /// Emulate the GBA LCD, which is darker than the GBC LCD
+    GbaLcd,
+}
+
+impl ColorCorrection {
+    /// Returns (input gamma, luminance, color matrix) for this correction.
+    ///
+    /// Each row of the matrix gives the contribution of the linear input red,
+    /// green, and blue to one output channel.
+    fn params(&self) -> (f32, f32, [[f32; 3]; 3]) {
+        match self {
+            Self::None => (
+                1.0,
+                1.0,
+                [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+            ),
+            Self::GbcLcd => (
+                2.2,
+                0.94,
+                [
+                    [0.82, 0.24, -0.06],
+                    [0.125, 0.665, 0.21],
+                    [0.195, 0.075, 0.73],
+                ],
+            ),
+            Self::GbaLcd => (
+                2.7,
+                0.93,
+                [
+                    [0.86, 0.19, -0.05],
+                    [0.11, 0.66, 0.23],
+                    [0.1325, 0.02, 0.8475],
+                ],
+            ),
+        }
+    }
+
+    /// Convert a single RGB555 color
+    fn convert(&self, color: u16) -> GameboyRgb {
+        if *self == Self::None {
+            return GameboyRgb::from_rgb555(color);
+        }
+
+        const DISPLAY_GAMMA: f32 = 2.2;
+
+        let (gamma, luminance, matrix) = self.params();
+
+        let input = [color & 0x1F, (color >> 5) & 0x1F, (color >> 10) & 0x1F];
+        let linear = input.map(|c| (c as f32 / 31.0).powf(gamma) * luminance);
+
+        let output = matrix.map(|row| {
+            let c = row[0] * linear[0] + row[1] * linear[1] + row[2] * linear[2];
+            let c = c.clamp(0.0, 1.0).powf(1.0 / DISPLAY_GAMMA);
+
+            (c * 255.0).round() as u8
+        });
+
+        GameboyRgb::new(output[0], output[1], output[2])
+    }
+}
+
+impl std::str::FromStr for ColorCorrection {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Self::None),
+            "gbc" => Ok(Self::GbcLcd),
+            "gba" => Ok(Self::GbaLcd),
+            _ => Err(Error::InvalidValue(format!(
+                "unknown color correction: {} (expected none, gbc, or gba)",
+                s
+            ))),
+        }
+    }
+}
+
+/// Lookup table that maps every RGB555 color to its corrected RGB value.
+struct ColorTable {
+    correction: ColorCorrection,
+    colors: Box<[GameboyRgb]>,
+}
+
+impl ColorTable {
+    fn new(correction: ColorCorrection) -> Self {
+        let colors = (0..0x8000u16)
+            .map(|color| correction.convert(color))
+            .collect();
+
+        Self { correction, colors }
+    }
+
+    #[inline]
+    fn lookup(&self, color: u16) -> GameboyRgb {
+        self.colors[(color & 0x7FFF) as usize]
+    }
+}
+
+impl Default for ColorTable {
+    fn default() -> Self {
+        Self::new(ColorCorrection::None)
+    }
+}
+
+/// Layers drawn by the PPU, used to track down graphics bugs.
+///
+/// Hidden BG and window pixels are drawn as if they had color 0 (i.e., behind
+/// every sprite), while hidden sprites are not drawn at all.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LayerMask {
+    pub bg: bool,
+    pub window: bool,
+    pub sprites: bool,
+
+    /// One bit per OAM entry (40 sprites). A set bit hides that sprite.
+    pub hidden_sprites: u64,
+}
+
+impl LayerMask {
+    /// Number of sprites in OAM
+    pub const SPRITE_COUNT: u8 = 40;
+
+    /// Returns `true` if the sprite at this OAM index is drawn (i.e., neither
+    /// the sprite nor the whole sprite layer is hidden)
+    pub fn sprite_visible(&self, oam_index: u8) -> bool {
+        self.sprites && self.hidden_sprites & 1 << oam_index == 0
+    }
+
+    /// Show or hide the sprite at this OAM index
+    pub fn set_sprite_visible(&mut self, oam_index: u8, visible: bool) {
+        if oam_index >= Self::SPRITE_COUNT {
+            return;
+        }
+
+        if visible {
+            self.hidden_sprites &= !(1 << oam_index);
+        } else {
+            self.hidden_sprites |= 1 << oam_index;
+        }
+    }
+}
+
+impl Default for LayerMask {
+    fn default() -> Self {
+        Self {
+            bg: true,
+            window: true,
+            sprites: true,
+            hidden_sprites: 0,
+        }
+    }
+}
+
+/// Layout of a single pixel in the frame buffer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "save", derive(serde::Serialize), derive(serde::Deserialize))]
+pub enum PixelFormat {
+    /// 3 bytes per pixel: red, green, blue
+    Rgb888,
+    /// 4 bytes per pixel: red, green, blue, alpha (always 0xFF). This matches
+    /// `ImageData` on a canvas.
+    Rgba8888,
+    /// 32-bit native-endian value per pixel: 0xXXRRGGBB, where X is 0xFF
+    Xrgb8888,
+    /// 16-bit native-endian value per pixel: 5 bits red, 6 bits green, 5 bits blue
+    Rgb565,
+}
+
+impl PixelFormat {
+    /// Number of bytes in a single pixel
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            Self::Rgb888 => 3,
+            Self::Rgba8888 | Self::Xrgb8888 => 4,
+            Self::Rgb565 => 2,
+        }
+    }
+}
 
 /// Buffer that holds pixel data for a single frame.
+///
+/// Pixels are stored in a packed `PixelFormat`, row by row, so the raw data
+/// can be handed directly to a texture or canvas.
+#[derive(Clone)]
 #[cfg_attr(feature = "save", derive(serde::Serialize), derive(serde::Deserialize))]
 pub struct FrameBuffer {
-    pub data: Box<[GameboyRgb]>,
+    data: Box<[u8]>,
+    format: PixelFormat,
     pub(crate) ready: bool,
 }
 
 impl FrameBuffer {
     pub fn new() -> Self {
-        Self {
-            data: Box::new([GameboyRgb::white(); LCD_WIDTH * LCD_HEIGHT]),
+        Self::with_format(PixelFormat::Rgb888)
+    }
+
+    /// Create a white frame buffer that stores pixels in the given format.
+    pub fn with_format(format: PixelFormat) -> Self {
+        let mut frame_buffer = Self {
+            data: vec![0; LCD_WIDTH * LCD_HEIGHT * format.bytes_per_pixel()].into_boxed_slice(),
+            format,
             ready: false,
-        }
+        };
+
+        frame_buffer.fill(GameboyRgb::white());
+        frame_buffer
+    }
+
+    /// Pixel format of this buffer
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    /// Raw pixel data, starting with the top-left pixel.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Number of bytes in a single row of pixels
+    pub fn pitch(&self) -> usize {
+        LCD_WIDTH * self.format.bytes_per_pixel()
     }
 
     /// Read a single pixel from the buffer.
     ///
-    /// `x` is the "column", `y` is the "row".
+    /// `x` is the "column", `y` is the "row". With `PixelFormat::Rgb565`, the
+    /// low bits of each color are lost.
     #[inline]
     pub fn read(&self, x: usize, y: usize) -> GameboyRgb {
-        self.data[y * LCD_WIDTH + x]
+        let bpp = self.format.bytes_per_pixel();
+        let pixel = &self.data[(y * LCD_WIDTH + x) * bpp..][..bpp];
+
+        match self.format {
+            PixelFormat::Rgb888 | PixelFormat::Rgba8888 => {
+                GameboyRgb::new(pixel[0], pixel[1], pixel[2])
+            }
+            PixelFormat::Xrgb8888 => {
+                let value = u32::from_ne_bytes([pixel[0], pixel[1], pixel[2], pixel[3]]);
+                GameboyRgb::new((value >> 16) as u8, (value >> 8) as u8, value as u8)
+            }
+            PixelFormat::Rgb565 => {
+                let value = u16::from_ne_bytes([pixel[0], pixel[1]]);
+                let (red, green, blue) = (value >> 11, (value >> 5) & 0x3F, value & 0x1F);
+
+                GameboyRgb::new(
+                    (red << 3 | red >> 2) as u8,
+                    (green << 2 | green >> 4) as u8,
+                    (blue << 3 | blue >> 2) as u8,
+                )
+            }
+        }
     }
 
     /// Write a single pixel to the buffer.
@@ -134,7 +376,115 @@ impl FrameBuffer {
     /// `x` is the "column", `y` is the "row".
     #[inline]
     pub fn write(&mut self, x: usize, y: usize, pixel: GameboyRgb) {
-        self.data[y * LCD_WIDTH + x] = pixel;
+        let bpp = self.format.bytes_per_pixel();
+        let offset = (y * LCD_WIDTH + x) * bpp;
+
+        Self::encode(self.format, pixel, &mut self.data[offset..offset + bpp]);
+    }
+
+    /// Set every pixel in the buffer to the same color.
+    pub fn fill(&mut self, pixel: GameboyRgb) {
+        let format = self.format;
+
+        for dst in self.data.chunks_exact_mut(format.bytes_per_pixel()) {
+            Self::encode(format, pixel, dst);
+        }
+    }
+
+    /// Copy the pixels of another frame buffer into this one, adopting its format.
+    pub fn copy_from(&mut self, other: &FrameBuffer) {
+        if self.data.len() == other.data.len() {
+            self.data.copy_from_slice(&other.data);
+        } else {
+            self.data = other.data.clone();
+        }
+
+        self.format = other.format;
+    }
+
+    #[inline]
+    fn encode(format: PixelFormat, pixel: GameboyRgb, dst: &mut [u8]) {
+        let GameboyRgb { red, green, blue } = pixel;
+
+        match format {
+            PixelFormat::Rgb888 => dst.copy_from_slice(&[red, green, blue]),
+            PixelFormat::Rgba8888 => dst.copy_from_slice(&[red, green, blue, 0xFF]),
+            PixelFormat::Xrgb8888 => {
+                let value = 0xFF00_0000 | (red as u32) << 16 | (green as u32) << 8 | blue as u32;
+                dst.copy_from_slice(&value.to_ne_bytes());
+            }
+            PixelFormat::Rgb565 => {
+                let value = (red as u16 >> 3) << 11 | (green as u16 >> 2) << 5 | blue as u16 >> 3;
+                dst.copy_from_slice(&value.to_ne_bytes());
+            }
+        }
+    }
+}
+
+/// Palette used to color tiles in the VRAM viewer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TilePalette {
+    /// BG palette 0-7 (CGB), or BGP (DMG)
+    Bg(u8),
+    /// Sprite palette 0-7 (CGB), or OBP0/OBP1 (DMG)
+    Obj(u8),
+}
+
+/// One of the two BG/window tile maps in VRAM
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TileMap {
+    /// 0x9800-0x9BFF
+    Low,
+    /// 0x9C00-0x9FFF
+    High,
+}
+
+impl TileMap {
+    /// Size of a tile map, in pixels
+    pub const SIZE: usize = 256;
+
+    /// Address of the first entry in this map
+    pub fn addr(&self) -> u16 {
+        match self {
+            Self::Low => 0x9800,
+            Self::High => 0x9C00,
+        }
+    }
+}
+
+/// Image decoded from VRAM, used for debugging.
+///
+/// Pixels are stored as RGBA8888, row by row.
+#[derive(Clone)]
+pub struct VramImage {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>,
+}
+
+impl VramImage {
+    fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            data: vec![0; width * height * 4],
+        }
+    }
+
+    /// Number of bytes in a single row of pixels
+    pub fn pitch(&self) -> usize {
+        self.width * 4
+    }
+
+    /// Read a single pixel from the image.
+    pub fn read(&self, x: usize, y: usize) -> GameboyRgb {
+        let pixel = &self.data[(y * self.width + x) * 4..][..4];
+        GameboyRgb::new(pixel[0], pixel[1], pixel[2])
+    }
+
+    fn write(&mut self, x: usize, y: usize, pixel: GameboyRgb) {
+        let offset = (y * self.width + x) * 4;
+        self.data[offset..offset + 4].copy_from_slice(&[pixel.red, pixel.green, pixel.blue, 0xFF]);
     }
 }
 
@@ -339,6 +689,91 @@ struct Sprite {
     pub x: u8,
     pub tile_number: u8,
     pub attr: u8,
+
+    /// Index of the sprite in OAM
+    pub oam_index: u8,
+
+    /// Set once the sprite has been fetched on this scanline
+    pub fetched: bool,
+}
+
+/// A single BG/window pixel in the BG FIFO
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "save", derive(serde::Serialize), derive(serde::Deserialize))]
+struct BgPixel {
+    /// Color index (2 bits)
+    color: u8,
+
+    /// Palette number (CGB only)
+    palette: u8,
+
+    /// BG-to-OAM priority (CGB only)
+    priority: bool,
+
+    /// Set if this pixel belongs to the window
+    window: bool,
+}
+
+/// A single sprite pixel in the sprite FIFO
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "save", derive(serde::Serialize), derive(serde::Deserialize))]
+struct SpritePixel {
+    /// Color index (2 bits). Color index 0 is transparent.
+    color: u8,
+
+    /// DMG: OBP0 or OBP1, CGB: palette number
+    palette: u8,
+
+    /// If `true`, the sprite is drawn over non-zero BG colors
+    priority: bool,
+
+    /// Index of the sprite in OAM
+    oam_index: u8,
+}
+
+impl SpritePixel {
+    fn transparent() -> Self {
+        Self {
+            color: 0,
+            palette: 0,
+            priority: false,
+            oam_index: u8::MAX,
+        }
+    }
+}
+
+/// Fetches BG/window tile data into the BG FIFO, one tile row at a time
+#[cfg_attr(feature = "save", derive(serde::Serialize), derive(serde::Deserialize))]
+struct Fetcher {
+    /// Number of dots spent on the current tile fetch
+    dots: u16,
+
+    /// Tile column of the next fetch, relative to the left edge of the screen
+    /// (BG) or the window
+    x: u8,
+
+    /// If `true`, tiles are fetched from the window tile map
+    window: bool,
+
+    /// Tile number and attributes of the current tile
+    tile_number: u8,
+    attr: u8,
+
+    /// Tile data (lower and upper byte) of the current tile row
+    data: [u8; 2],
+}
+
+impl Fetcher {
+    fn new() -> Self {
+        Self {
+            dots: 0,
+            x: 0,
+            window: false,
+            tile_number: 0,
+            attr: 0,
+            data: [0; 2],
+        }
+    }
 }
 
 #[cfg_attr(feature = "save", derive(serde::Serialize), derive(serde::Deserialize))]
@@ -399,6 +834,10 @@ pub struct Ppu {
     /// reset at the end of the frame
     window_line_counter: u8,
 
+    /// Set once LY has matched WY in this frame. The window is only drawn
+    /// after that.
+    wy_triggered: bool,
+
     /// Color palette index registers (0xFF68-0xFF6B)
     bcps: u8,
     ocps: u8,
@@ -417,18 +856,66 @@ pub struct Ppu {
     /// Same as above, but for sprites
     sprite_palette_ram: Box<[u8]>,
 
+    /// Colors used to render DMG games. This is a display setting, so it is
+    /// not part of the save state.
+    #[cfg_attr(feature = "save", serde(skip))]
+    dmg_palette: DmgPalette,
+
+    /// Maps CGB colors to RGB, with color correction applied. This is a
+    /// display setting, so it is not part of the save state.
+    #[cfg_attr(feature = "save", serde(skip))]
+    color_table: ColorTable,
+
+    /// Layers to draw. This is a debugging aid, so it is not part of the save
+    /// state.
+    #[cfg_attr(feature = "save", serde(skip))]
+    layer_mask: LayerMask,
+
     /// Buffer for the current frame
     frame_buffer: FrameBuffer,
 
     /// Sprites that are visible on this scanline
     sprites: Vec<Sprite>,
 
+    /// Pixel FIFOs for BG/window and sprite pixels
+    bg_fifo: VecDeque<BgPixel>,
+    sprite_fifo: VecDeque<SpritePixel>,
+
+    /// Fetcher for BG/window pixels
+    fetcher: Fetcher,
+
+    /// Number of pixels pushed to the LCD on this scanline
+    lx: u8,
+
+    /// Number of pixels left to discard from the BG FIFO
+    discard: u8,
+
+    /// Number of dots left until the pixel pipeline resumes (e.g., after a
+    /// sprite fetch)
+    delay: u16,
+
+    /// `true` while pixels are being drawn on this scanline (i.e., mode 3)
+    drawing: bool,
+
+    /// Number of dots in HBLANK on this scanline
+    hblank_dots: u16,
+
     /// Current dot being rendered in this scanline
     dot: u16,
 
     /// Previous STAT interrupt state
     prev_stat_interrupt: bool,
 
+    /// Set when a write to STAT triggers a spurious STAT interrupt (DMG only)
+    stat_write_interrupt: bool,
+
+    /// `true` on the first scanline after the LCD is turned on
+    first_line: bool,
+
+    /// Number of dots since the LCD was turned off, or since the last blank
+    /// frame while it is off
+    lcd_off_dots: u32,
+
     /// If `true`, operate in CGB mode
     cgb: bool,
 }
@@ -451,10 +938,18 @@ impl Ppu {
     const VBLANK_START_LINE: u8 = 144;
     const TOTAL_LINES: u8 = 154;
     const OAM_SCAN_DOTS: u16 = 80;
+
+    /// Minimum number of dots in mode 3 (i.e., no scrolling, window, or sprites),
+    /// and the corresponding number of dots in HBLANK
     const OAM_READ_DOTS: u16 = 172;
     const HBLANK_DOTS: u16 = 204;
+
+    /// Number of dots needed to fetch a single row of tile data
+    const TILE_FETCH_DOTS: u16 = 6;
+
     const VBLANK_DOTS: u16 =
         Self::DOTS_PER_LINE * (Self::TOTAL_LINES - Self::VBLANK_START_LINE) as u16;
+    const FRAME_DOTS: u32 = Self::DOTS_PER_LINE as u32 * Self::TOTAL_LINES as u32;
 
     pub fn new(cgb: bool, boot_rom: bool) -> Self {
         Self {
@@ -474,93 +969,132 @@ impl Ppu {
             wy: 0,
             wx: 0,
             window_line_counter: 0,
+            wy_triggered: false,
             bcps: 0,
             ocps: 0,
             opri: 0,
             bg_palette_ram: Box::new([0xFF; 64]),
             sprite_palette_ram: Box::new([0xFF; 64]),
+            dmg_palette: DmgPalette::default(),
+            color_table: ColorTable::default(),
+            layer_mask: LayerMask::default(),
             frame_buffer: FrameBuffer::new(),
             sprites: Vec::with_capacity(10),
+            bg_fifo: VecDeque::with_capacity(8),
+            sprite_fifo: VecDeque::with_capacity(8),
+            fetcher: Fetcher::new(),
+            lx: 0,
+            discard: 0,
+            delay: 0,
+            drawing: false,
+            hblank_dots: Self::HBLANK_DOTS,
             dot: 0,
             prev_stat_interrupt: false,
+            stat_write_interrupt: false,
+            first_line: false,
+            lcd_off_dots: 0,
             cgb,
         }
     }
 
-    /// Returns the next: (dot, scanline, STAT mode)
-    fn get_next_dot(&self, cycles: u16, speed: bool) -> (u16, u8, StatMode) {
-        let mut line = self.ly;
-        let mut dot = self.dot;
-
-        // Figure out the number of pixels to render in this step
-        let dots = if speed {
-            // If we are in double-speed mode, we render a pixel every 2 cycles
-            cycles / 2
-        } else {
-            cycles
-        };
-
-        dot += dots;
-
-        if dot >= Self::DOTS_PER_LINE {
-            // Move to the next scanline
-            line += 1;
-            dot -= Self::DOTS_PER_LINE;
-        }
-
-        if line == Self::TOTAL_LINES {
-            // Start of new frame
-            line = 0;
-        }
-
-        // Figure out which stat mode we are in based on line and dot.
-        //
-        // Recall that we have 456 dots in a line.
-        let mode = if line < Self::VBLANK_START_LINE {
-            if dot < Self::OAM_SCAN_DOTS {
-                StatMode::OamScan
-            } else if dot >= Self::OAM_SCAN_DOTS && dot < Self::OAM_SCAN_DOTS + Self::OAM_READ_DOTS
-            {
-                StatMode::OamRead
-            } else {
+    /// Returns the current STAT mode, based on the scanline, dot, and whether
+    /// pixels are still being drawn on this scanline.
+    fn current_mode(&self) -> StatMode {
+        if self.ly >= Self::VBLANK_START_LINE {
+            StatMode::Vblank
+        } else if self.dot < Self::OAM_SCAN_DOTS {
+            // There is no OAM scan on the first scanline after the LCD is
+            // turned on, and the mode reads as HBLANK instead
+            if self.first_line {
                 StatMode::Hblank
+            } else {
+                StatMode::OamScan
             }
+        } else if self.drawing {
+            StatMode::OamRead
         } else {
-            // VBLANK
-            StatMode::Vblank
-        };
-
-        (dot, line, mode)
+            StatMode::Hblank
+        }
     }
 
     /// Update the PPU status registers based on current cycle and CPU speed.
     ///
-    /// This function is called once per CPU step from the main frame loop. The
-    /// function is called *after* the CPU step completes. The value passed in
-    /// for `cycles` is the number of cycles spent in the CPU.
+    /// This function is called by the memory bus once per M-cycle. The PPU is
+    /// advanced one dot at a time, so that pixels are drawn using the register
+    /// values at the time each pixel is pushed to the LCD.
     ///
     /// If any interrupts need to be triggered, they are pushed to the input `interrupts`
     /// vector.
     pub fn step(&mut self, cycles: u16, speed: bool, interrupts: &mut Vec<Interrupt>) {
-        let (dot, line, mode) = self.get_next_dot(cycles, speed);
+        // If we are in double-speed mode, we render a pixel every 2 cycles
+        let dots = if speed { cycles / 2 } else { cycles };
+
+        if !self.lcdc.lcd_display_enable() {
+            // The PPU is stopped while the LCD is off, and the screen is blank.
+            // Keep producing blank frames, so that the frontend still receives
+            // frames.
+            self.lcd_off_dots += dots as u32;
+
+            if self.lcd_off_dots >= Self::FRAME_DOTS {
+                self.lcd_off_dots -= Self::FRAME_DOTS;
+                self.blank_frame();
+            }
 
-        self.dot = dot;
-        self.ly = line;
+            return;
+        }
+
+        for _ in 0..dots {
+            self.tick();
+        }
 
         // Update the internal PPU status
         //
         // This also returns which interrupts need to be triggered
+        let mode = self.current_mode();
         let stat_mode_change = self.update_status(mode, interrupts);
 
-        if self.lcdc.lcd_display_enable() && stat_mode_change {
-            // Render data to the frame
-            self.render();
+        if stat_mode_change && mode == StatMode::Vblank {
+            // Mark the frame as ready for rendering
+            self.frame_buffer.ready = true;
+
+            // Reset the window line counter
+            self.window_line_counter = 0;
+            self.wy_triggered = false;
+        }
+    }
+
+    /// Advance the PPU by a single dot.
+    fn tick(&mut self) {
+        self.dot += 1;
+
+        if self.dot == Self::DOTS_PER_LINE {
+            // Move to the next scanline
+            self.dot = 0;
+            self.ly += 1;
+            self.first_line = false;
+
+            if self.ly == Self::TOTAL_LINES {
+                // Start of new frame
+                self.ly = 0;
+            }
+        }
+
+        if self.ly >= Self::VBLANK_START_LINE {
+            return;
+        }
+
+        if self.dot == Self::OAM_SCAN_DOTS {
+            self.start_drawing();
+        }
+
+        if self.drawing {
+            self.draw();
         }
     }
 
     /// Returns: (stat_mode_change, vblank_interrupt, stat_interrupt)
     fn update_status(&mut self, mode: StatMode, interrupts: &mut Vec<Interrupt>) -> bool {
-        let ly_coincidence = self.ly == self.lyc;
+        let ly_coincidence = self.ly_compare() == Some(self.lyc);
 
         let mut stat = mode as u8;
         if ly_coincidence {
@@ -582,15 +1116,28 @@ impl Ppu {
         //
         // 1. LY coincidence interrupt is enabled and changed from false to true
         // 2. STAT mode interrupt is enabled and has changed
+        //
+        // All conditions are ORed into a single STAT interrupt line, and the interrupt
+        // only fires when the line goes from low to high. Mode 3 is not a source.
         let stat_interrupt = (ly_coincidence && self.stat.ly_enabled()) || {
             match mode {
                 StatMode::Hblank => self.stat.hblank_enabled(),
-                StatMode::Vblank => self.stat.vblank_enabled(),
-                StatMode::OamScan | StatMode::OamRead => self.stat.oam_enabled(),
+                StatMode::Vblank => {
+                    // The OAM condition is also checked at the start of VBLANK
+                    self.stat.vblank_enabled()
+                        || (self.ly == Self::VBLANK_START_LINE
+                            && self.dot < 4
+                            && self.stat.oam_enabled())
+                }
+                StatMode::OamScan => self.stat.oam_enabled(),
+                StatMode::OamRead => false,
             }
         };
 
-        if !self.prev_stat_interrupt && stat_interrupt {
+        // A STAT write on DMG can trigger an interrupt (see `Ppu::write_stat`)
+        let stat_write_interrupt = std::mem::take(&mut self.stat_write_interrupt);
+
+        if (!self.prev_stat_interrupt && stat_interrupt) || stat_write_interrupt {
             interrupts.push(Interrupt::LcdStat);
         }
 
@@ -602,78 +1149,348 @@ impl Ppu {
         stat_mode_change
     }
 
-    /// Given a number of cycles, returns the _next_ mode and number of cycles
-    /// the PPU would remain in that mode.
-    pub fn next_mode(&self, cycles: u16, speed: bool) -> (StatMode, u16) {
-        let (.., mode) = self.get_next_dot(cycles, speed);
-
-        // Determine the number of cycles the PPU would spend in the next mode
-        let dots = match mode {
-            StatMode::OamScan => Self::OAM_SCAN_DOTS,
-            StatMode::OamRead => Self::OAM_READ_DOTS,
-            StatMode::Hblank => Self::HBLANK_DOTS,
-            StatMode::Vblank => Self::VBLANK_DOTS,
-        };
-
-        let cycles_in_mode = if speed { dots * 2 } else { dots };
-
-        (mode, cycles_in_mode as u16)
+    /// Returns the value of the LY register.
+    ///
+    /// LY only reads 153 for the first M-cycle of the last scanline. It reads 0
+    /// for the rest of that scanline, and the first scanline of the next frame.
+    fn ly_register(&self) -> u8 {
+        if self.ly == Self::TOTAL_LINES - 1 && self.dot >= 4 {
+            0
+        } else {
+            self.ly
+        }
     }
 
-    /// Render pixel data to the internal frame buffer
+    /// Returns the value that LYC is compared against, if any.
     ///
-    /// Once we get to VBLANK, we mark the frame buffer as ready for
-    /// rendering.
-    fn render(&mut self) {
-        match self.stat.mode() {
-            StatMode::OamRead => {
-                // At the end of OAM scan/start of OAM read, build a list of
-                // visible sprites on this scanline. OAM is locked in this mode.
-                self.sprites.clear();
-                self.find_visible_sprites();
-            }
-            StatMode::Hblank => {
-                // If we are at the end of OAM read/start of HBLANK, render a
-                // scanline worth of BG and sprites to the frame buffer.
-                //
-                // Both VRAM and OAM are locked in this mode.
-                self.render_scanline();
-
-                // If the window has been drawn on this scanline, increment the
-                // internal window line counter
-                let window_drawn = self.lcdc.window_display_enable()
-                    && self.ly >= self.wy
-                    && 159 >= self.wx.wrapping_sub(7);
-
-                if window_drawn {
-                    self.window_line_counter += 1;
-                }
-            }
-            StatMode::Vblank => {
-                // Mark the frame as ready for rendering
-                self.frame_buffer.ready = true;
+    /// Whenever LY changes, there is a single M-cycle where LY is not compared
+    /// against LYC. This happens twice on the last scanline: once when it starts,
+    /// and once when LY switches to 0.
+    fn ly_compare(&self) -> Option<u8> {
+        match (self.ly, self.dot) {
+            (0, _) => Some(0),
+            (153, 4..=7) => Some(153),
+            (153, 8..=11) => None,
+            (153, _) if self.dot >= 12 => Some(0),
+            (_, 0..=3) => None,
+            (ly, _) => Some(ly),
+        }
+    }
 
-                // Reset the window line counter
-                self.window_line_counter = 0;
+    /// Write to the STAT register.
+    ///
+    /// On DMG, a write to STAT behaves as if all STAT interrupt sources were
+    /// enabled for one cycle. If any condition other than mode 3 is met at that
+    /// point, a spurious STAT interrupt is triggered.
+    fn write_stat(&mut self, value: u8) {
+        if !self.cgb && self.lcdc.lcd_display_enable() && !self.prev_stat_interrupt {
+            let ly_coincidence = self.ly_compare() == Some(self.lyc);
+
+            if ly_coincidence || self.stat.mode() != StatMode::OamRead {
+                self.stat_write_interrupt = true;
+                self.prev_stat_interrupt = true;
             }
-            _ => (),
         }
+
+        // Lower 3 bits are read-only, and bit 7 is unused
+        let value = value & 0x78;
+        self.stat.raw = value | self.stat.raw & 0x07;
     }
 
-    /// Find all visible sprites on this scanline.
+    /// Turn off the LCD.
     ///
-    /// Sprites will be sorted according to required priority:
+    /// This stops the PPU, resets LY, and puts the PPU in HBLANK.
+    fn lcd_off(&mut self) {
+        self.ly = 0;
+        self.dot = 0;
+        self.drawing = false;
+        self.lcd_off_dots = 0;
+
+        self.stat.raw &= !0x03;
+        self.prev_stat_interrupt = false;
+    }
+
+    /// Turn on the LCD.
     ///
-    /// CGB mode: in OAM order
-    /// DMG mode: sorted by x-pos
-    fn find_visible_sprites(&mut self) {
-        let scanline = self.ly;
+    /// The PPU starts at the beginning of the first scanline, but without an
+    /// OAM scan (see `Ppu::current_mode`).
+    fn lcd_on(&mut self) {
+        self.ly = 0;
+        self.dot = 0;
+        self.first_line = true;
+
+        self.window_line_counter = 0;
+        self.wy_triggered = false;
+    }
 
-        let size = if self.lcdc.sprite_size() { 16 } else { 8 };
+    /// Given a number of cycles, returns the _next_ mode and number of cycles
+    /// the PPU would remain in that mode.
+    ///
+    /// The length of mode 3 (and thus HBLANK) is only known once all pixels of
+    /// the scanline have been drawn. Until then, the minimum length of mode 3
+    /// is assumed.
+    pub fn next_mode(&self, cycles: u16, speed: bool) -> (StatMode, u16) {
+        let dots = if speed { cycles / 2 } else { cycles };
 
-        for chunk in self.oam.chunks_exact(4) {
-            let y = chunk[0];
-            let x = chunk[1];
+        let mut line = self.ly;
+        let mut dot = self.dot + dots;
+
+        if dot >= Self::DOTS_PER_LINE {
+            line += 1;
+            dot -= Self::DOTS_PER_LINE;
+
+            if line == Self::TOTAL_LINES {
+                line = 0;
+            }
+        }
+
+        // Determine the next mode, and the number of dots the PPU would spend in it
+        let (mode, dots) = if line >= Self::VBLANK_START_LINE {
+            (StatMode::Vblank, Self::VBLANK_DOTS)
+        } else if dot < Self::OAM_SCAN_DOTS {
+            (StatMode::OamScan, Self::OAM_SCAN_DOTS)
+        } else if line == self.ly && self.dot >= Self::OAM_SCAN_DOTS && !self.drawing {
+            (StatMode::Hblank, self.hblank_dots)
+        } else {
+            (StatMode::OamRead, Self::OAM_READ_DOTS)
+        };
+
+        let cycles_in_mode = if speed { dots * 2 } else { dots };
+
+        (mode, cycles_in_mode as u16)
+    }
+
+    /// Start drawing pixels on this scanline (i.e., start of mode 3).
+    fn start_drawing(&mut self) {
+        // At the end of OAM scan/start of OAM read, build a list of
+        // visible sprites on this scanline. OAM is locked in this mode.
+        self.sprites.clear();
+        self.find_visible_sprites();
+
+        // The window can only be drawn once LY has matched WY in this frame
+        if self.ly == self.wy {
+            self.wy_triggered = true;
+        }
+
+        self.bg_fifo.clear();
+        self.sprite_fifo.clear();
+        self.fetcher = Fetcher::new();
+        self.lx = 0;
+
+        // Pixels scrolled off the left edge of the screen are still shifted
+        // out of the FIFO, and are discarded
+        self.discard = self.scx & 0x7;
+
+        // The first tile fetch of every scanline is thrown away
+        self.delay = Self::TILE_FETCH_DOTS;
+
+        self.drawing = true;
+        self.hblank_dots = Self::HBLANK_DOTS;
+    }
+
+    /// Finish drawing this scanline (i.e., start of HBLANK).
+    fn finish_drawing(&mut self) {
+        self.drawing = false;
+        self.hblank_dots = Self::DOTS_PER_LINE - self.dot;
+
+        // If the window has been drawn on this scanline, increment the
+        // internal window line counter
+        if self.fetcher.window {
+            self.window_line_counter += 1;
+        }
+    }
+
+    /// Run the pixel pipeline for a single dot of mode 3.
+    ///
+    /// On every dot, a pixel is shifted out of the BG FIFO (and the sprite FIFO, if
+    /// it contains any pixels) and pushed to the LCD, while the fetcher refills the
+    /// BG FIFO one tile row at a time. Pixels are only shifted out while the BG FIFO
+    /// is non-empty, so mode 3 is extended by:
+    ///
+    /// 1. Discarding the first `SCX % 8` pixels of the scanline
+    /// 2. Restarting the fetcher once the window starts
+    /// 3. Pausing the pipeline to fetch each sprite on this scanline
+    fn draw(&mut self) {
+        if self.lx as usize == LCD_WIDTH {
+            self.finish_drawing();
+            return;
+        }
+
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        // Once the window starts, the BG pixels in the FIFO are thrown away, and
+        // the fetcher switches over to the window tile map
+        if self.lcdc.window_display_enable()
+            && self.wy_triggered
+            && !self.fetcher.window
+            && self.lx as u16 + 7 >= self.wx as u16
+        {
+            self.bg_fifo.clear();
+            self.fetcher = Fetcher::new();
+            self.fetcher.window = true;
+
+            // If WX < 7, the window is partially scrolled off the left edge
+            self.discard = 7u8.saturating_sub(self.wx);
+        }
+
+        // If a sprite starts at this pixel, wait for the fetcher to finish
+        // fetching the current BG tile, and then fetch the sprite
+        if self.lcdc.sprite_enable() && self.discard == 0 {
+            if let Some(idx) = self.next_sprite() {
+                if self.fetcher.dots >= Self::TILE_FETCH_DOTS - 1 {
+                    self.fetch_sprite(idx);
+                    self.delay = Self::TILE_FETCH_DOTS - 1;
+                } else {
+                    self.fetcher_step();
+                }
+
+                return;
+            }
+        }
+
+        if let Some(bg) = self.bg_fifo.pop_front() {
+            let sprite = self.sprite_fifo.pop_front();
+
+            if self.discard > 0 {
+                self.discard -= 1;
+            } else {
+                self.render_pixel(bg, sprite);
+                self.lx += 1;
+            }
+        }
+
+        self.fetcher_step();
+    }
+
+    /// Run the BG/window fetcher for a single dot.
+    ///
+    /// Fetching a tile row takes 6 dots: 2 for each of the tile number, the lower
+    /// byte of tile data, and the upper byte of tile data. Once done, the row is
+    /// pushed to the BG FIFO as soon as it is empty.
+    fn fetcher_step(&mut self) {
+        if self.fetcher.dots < Self::TILE_FETCH_DOTS {
+            self.fetcher.dots += 1;
+
+            match self.fetcher.dots {
+                2 => self.fetch_tile_number(),
+                4 => self.fetcher.data[0] = self.fetch_tile_data(0),
+                6 => self.fetcher.data[1] = self.fetch_tile_data(1),
+                _ => (),
+            }
+        }
+
+        if self.fetcher.dots == Self::TILE_FETCH_DOTS && self.bg_fifo.is_empty() {
+            let [lower, upper] = self.fetcher.data;
+            let attr = self.fetcher.attr;
+            let palette = attr & 0x07; // bits 0-2
+            let horizontal_flip = (attr & (1 << 5)) != 0; // bit 5
+            let priority = self.cgb && (attr & (1 << 7)) != 0; // bit 7
+            let window = self.fetcher.window;
+
+            for pixel in 0..8 {
+                // The leftmost pixel is tracked in bit 7 of each byte
+                let bit = if horizontal_flip { pixel } else { 7 - pixel };
+
+                self.bg_fifo.push_back(BgPixel {
+                    color: (upper >> bit & 1) << 1 | (lower >> bit & 1),
+                    palette,
+                    priority,
+                    window,
+                });
+            }
+
+            self.fetcher.dots = 0;
+            self.fetcher.x = self.fetcher.x.wrapping_add(1);
+        }
+    }
+
+    /// Returns the y position of the current scanline in the BG or window tile map
+    #[inline]
+    fn fetcher_y(&self) -> u8 {
+        if self.fetcher.window {
+            self.window_line_counter
+        } else {
+            self.ly.wrapping_add(self.scy)
+        }
+    }
+
+    /// Fetch the tile number (bank 0) and tile attributes (bank 1) of the next tile
+    /// from the BG or window tile map.
+    fn fetch_tile_number(&mut self) {
+        let (tile_map_base, tile_x) = if self.fetcher.window {
+            (self.lcdc.window_tile_map(), self.fetcher.x)
+        } else {
+            (
+                self.lcdc.bg_tile_map(),
+                (self.scx / 8).wrapping_add(self.fetcher.x),
+            )
+        };
+
+        let tile_y = self.fetcher_y() / 8;
+        let addr = tile_map_base + tile_y as u16 * 32 + (tile_x & 31) as u16;
+
+        self.fetcher.tile_number = self.vram.read_bank(0, addr);
+        self.fetcher.attr = if self.cgb {
+            self.vram.read_bank(1, addr)
+        } else {
+            // No 2nd bank for tile attributes in DMG mode
+            0
+        };
+    }
+
+    /// Fetch a single byte (0: lower, 1: upper) of the current row of the tile
+    /// that is being fetched.
+    fn fetch_tile_data(&self, byte: u16) -> u8 {
+        let tile_number = self.fetcher.tile_number as u16;
+        let attr = self.fetcher.attr;
+
+        let tile_data_bank = (attr & (1 << 3)) >> 3; // bit 3
+        let vertical_flip = (attr & (1 << 6)) != 0; // bit 6
+
+        let mut tile_pixel_y = self.fetcher_y() % 8;
+        if vertical_flip {
+            tile_pixel_y = 7 - tile_pixel_y;
+        }
+
+        let addr = self.bg_tile_addr(tile_number) + tile_pixel_y as u16 * 2 + byte;
+
+        self.vram.read_bank(tile_data_bank, addr)
+    }
+
+    /// Returns the address of a BG/window tile in VRAM
+    #[inline]
+    fn bg_tile_addr(&self, tile_number: u16) -> u16 {
+        // Select base address for BG tile data based on LCDC register
+        if !self.lcdc.bg_tile_data_select() {
+            0x8000 + tile_number * 16
+        } else if tile_number <= 127 {
+            0x9000 + tile_number * 16
+        } else {
+            // For "signed" tiles in 8800 mode:
+            //
+            // * Tile 128 -> 0x8800-0x880F
+            // * Tile 255 -> 0x8FF0-0x8FFF
+            0x8800 + (tile_number - 128) * 16
+        }
+    }
+
+    /// Find all visible sprites on this scanline.
+    ///
+    /// Sprites will be sorted according to required priority:
+    ///
+    /// CGB mode: in OAM order
+    /// DMG mode: sorted by x-pos
+    fn find_visible_sprites(&mut self) {
+        let scanline = self.ly;
+
+        let size = if self.lcdc.sprite_size() { 16 } else { 8 };
+
+        for (oam_index, chunk) in self.oam.chunks_exact(4).enumerate() {
+            let y = chunk[0];
+            let x = chunk[1];
             let tile_number = chunk[2];
             let attr = chunk[3];
 
@@ -701,6 +1518,8 @@ impl Ppu {
                     x,
                     tile_number,
                     attr,
+                    oam_index: oam_index as u8,
+                    fetched: false,
                 });
             }
         }
@@ -714,326 +1533,181 @@ impl Ppu {
         }
     }
 
-    /// Render a single pixel to the frame buffer (screen).
+    /// Returns the index of the next sprite to fetch, if a sprite starts at the
+    /// current pixel.
     ///
-    /// This is split into rendering the BG/window pixel and rendering the sprite
-    /// pixel. Note that sprites are more often layered on top of the BG, depending
-    /// on color and priority.
-    fn render_pixel(&mut self, pixel: u8) {
-        let scanline = self.ly;
-
-        // Select base address for BG and window tile maps based on LCDC register
-        let bg_tile_map_base: u16 = self.lcdc.bg_tile_map();
-        let window_tile_map_base: u16 = self.lcdc.window_tile_map();
-
-        let mut pixel_data;
-        let bg_priority;
-        let bg_color_index;
-
-        // Fetch pixel data for the BG or window, depending on which is currently active.
-        //
-        // Conditions:
-        //
-        // 1. If the priority bit is set, the BG will _always_ have priority over sprites
-        // 2. If priority bit is reset (CGB): BG and window are still rendered, but sprites get priority
-        // 3. If priority bit is reset (DMG): BG and window turn white and sprites get priority
-        if self.lcdc.bg_priority() || self.cgb {
-            // Check if this pixel is inside the window area
-            let in_window = self.lcdc.window_display_enable()
-                && scanline >= self.wy
-                && pixel >= self.wx.wrapping_sub(7);
-
-            let (data, priority, color_index) = if !in_window {
-                // Normal BG pixel
-                let bg_pixel_x = pixel.wrapping_add(self.scx);
-                let bg_pixel_y = scanline.wrapping_add(self.scy);
-                self.fetch_bg_pixel_data(bg_pixel_x, bg_pixel_y, bg_tile_map_base)
-            } else {
-                // Window pixel
-                let pixel_x = pixel - self.wx.wrapping_sub(7);
-                let pixel_y = self.window_line_counter;
-                self.fetch_bg_pixel_data(pixel_x, pixel_y, window_tile_map_base)
-            };
-
-            pixel_data = Some(data);
+    /// Sprites that are partially off the left edge of the screen are fetched
+    /// at the first pixel. Sprites that are entirely off the right edge are
+    /// never fetched.
+    fn next_sprite(&self) -> Option<usize> {
+        self.sprites.iter().position(|sprite| {
+            !sprite.fetched && sprite.x < 168 && sprite.x.saturating_sub(8) <= self.lx
+        })
+    }
 
-            // On CGB, if LCDC priority is reset, BG & window lose priority
-            bg_priority = self.lcdc.bg_priority() && priority;
-            bg_color_index = color_index;
-        } else {
-            // On DMG, reset the BG to white in non-priority mode
-            pixel_data = Some(DMG_PALETTE[0]);
-            bg_priority = false;
-            bg_color_index = 0;
-        }
+    /// Fetch a row of sprite pixel data, and merge it into the sprite FIFO.
+    ///
+    /// Sprites can be either a single tile (8x8) or two vertically stacked tiles
+    /// (8x16). In case of the latter, we need to adjust our logic based on which
+    /// tile the current scanline lies in (upper vs. lower).
+    ///
+    /// Sprite pixels that are already in the FIFO take priority, unless they are
+    /// transparent. In CGB mode, sprites are prioritized by OAM order instead.
+    fn fetch_sprite(&mut self, idx: usize) {
+        let tile_data_base = 0x8000;
 
-        // Render sprites
-        if self.lcdc.sprite_enable() {
-            // Fetch sprite pixel data
-            //
-            // The method returns `None` if the sprite pixel is transparent.
-            if let Some((data, priority)) = self.fetch_sprite_pixel_data(pixel) {
-                if bg_color_index == 0 || (!bg_priority && priority) || pixel_data.is_none() {
-                    pixel_data = Some(data);
-                }
-            }
-        }
+        let size = if self.lcdc.sprite_size() { 16 } else { 8 };
 
-        // Push the pixel to the frame buffer
-        if let Some(data) = pixel_data {
-            self.frame_buffer
-                .write(pixel as usize, scanline as usize, data);
-        }
-    }
+        let sprite = &mut self.sprites[idx];
+        sprite.fetched = true;
 
-    /// Render a single scanline worth of pixel data to the frame buffer
-    fn render_scanline(&mut self) {
-        for pixel in 0..LCD_WIDTH as u8 {
-            self.render_pixel(pixel);
+        // Hidden sprites still take time to fetch, but are never drawn. This
+        // way, sprites below a hidden sprite show through.
+        if !self.layer_mask.sprite_visible(sprite.oam_index) {
+            return;
         }
-    }
-
-    /// Fetches BG data for a single pixel
-    ///
-    /// Given the x and y position of the pixel in the BG map, we need to do the following:
-    ///
-    /// 1. Using the BG pixel positions, determine the BG tile map x and y.
-    /// 2. Using the tile coordinates, compute an index into the BG tile map.
-    /// 3. Get the tile data index (bank 0) and tile attributes (bank 1) from VRAM.
-    /// 4. Extract tile attributes.
-    /// 5. Read the actual tile data (16 bytes) from the relevant VRAM bank.
-    /// 6. Compute the pixel's index within the 8x8 pixel tile, and adjust if flips are
-    ///    present in this tile.
-    ///
-    /// In `fetch_pixel_data`:
-    ///
-    /// 7. Compute the pixel's color palette index (2 bits).
-    /// 8. Finally, using the tile's palette number and the index from (8),
-    ///    compute the RGB value for the pixel.
-    ///
-    /// Returns: (pixel data, BG priority, pixel color index)
-    fn fetch_bg_pixel_data(
-        &self,
-        bg_pixel_x: u8,
-        bg_pixel_y: u8,
-        tile_map_base: u16,
-    ) -> (GameboyRgb, bool, u8) {
-        // Select base address for BG tile data based on LCDC register
-        let (tile_data_base, tile_data_index_signed) = if !self.lcdc.bg_tile_data_select() {
-            (0x8000, false)
-        } else {
-            (0x9000, true)
-        };
 
-        // (1) and (2)
-        let bg_tile_x = bg_pixel_x / 8;
-        let bg_tile_y = bg_pixel_y / 8;
-        let tile_map_index = (bg_tile_y as u16 * 32 + bg_tile_x as u16) as u16;
+        // Find the position of upper left corner of the tile on the screen. We
+        // need to use wrapping operations here to address the boundary conditions.
+        //
+        // For example, suppose we have a 8x16 sprite that is halfway above the screen -
+        // i.e., y = 8 -> y - 16 = -8. The upper corner of the tile is indeed at y = -8,
+        // but we still need to display pixels that are within the _lower_ half of the tile.
+        let tile_y = sprite.y.wrapping_sub(16);
 
-        // (3)
-        let tile_number = self.vram.read_bank(0, tile_map_base + tile_map_index);
-        let tile_data_attr = if self.cgb {
-            self.vram.read_bank(1, tile_map_base + tile_map_index)
-        } else {
-            // No 2nd bank for tile attributes in DMG mode
-            0
-        };
+        let tile_number = sprite.tile_number;
+        let attr = sprite.attr;
+        let oam_index = sprite.oam_index;
+        let palette_num;
+        let vram_bank;
 
-        // (4)
-        let tile_palette_num = tile_data_attr & 0x07; // bits 0-2
-        let tile_data_bank = (tile_data_attr & (1 << 3)) >> 3; // bit 3
-        let horizontal_flip = (tile_data_attr & (1 << 5)) != 0; // bit 5
-        let vertical_flip = (tile_data_attr & (1 << 6)) != 0; // bit 6
-        let bg_priority = if self.cgb {
-            (tile_data_attr & (1 << 7)) != 0 // bit 7
+        if self.cgb {
+            palette_num = attr & 0x07;
+            vram_bank = (attr & 1 << 3) >> 3;
         } else {
-            // On DMG, BG priority is based on the color index
-            false
+            palette_num = (attr & 1 << 4) >> 4;
+            vram_bank = 0;
         };
 
-        // (5)
-        let mut tile_data = [0u8; 16];
-        for i in 0..tile_data.len() as u16 {
-            let addr;
+        let horizontal_flip = (attr & 1 << 5) != 0;
+        let vertical_flip = (attr & 1 << 6) != 0;
+        let priority = (attr & 1 << 7) == 0;
 
-            if !tile_data_index_signed || tile_number <= 127 {
-                // If we are in 8000 mode OR 8800 mode with tile number <= 127,
-                // just add the index to the base address as normal.
-                let tile_data_index = tile_number as u16 * 16 + i;
-                addr = tile_data_base + tile_data_index;
-            } else {
-                // For "signed" tiles in 8800 mode:
-                //
-                // * Tile 128 -> 0x8800-0x880F
-                // * Tile 255 -> 0x8FF0-0x8FFF
-                let tile_data_index = (tile_number as u16 - 128) * 16 + i;
-                addr = 0x8800 + tile_data_index;
-            }
+        // Pixels of this sprite that are left of the current pixel are not drawn
+        let skip = (self.lx + 8).saturating_sub(sprite.x);
 
-            tile_data[i as usize] = self.vram.read_bank(tile_data_bank, addr);
-        }
-
-        // (6)
-        let mut tile_pixel_x = bg_pixel_x - bg_tile_x * 8;
-        let mut tile_pixel_y = bg_pixel_y - bg_tile_y * 8;
+        // Find the row of the tile data for this scanline
+        let mut tile_pixel_y = self.ly.wrapping_sub(tile_y);
 
         // Handle flipped pixels
-        if horizontal_flip {
-            tile_pixel_x = 7 - tile_pixel_x;
-        }
         if vertical_flip {
-            tile_pixel_y = 7 - tile_pixel_y;
+            tile_pixel_y = (size - 1) - tile_pixel_y;
         }
 
-        let (pixel_data, color_index) = self.fetch_pixel_data(
-            tile_data,
-            tile_pixel_x,
-            tile_pixel_y,
-            tile_palette_num,
-            false,
-        );
-
-        (pixel_data, bg_priority, color_index)
-    }
+        // If this is true, pixel data is part of the lower tile for this
+        // 8x16 sprite
+        let lower_tile = tile_pixel_y >= 8;
+        if lower_tile {
+            // Correct pixel_y in lower sprite tile
+            tile_pixel_y -= 8;
+        }
 
-    /// We go through a similar sequence as in the BG method.
-    ///
-    /// The first difference is that sprite info is stored in OAM, so we need to
-    /// walk through OAM to determine which sprite needs to be rendered at this location.
-    ///
-    /// The second difference is that sprites can be either a single tile (8x8) or two
-    /// vertically stacked tiles (8x16). In case of the latter, we need to adjust our logic
-    /// based on which tile the current pixel lies in (upper vs. lower).
-    fn fetch_sprite_pixel_data(&self, pixel: u8) -> Option<(GameboyRgb, bool)> {
-        let tile_data_base = 0x8000;
+        // Convert tile number to index in VRAM
+        let tile_index = if size == 8 {
+            tile_number as u16
+        } else if !lower_tile {
+            tile_number as u16 & 0xFE
+        } else {
+            tile_number as u16 | 0x01
+        };
 
-        let size = if self.lcdc.sprite_size() { 16 } else { 8 };
+        let addr = tile_data_base + tile_index * 16 + tile_pixel_y as u16 * 2;
+        let lower = self.vram.read_bank(vram_bank, addr);
+        let upper = self.vram.read_bank(vram_bank, addr + 1);
 
-        let scanline = self.ly;
+        let oam_priority = self.cgb && self.opri & 1 == 0;
 
-        for sprite in &self.sprites {
-            // Same logic as vertical position check in `find_visible_sprites()`.
-            let sprite_start = sprite.x.wrapping_sub(8);
-            let sprite_end = sprite.x;
-            let visible = if sprite_start < sprite_end {
-                sprite_start <= pixel && pixel < sprite_end
-            } else {
-                pixel < sprite_end
-            };
-
-            if !visible {
-                // If the sprite is not visible at this pixel, skip it
-                continue;
-            }
-
-            // Find the position of upper left corner of the tile on the screen. We
-            // need to use wrapping adds here to address the boundary conditions.
-            //
-            // For example, suppose we have a 8x16 sprite that is halfway above the screen -
-            // i.e., y = 8 -> y - 16 = -8. The upper corner of the tile is indeed at y = -8,
-            // but we still need to display pixels that are within the _lower_ half of the tile.
-            //
-            // This is why wrapping operations are used throughout this function. Once we have
-            // the pixel positions within the tile (e.g. `tile_pixel_x`), we are done.
-            let tile_y = sprite.y.wrapping_sub(16);
-            let tile_x = sprite.x.wrapping_sub(8);
-
-            let tile_number = sprite.tile_number;
-            let attr = sprite.attr;
-            let palette_num;
-            let vram_bank;
-
-            if self.cgb {
-                palette_num = attr & 0x07;
-                vram_bank = (attr & 1 << 3) >> 3;
-            } else {
-                palette_num = (attr & 1 << 4) >> 4;
-                vram_bank = 0;
-            };
+        while self.sprite_fifo.len() < 8 {
+            self.sprite_fifo.push_back(SpritePixel::transparent());
+        }
 
-            let horizontal_flip = (attr & 1 << 5) != 0;
-            let vertical_flip = (attr & 1 << 6) != 0;
-            let priority = (attr & 1 << 7) == 0;
+        for pixel in skip..8 {
+            let bit = if horizontal_flip { pixel } else { 7 - pixel };
+            let color = (upper >> bit & 1) << 1 | (lower >> bit & 1);
 
-            // Find the location of the pixel _within_ the tile data
-            let mut tile_pixel_x = pixel.wrapping_sub(tile_x);
-            let mut tile_pixel_y = scanline.wrapping_sub(tile_y);
+            let current = &mut self.sprite_fifo[(pixel - skip) as usize];
 
-            // Handle flipped pixels
-            if vertical_flip {
-                tile_pixel_y = (size - 1) - tile_pixel_y;
+            if color != 0 && (current.color == 0 || oam_priority && oam_index < current.oam_index) {
+                *current = SpritePixel {
+                    color,
+                    palette: palette_num,
+                    priority,
+                    oam_index,
+                };
             }
+        }
+    }
 
-            if horizontal_flip {
-                tile_pixel_x = 7 - tile_pixel_x;
-            }
+    /// Render a single pixel to the frame buffer (screen).
+    ///
+    /// This mixes the pixels shifted out of the BG and sprite FIFOs. Note that sprites
+    /// are more often layered on top of the BG, depending on color and priority.
+    fn render_pixel(&mut self, mut bg: BgPixel, sprite: Option<SpritePixel>) {
+        let mut pixel_data;
 
-            // If this is true, pixel data is part of the lower tile for this
-            // 8x16 sprite
-            let lower_tile = tile_pixel_y >= 8;
-            if lower_tile {
-                // Correct pixel_y in lower sprite tile
-                tile_pixel_y -= 8;
-            }
+        // Hidden BG and window layers (debugging only)
+        let bg_visible = if bg.window {
+            self.layer_mask.window
+        } else {
+            self.layer_mask.bg
+        };
 
-            // Convert tile number to index in VRAM
-            let tile_index = if size == 8 {
-                tile_number as u16
-            } else if !lower_tile {
-                tile_number as u16 & 0xFE
-            } else {
-                tile_number as u16 | 0x01
-            };
+        if !bg_visible {
+            bg.color = 0;
+            bg.priority = false;
+        }
 
-            // Fetch tile data for the pixel
-            let mut tile_data = [0u8; 16];
-            for i in 0..tile_data.len() as u16 {
-                let tile_data_index = tile_index * 16 + i;
-                let addr = tile_data_base + tile_data_index;
-                tile_data[i as usize] = self.vram.read_bank(vram_bank, addr);
-            }
+        let bg_priority;
+        let bg_color_index;
 
-            let (pixel_data, color_index) =
-                self.fetch_pixel_data(tile_data, tile_pixel_x, tile_pixel_y, palette_num, true);
+        // Conditions:
+        //
+        // 1. If the priority bit is set, the BG will _always_ have priority over sprites
+        // 2. If priority bit is reset (CGB): BG and window are still rendered, but sprites get priority
+        // 3. If priority bit is reset (DMG): BG and window turn white and sprites get priority
+        if self.lcdc.bg_priority() || self.cgb {
+            pixel_data = self.pixel_color(bg.color, bg.palette, false);
+
+            // On CGB, if LCDC priority is reset, BG & window lose priority
+            bg_priority = self.lcdc.bg_priority() && bg.priority;
+            bg_color_index = bg.color;
+        } else {
+            // On DMG, reset the BG to white in non-priority mode
+            pixel_data = self.dmg_palette.bg[0];
+            bg_priority = false;
+            bg_color_index = 0;
+        }
 
-            // If the sprite pixel is not transparent, return it
-            // Otherwise, check the next sprite in the list (priority ordered)
-            if color_index != 0 {
-                return Some((pixel_data, priority));
+        // Render sprites
+        //
+        // Transparent sprite pixels have a color index of 0.
+        if let Some(sprite) = sprite {
+            if self.lcdc.sprite_enable()
+                && sprite.color != 0
+                && (bg_color_index == 0 || (!bg_priority && sprite.priority))
+            {
+                pixel_data = self.pixel_color(sprite.color, sprite.palette, true);
             }
         }
 
-        None
+        // Push the pixel to the frame buffer
+        self.frame_buffer
+            .write(self.lx as usize, self.ly as usize, pixel_data);
     }
 
-    /// Returns pixel data for a single BG/window or sprite pixel.
-    ///
-    /// Returns: (pixel data, color index)
-    fn fetch_pixel_data(
-        &self,
-        tile_data: [u8; 16],
-        tile_pixel_x: u8,
-        tile_pixel_y: u8,
-        tile_palette_num: u8,
-        sprite: bool,
-    ) -> (GameboyRgb, u8) {
-        // (7)
-        //
-        // The y position of the pixel maps to the "line" (2 bytes) in the tile data
-        // The x position maps to the bit we need to check in the upper and lower nibbles of the line
-        // Note that the x position is *inverted*: e.g., the leftmost pixel is tracked in bit 7 of each nibble
-        let line_idx = tile_pixel_y as usize * 2;
-        let lower = tile_data[line_idx];
-        let upper = tile_data[line_idx + 1];
-        let pixel_pos = 7 - tile_pixel_x; // This corrects the inversion noted above
-
-        let lower_bit = (lower & 1 << pixel_pos) >> pixel_pos;
-        let upper_bit = (upper & 1 << pixel_pos) >> pixel_pos;
-        let color_index = upper_bit << 1 | lower_bit;
-
-        // (8)
-        let mut pixel_data;
-
+    /// Returns pixel data for a single BG/window or sprite pixel, given its
+    /// color index (2 bits) and palette number.
+    fn pixel_color(&self, color_index: u8, tile_palette_num: u8, sprite: bool) -> GameboyRgb {
         if self.cgb {
             let palette_index = (tile_palette_num * 8 + color_index * 2) as usize;
             let palette_ram = if sprite {
@@ -1045,20 +1719,14 @@ impl Ppu {
             let pixel_color =
                 (palette_ram[palette_index + 1] as u16) << 8 | palette_ram[palette_index] as u16;
 
-            let red = (pixel_color & 0x001F) as u8;
-            let green = ((pixel_color & 0x03E0) >> 5) as u8;
-            let blue = ((pixel_color & 0x7C00) >> 10) as u8;
-
-            pixel_data = GameboyRgb { red, blue, green };
-
-            pixel_data.scale_to_rgb();
+            self.color_table.lookup(pixel_color)
         } else {
-            let palette_reg = if !sprite {
-                self.bgp
+            let (palette_reg, colors) = if !sprite {
+                (self.bgp, &self.dmg_palette.bg)
             } else {
                 match tile_palette_num {
-                    0 => self.obp0,
-                    _ => self.obp1,
+                    0 => (self.obp0, &self.dmg_palette.obj0),
+                    _ => (self.obp1, &self.dmg_palette.obj1),
                 }
             };
 
@@ -1071,10 +1739,8 @@ impl Ppu {
                 _ => unreachable!(),
             };
 
-            pixel_data = DMG_PALETTE[palette_index as usize];
+            colors[palette_index as usize]
         }
-
-        (pixel_data, color_index)
     }
 
     /// Write a single byte of data to palette RAM.
@@ -1167,9 +1833,179 @@ impl Ppu {
         }
     }
 
+    /// Returns the colors used to render DMG games
+    pub fn dmg_palette(&self) -> DmgPalette {
+        self.dmg_palette
+    }
+
+    /// Set the colors used to render DMG games. Has no effect in CGB mode.
+    pub fn set_dmg_palette(&mut self, palette: DmgPalette) {
+        self.dmg_palette = palette;
+    }
+
+    /// Returns the color correction applied to CGB colors
+    pub fn color_correction(&self) -> ColorCorrection {
+        self.color_table.correction
+    }
+
+    /// Set the color correction applied to CGB colors. DMG palettes are not
+    /// affected.
+    pub fn set_color_correction(&mut self, correction: ColorCorrection) {
+        if correction != self.color_table.correction {
+            self.color_table = ColorTable::new(correction);
+        }
+    }
+
+    /// Returns the pixel format of the frame buffer
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.frame_buffer.format()
+    }
+
+    /// Set the pixel format of the frame buffer. This clears the current frame.
+    pub fn set_pixel_format(&mut self, format: PixelFormat) {
+        if format != self.frame_buffer.format() {
+            self.frame_buffer = FrameBuffer::with_format(format);
+        }
+    }
+
+    /// Returns the layers that are drawn
+    pub fn layer_mask(&self) -> LayerMask {
+        self.layer_mask
+    }
+
+    /// Show or hide individual layers and sprites (for debugging).
+    pub fn set_layer_mask(&mut self, mask: LayerMask) {
+        self.layer_mask = mask;
+    }
+
     pub fn is_frame_ready(&self) -> bool {
         self.frame_buffer.ready
     }
+
+    /// Clear the frame buffer to white and mark it as ready.
+    ///
+    /// This is used when the LCD is not running (e.g., in STOP mode), so that
+    /// the frontend still receives frames.
+    pub fn blank_frame(&mut self) {
+        self.frame_buffer.fill(GameboyRgb::white());
+        self.frame_buffer.ready = true;
+    }
+
+    /// Render every tile in VRAM (384 per bank), 16 tiles per row, using the
+    /// given palette.
+    ///
+    /// On CGB, the tiles in bank 1 are placed to the right of those in bank 0.
+    pub fn render_tiles(&self, palette: TilePalette) -> VramImage {
+        const TILES_PER_ROW: usize = 16;
+        const TILE_COUNT: usize = 384;
+
+        let banks = if self.cgb { 2 } else { 1 };
+        let bank_width = TILES_PER_ROW * 8;
+        let mut image = VramImage::new(bank_width * banks, TILE_COUNT / TILES_PER_ROW * 8);
+
+        let (palette_num, sprite) = match palette {
+            TilePalette::Bg(num) => (num & 0x7, false),
+            TilePalette::Obj(num) => (num & 0x7, true),
+        };
+
+        for bank in 0..banks {
+            for tile in 0..TILE_COUNT {
+                let tile_addr = Vram::BASE_ADDR + tile as u16 * 16;
+                let x = bank * bank_width + tile % TILES_PER_ROW * 8;
+                let y = tile / TILES_PER_ROW * 8;
+
+                for row in 0..8 {
+                    let colors = self.tile_row(bank as u8, tile_addr, row, false);
+
+                    for (col, color_index) in colors.iter().enumerate() {
+                        let pixel = self.pixel_color(*color_index, palette_num, sprite);
+                        image.write(x + col, y + row as usize, pixel);
+                    }
+                }
+            }
+        }
+
+        image
+    }
+
+    /// Render a full 256x256 BG/window tile map.
+    ///
+    /// Tiles are addressed as selected in LCDC, and CGB tile attributes (palette,
+    /// bank, and flips) are applied. If this is the current BG map, the area
+    /// shown on the LCD (at SCX/SCY, wrapping around the edges) is outlined in red.
+    pub fn render_tile_map(&self, map: TileMap) -> VramImage {
+        let mut image = VramImage::new(TileMap::SIZE, TileMap::SIZE);
+
+        for tile_y in 0..32 {
+            for tile_x in 0..32 {
+                let addr = map.addr() + tile_y * 32 + tile_x;
+                let tile_number = self.vram.read_bank(0, addr) as u16;
+                let attr = if self.cgb {
+                    self.vram.read_bank(1, addr)
+                } else {
+                    0
+                };
+
+                let palette_num = attr & 0x7;
+                let bank = (attr & (1 << 3)) >> 3;
+                let horizontal_flip = attr & (1 << 5) != 0;
+                let vertical_flip = attr & (1 << 6) != 0;
+
+                for row in 0..8 {
+                    let tile_row = if vertical_flip { 7 - row } else { row };
+                    let colors = self.tile_row(
+                        bank,
+                        self.bg_tile_addr(tile_number),
+                        tile_row,
+                        horizontal_flip,
+                    );
+
+                    for (col, color_index) in colors.iter().enumerate() {
+                        let pixel = self.pixel_color(*color_index, palette_num, false);
+                        image.write(
+                            tile_x as usize * 8 + col,
+                            tile_y as usize * 8 + row as usize,
+                            pixel,
+                        );
+                    }
+                }
+            }
+        }
+
+        if map.addr() == self.lcdc.bg_tile_map() {
+            let outline = GameboyRgb::new(0xFF, 0, 0);
+            let (scx, scy) = (self.scx as usize, self.scy as usize);
+            let wrap = |n: usize| n % TileMap::SIZE;
+
+            for x in 0..LCD_WIDTH {
+                image.write(wrap(scx + x), scy, outline);
+                image.write(wrap(scx + x), wrap(scy + LCD_HEIGHT - 1), outline);
+            }
+
+            for y in 0..LCD_HEIGHT {
+                image.write(scx, wrap(scy + y), outline);
+                image.write(wrap(scx + LCD_WIDTH - 1), wrap(scy + y), outline);
+            }
+        }
+
+        image
+    }
+
+    /// Decode a single row (0-7) of a tile into color indices, from left to right.
+    fn tile_row(&self, bank: u8, tile_addr: u16, row: u8, horizontal_flip: bool) -> [u8; 8] {
+        let addr = tile_addr + row as u16 * 2;
+        let lower = self.vram.read_bank(bank, addr);
+        let upper = self.vram.read_bank(bank, addr + 1);
+
+        let mut colors = [0; 8];
+
+        for (col, color) in colors.iter_mut().enumerate() {
+            let bit = if horizontal_flip { col } else { 7 - col };
+            *color = (upper >> bit & 1) << 1 | lower >> bit & 1;
+        }
+
+        colors
+    }
 }
 
 impl MemoryRead<u16, u8> for Ppu {
@@ -1196,10 +2032,10 @@ impl MemoryRead<u16, u8> for Ppu {
                 self.oam[idx]
             }
             Self::LCDC_ADDR => self.lcdc.raw,
-            Self::STAT_ADDR => self.stat.raw,
+            Self::STAT_ADDR => self.stat.raw | 0x80,
             Self::SCY_ADDR => self.scy,
             Self::SCX_ADDR => self.scx,
-            Self::LY_ADDR => self.ly,
+            Self::LY_ADDR => self.ly_register(),
             Self::LYC_ADDR => self.lyc,
             0xFF46 => self.oam_dma,
             0xFF47 => self.bgp,
@@ -1238,17 +2074,17 @@ impl MemoryWrite<u16, u8> for Ppu {
                 }
             }
             Self::LCDC_ADDR => {
+                let enabled = self.lcdc.lcd_display_enable();
+
                 self.lcdc.raw = value;
-                if value & 1 << 7 == 0 {
-                    // Disabling the LCD resets LY
-                    self.ly = 0;
+
+                match (enabled, self.lcdc.lcd_display_enable()) {
+                    (true, false) => self.lcd_off(),
+                    (false, true) => self.lcd_on(),
+                    _ => (),
                 }
             }
-            Self::STAT_ADDR => {
-                // Lower 3 bits are read-only
-                let value = value & 0xF8;
-                self.stat.raw = value | self.stat.raw & 0x07;
-            }
+            Self::STAT_ADDR => self.write_stat(value),
             Self::SCY_ADDR => self.scy = value,
             Self::SCX_ADDR => self.scx = value,
             Self::LYC_ADDR => self.lyc = value,
@@ -1299,3 +2135,311 @@ impl MemoryWrite<u16, u8> for Ppu {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Returns the number of dots in mode 3 on the current scanline
+    fn mode3_dots(ppu: &mut Ppu) -> u16 {
+        let mut interrupts = Vec::new();
+        let mut dots = 0;
+
+        while ppu.current_mode() != StatMode::OamRead {
+            ppu.step(1, false, &mut interrupts);
+        }
+
+        while ppu.current_mode() == StatMode::OamRead {
+            ppu.step(1, false, &mut interrupts);
+            dots += 1;
+        }
+
+        dots
+    }
+
+    #[test]
+    fn mode3_length() {
+        let mut ppu = Ppu::new(false, false);
+        assert_eq!(mode3_dots(&mut ppu), 172);
+
+        // Fine scrolling discards pixels at the start of the scanline
+        let mut ppu = Ppu::new(false, false);
+        ppu.write(Ppu::SCX_ADDR, 3);
+        assert_eq!(mode3_dots(&mut ppu), 175);
+
+        // The fetcher restarts once the window starts
+        let mut ppu = Ppu::new(false, false);
+        ppu.write(Ppu::LCDC_ADDR, 0x91 | 1 << 5);
+        ppu.write(Ppu::WX_ADDR, 87);
+        assert_eq!(mode3_dots(&mut ppu), 178);
+    }
+
+    #[test]
+    fn mode3_sprite_penalty() {
+        // Sprites that are aligned with a BG tile wait for a whole tile fetch
+        for &(x, dots) in &[(16, 183), (21, 178), (200, 172)] {
+            let mut ppu = Ppu::new(false, false);
+            ppu.write(Ppu::LCDC_ADDR, 0x91 | 1 << 1);
+            ppu.oam[..2].copy_from_slice(&[16, x]);
+
+            assert_eq!(mode3_dots(&mut ppu), dots, "x = {}", x);
+        }
+    }
+
+    #[test]
+    fn mid_scanline_palette_write() {
+        let mut ppu = Ppu::new(false, false);
+        let mut interrupts = Vec::new();
+
+        // Tile 0 is solid black
+        for addr in 0x8000..0x8010 {
+            ppu.write(addr, 0xFF);
+        }
+
+        while ppu.lx < 80 {
+            ppu.step(1, false, &mut interrupts);
+        }
+
+        // Map color 3 to white for the rest of the scanline
+        ppu.write(0xFF47, 0x3C);
+
+        while ppu.current_mode() == StatMode::OamRead {
+            ppu.step(1, false, &mut interrupts);
+        }
+
+        assert_eq!(ppu.frame_buffer.read(79, 0), ppu.dmg_palette.bg[3]);
+        assert_eq!(ppu.frame_buffer.read(80, 0), ppu.dmg_palette.bg[0]);
+    }
+
+    /// Run the PPU for the given number of dots, one M-cycle at a time.
+    ///
+    /// Returns the number of STAT interrupts triggered.
+    fn run(ppu: &mut Ppu, dots: u32) -> usize {
+        let mut interrupts = Vec::new();
+
+        for _ in 0..dots / 4 {
+            ppu.step(4, false, &mut interrupts);
+        }
+
+        interrupts
+            .iter()
+            .filter(|i| matches!(i, Interrupt::LcdStat))
+            .count()
+    }
+
+    #[test]
+    fn ly_153_early_wrap() {
+        let mut ppu = Ppu::new(true, false);
+
+        // Run until the start of the last scanline
+        run(&mut ppu, 153 * 456);
+        ppu.write(Ppu::STAT_ADDR, 1 << 6);
+        assert_eq!(ppu.read(Ppu::LY_ADDR), 153);
+
+        // LY switches to 0 after one M-cycle, and LYC=0 matches after
+        // another two
+        ppu.write(Ppu::LYC_ADDR, 0);
+        assert_eq!(run(&mut ppu, 4), 0);
+        assert_eq!(ppu.read(Ppu::LY_ADDR), 0);
+        assert_eq!(run(&mut ppu, 4), 0);
+        assert_eq!(run(&mut ppu, 4), 1);
+        assert_eq!(ppu.read(Ppu::STAT_ADDR) & 0x04, 0x04);
+
+        // LY stays 0 on the first line of the next frame, so the STAT line
+        // stays high
+        assert_eq!(run(&mut ppu, 444), 0);
+        assert_eq!(ppu.read(Ppu::LY_ADDR), 0);
+        assert_eq!(ppu.read(Ppu::STAT_ADDR) & 0x03, 2);
+    }
+
+    #[test]
+    fn lcd_on_first_line() {
+        let mut ppu = Ppu::new(false, false);
+        run(&mut ppu, 1000);
+
+        ppu.write(Ppu::LCDC_ADDR, 0x11);
+        assert_eq!(ppu.read(Ppu::LY_ADDR), 0);
+        assert_eq!(ppu.read(Ppu::STAT_ADDR) & 0x03, 0);
+
+        // The PPU is stopped while the LCD is off
+        run(&mut ppu, 1000);
+        assert_eq!(ppu.read(Ppu::LY_ADDR), 0);
+
+        // There is no OAM scan on the first line after the LCD is turned on
+        ppu.write(Ppu::LCDC_ADDR, 0x91);
+        run(&mut ppu, 76);
+        assert_eq!(ppu.read(Ppu::STAT_ADDR) & 0x03, 0);
+        run(&mut ppu, 4);
+        assert_eq!(ppu.read(Ppu::STAT_ADDR) & 0x03, 3);
+
+        run(&mut ppu, 380);
+        assert_eq!(ppu.read(Ppu::LY_ADDR), 1);
+        assert_eq!(ppu.read(Ppu::STAT_ADDR) & 0x03, 2);
+    }
+
+    #[test]
+    fn stat_write_interrupt() {
+        // Writing to STAT during HBLANK triggers an interrupt on DMG
+        let mut ppu = Ppu::new(false, false);
+        run(&mut ppu, 300);
+        ppu.write(Ppu::STAT_ADDR, 0);
+        assert_eq!(run(&mut ppu, 4), 1);
+
+        // But not during mode 3, unless LY matches LYC
+        let mut ppu = Ppu::new(false, false);
+        ppu.write(Ppu::LYC_ADDR, 1);
+        run(&mut ppu, 100);
+        ppu.write(Ppu::STAT_ADDR, 0);
+        assert_eq!(run(&mut ppu, 4), 0);
+
+        // And never on CGB
+        let mut ppu = Ppu::new(true, false);
+        run(&mut ppu, 300);
+        ppu.write(Ppu::STAT_ADDR, 0);
+        assert_eq!(run(&mut ppu, 4), 0);
+    }
+
+    #[test]
+    fn color_correction() {
+        let red = 0x001F;
+        let white = 0x7FFF;
+
+        let table = ColorTable::default();
+        assert_eq!(table.lookup(red), GameboyRgb::new(0xFF, 0, 0));
+        assert_eq!(table.lookup(0x8000 | red), GameboyRgb::new(0xFF, 0, 0));
+
+        for correction in [ColorCorrection::GbcLcd, ColorCorrection::GbaLcd].iter() {
+            let table = ColorTable::new(*correction);
+            assert_eq!(table.lookup(0), GameboyRgb::new(0, 0, 0));
+
+            // White stays neutral, but is dimmed
+            let color = table.lookup(white);
+            assert!(color.red < 0xFF);
+            assert_eq!(color.red, color.green);
+            assert_eq!(color.red, color.blue);
+
+            // Channels bleed into each other
+            let color = table.lookup(red);
+            assert!(color.red < 0xFF && color.green > 0 && color.blue > 0);
+        }
+
+        let mut ppu = Ppu::new(true, false);
+        ppu.set_color_correction("gba".parse().unwrap());
+        assert_eq!(ppu.color_correction(), ColorCorrection::GbaLcd);
+        assert!("vivid".parse::<ColorCorrection>().is_err());
+    }
+
+    #[test]
+    fn pixel_formats() {
+        let pixel = GameboyRgb::new(0x12, 0x34, 0x56);
+
+        let expected: [(PixelFormat, &[u8]); 4] = [
+            (PixelFormat::Rgb888, &[0x12, 0x34, 0x56]),
+            (PixelFormat::Rgba8888, &[0x12, 0x34, 0x56, 0xFF]),
+            (PixelFormat::Xrgb8888, &0xFF12_3456u32.to_ne_bytes()),
+            (PixelFormat::Rgb565, &0x11AAu16.to_ne_bytes()),
+        ];
+
+        for (format, bytes) in expected.iter() {
+            let mut frame_buffer = FrameBuffer::with_format(*format);
+            let bpp = format.bytes_per_pixel();
+
+            assert_eq!(frame_buffer.pitch(), LCD_WIDTH * bpp);
+            assert_eq!(frame_buffer.read(0, 0), GameboyRgb::white());
+
+            frame_buffer.write(1, 2, pixel);
+            let offset = 2 * frame_buffer.pitch() + bpp;
+            assert_eq!(&frame_buffer.as_bytes()[offset..offset + bpp], *bytes);
+        }
+
+        // RGB565 drops the low bits of each color
+        let mut frame_buffer = FrameBuffer::with_format(PixelFormat::Rgb565);
+        frame_buffer.write(0, 0, pixel);
+        assert_eq!(frame_buffer.read(0, 0), GameboyRgb::new(0x10, 0x34, 0x52));
+    }
+
+    #[test]
+    fn layer_mask() {
+        let mut ppu = Ppu::new(false, false);
+        let mut interrupts = Vec::new();
+
+        // Tile 0 is solid black
+        for addr in 0x8000..0x8010 {
+            ppu.write(addr, 0xFF);
+        }
+
+        // Hide the BG for the first half of the scanline
+        let mut mask = LayerMask {
+            bg: false,
+            ..LayerMask::default()
+        };
+        ppu.set_layer_mask(mask);
+
+        while ppu.lx < 80 {
+            ppu.step(1, false, &mut interrupts);
+        }
+
+        ppu.set_layer_mask(LayerMask::default());
+
+        while ppu.current_mode() == StatMode::OamRead {
+            ppu.step(1, false, &mut interrupts);
+        }
+
+        assert_eq!(ppu.frame_buffer.read(79, 0), ppu.dmg_palette.bg[0]);
+        assert_eq!(ppu.frame_buffer.read(80, 0), ppu.dmg_palette.bg[3]);
+
+        mask.set_sprite_visible(3, false);
+        mask.set_sprite_visible(LayerMask::SPRITE_COUNT, false);
+        assert_eq!(mask.hidden_sprites, 1 << 3);
+        assert!(!mask.sprite_visible(3));
+        assert!(mask.sprite_visible(4));
+
+        mask.sprites = false;
+        assert!(!mask.sprite_visible(4));
+    }
+
+    #[test]
+    fn vram_viewer() {
+        let mut ppu = Ppu::new(true, false);
+
+        // Tile 1 in bank 1: leftmost pixel of the first row has color 3
+        ppu.write(Vram::BANK_SELECT_ADDR, 1);
+        ppu.write(0x8010, 0x80);
+        ppu.write(0x8011, 0x80);
+
+        // Tile map entry (1, 0) uses tile 1 from bank 1, flipped horizontally,
+        // with BG palette 2 (color 3 is red)
+        ppu.write(0x9801, 1 << 3 | 1 << 5 | 2);
+        ppu.write(Vram::BANK_SELECT_ADDR, 0);
+        ppu.write(0x9801, 1);
+        ppu.bg_palette_ram[2 * 8 + 6] = 0x1F;
+        ppu.bg_palette_ram[2 * 8 + 7] = 0x00;
+        ppu.write(0xFF40, 0x91);
+
+        let red = GameboyRgb::new(0xFF, 0, 0);
+
+        let tiles = ppu.render_tiles(TilePalette::Bg(2));
+        assert_eq!((tiles.width, tiles.height), (256, 192));
+        assert_eq!(tiles.read(128 + 8, 0), red);
+        assert_eq!(tiles.read(8, 0), GameboyRgb::white());
+
+        ppu.scx = 250;
+        ppu.scy = 200;
+
+        let map = ppu.render_tile_map(TileMap::Low);
+        assert_eq!(map.read(15, 0), red);
+        assert_eq!(map.read(8, 0), GameboyRgb::white());
+
+        // The viewport wraps around the edges
+        assert_eq!(map.read(250, 30), red);
+        assert_eq!(map.read(150, 199), GameboyRgb::white());
+        assert_eq!(map.read(153, 200), red);
+        assert_eq!(map.read(153, 87), red);
+        assert_eq!(map.read(154, 87), GameboyRgb::white());
+
+        // And is only outlined on the current BG map
+        let map = ppu.render_tile_map(TileMap::High);
+        assert_eq!(map.read(15, 0), GameboyRgb::white());
+        assert_eq!(map.read(250, 30), GameboyRgb::white());
+    }
+}
@@ -0,0 +1,468 @@
+//! Game Boy Printer emulation
+//!
+//! The printer is a `SerialDevice` that receives packets from the Gameboy. Each
+//! packet has the following format:
+//!
+//! | Magic     | Command | Compression | Length | Data | Checksum | Alive | Status |
+//! |-----------|---------|-------------|--------|------|----------|-------|--------|
+//! | 0x88 0x33 | 1 byte  | 1 byte      | 2 (LE) | N    | 2 (LE)   | 1     | 1      |
+//!
+//! The printer responds with 0x00 for every byte, except for the last two: it
+//! returns 0x81 (i.e., the device ID) followed by its current status.
+//!
+//! Image data is sent as 2bpp tiles, 20 tiles (160 pixels) per row, and is
+//! optionally RLE-compressed. On each print command, the buffered image data is
+//! decoded and written out as a greyscale PNG.
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+use crate::serial::SerialDevice;
+
+/// Printer commands
+mod command {
+    pub const INIT: u8 = 0x01;
+    pub const PRINT: u8 = 0x02;
+    pub const DATA: u8 = 0x04;
+    pub const STATUS: u8 = 0x0F;
+}
+
+/// Printer status bits
+mod status {
+    pub const CHECKSUM_ERROR: u8 = 1 << 0;
+    pub const PRINTING: u8 = 1 << 1;
+    pub const DATA_FULL: u8 = 1 << 2;
+    pub const UNPROCESSED_DATA: u8 = 1 << 3;
+}
+
+/// Current position within a packet
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+/// Game Boy Printer
+///
+/// Each printout is written as a PNG to the output directory, named
+/// `print-NNN.png`. Existing files are never overwritten.
+pub struct Printer {
+    dir: PathBuf,
+
+    state: State,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16,
+
+    /// Uncompressed image data received since the last print
+    buffer: Vec<u8>,
+
+    /// Rows of pixels of the current printout. A printout can span multiple
+    /// print commands, and ends when a print command has a bottom margin.
+    page: Vec<u8>,
+
+    status: u8,
+
+    /// Number of status packets left until the current print completes
+    busy: u8,
+
+    /// Number of the next printout
+    count: usize,
+}
+
+impl Printer {
+    /// Device ID returned in the first response byte
+    const DEVICE_ID: u8 = 0x81;
+
+    const MAGIC: [u8; 2] = [0x88, 0x33];
+
+    /// Maximum amount of image data: 9 DATA packets of 0x280 bytes each (two
+    /// rows of 20 tiles per packet)
+    const BUFFER_SIZE: usize = 0x280 * 9;
+
+    /// Width of a printout, in pixels
+    pub const WIDTH: usize = 160;
+
+    /// Tiles per row of image data
+    const TILES_PER_ROW: usize = Self::WIDTH / 8;
+
+    /// Number of status packets for which the printer reports that it is
+    /// still printing
+    const PRINT_DURATION: u8 = 4;
+
+    /// Greyscale value of each shade in the printout
+    const SHADES: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+    /// Create a printer that writes printouts to the given directory. The
+    /// directory is created if it does not exist.
+    pub fn new<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+
+        std::fs::create_dir_all(&dir)?;
+
+        Ok(Self {
+            dir,
+            state: State::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            buffer: Vec::new(),
+            page: Vec::new(),
+            status: 0,
+            busy: 0,
+            count: 0,
+        })
+    }
+
+    /// Process a byte sent by the Gameboy, and return the printer's response.
+    fn receive(&mut self, byte: u8) -> u8 {
+        let mut response = 0x00;
+
+        self.state = match self.state {
+            State::Magic1 if byte == Self::MAGIC[0] => State::Magic2,
+            State::Magic1 => State::Magic1,
+            State::Magic2 if byte == Self::MAGIC[1] => State::Command,
+            State::Magic2 if byte == Self::MAGIC[0] => State::Magic2,
+            State::Magic2 => State::Magic1,
+            State::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                State::Compression
+            }
+            State::Compression => {
+                self.compressed = byte & 0x1 != 0;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                State::LengthLow
+            }
+            State::LengthLow => {
+                self.length = byte as u16;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                State::LengthHigh
+            }
+            State::LengthHigh => {
+                self.length |= (byte as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.data.clear();
+
+                if self.length > 0 {
+                    State::Data
+                } else {
+                    State::ChecksumLow
+                }
+            }
+            State::Data => {
+                self.data.push(byte);
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+
+                if self.data.len() == self.length as usize {
+                    State::ChecksumLow
+                } else {
+                    State::Data
+                }
+            }
+            State::ChecksumLow => {
+                self.checksum ^= byte as u16;
+                State::ChecksumHigh
+            }
+            State::ChecksumHigh => {
+                self.checksum ^= (byte as u16) << 8;
+                self.handle_packet();
+                State::Alive
+            }
+            State::Alive => {
+                response = Self::DEVICE_ID;
+                State::Status
+            }
+            State::Status => {
+                response = self.status;
+                State::Magic1
+            }
+        };
+
+        response
+    }
+
+    /// Handle a complete packet
+    fn handle_packet(&mut self) {
+        // At this point, the checksum is 0 if it matched
+        if self.checksum != 0 {
+            log::warn!("Printer packet checksum mismatch");
+            self.status |= status::CHECKSUM_ERROR;
+            return;
+        }
+
+        self.status &= !status::CHECKSUM_ERROR;
+
+        match self.command {
+            command::INIT => {
+                self.buffer.clear();
+                self.status = 0;
+                self.busy = 0;
+            }
+            command::DATA => {
+                if self.compressed {
+                    decompress(&self.data, &mut self.buffer);
+                } else {
+                    self.buffer.extend_from_slice(&self.data);
+                }
+
+                self.buffer.truncate(Self::BUFFER_SIZE);
+
+                if !self.buffer.is_empty() {
+                    self.status |= status::UNPROCESSED_DATA;
+                }
+
+                if self.buffer.len() == Self::BUFFER_SIZE {
+                    self.status |= status::DATA_FULL;
+                }
+            }
+            command::PRINT => {
+                if self.data.len() < 4 {
+                    log::warn!("Invalid printer print command");
+                    return;
+                }
+
+                // Data: number of sheets, margins, palette, exposure
+                let margins = self.data[1];
+                let palette = self.data[2];
+
+                self.print(palette);
+
+                // A bottom margin means that the printout is complete
+                if margins & 0x0F != 0 {
+                    if let Err(err) = self.save() {
+                        log::error!("Failed to save printout: {}", err);
+                    }
+                }
+
+                self.status &= !(status::UNPROCESSED_DATA | status::DATA_FULL);
+                self.status |= status::PRINTING;
+                self.busy = Self::PRINT_DURATION;
+            }
+            command::STATUS => {
+                if self.busy > 0 {
+                    self.busy -= 1;
+
+                    if self.busy == 0 {
+                        self.status &= !status::PRINTING;
+                    }
+                }
+            }
+            _ => {
+                log::warn!("Unknown printer command: {:#04X}", self.command);
+            }
+        }
+    }
+
+    /// Decode the buffered image data using the given palette, and append it
+    /// to the current page.
+    fn print(&mut self, palette: u8) {
+        // A palette of 0 is treated as the default palette
+        let palette = if palette == 0 { 0xE4 } else { palette };
+
+        let tile_rows = self.buffer.len() / (Self::TILES_PER_ROW * 16);
+
+        for row in 0..tile_rows {
+            for y in 0..8 {
+                for tile in 0..Self::TILES_PER_ROW {
+                    let offset = (row * Self::TILES_PER_ROW + tile) * 16 + y * 2;
+                    let (low, high) = (self.buffer[offset], self.buffer[offset + 1]);
+
+                    for bit in (0..8).rev() {
+                        let color = (high >> bit & 0x1) << 1 | (low >> bit & 0x1);
+                        let shade = palette >> (color * 2) & 0x3;
+                        self.page.push(Self::SHADES[shade as usize]);
+                    }
+                }
+            }
+        }
+
+        self.buffer.clear();
+    }
+
+    /// Write out the current page as a PNG, if it is not empty.
+    fn save(&mut self) -> Result<()> {
+        if self.page.is_empty() {
+            return Ok(());
+        }
+
+        // Find the next file that does not exist yet
+        let path = loop {
+            self.count += 1;
+
+            let path = self.dir.join(format!("print-{:03}.png", self.count));
+            if !path.exists() {
+                break path;
+            }
+        };
+
+        let height = self.page.len() / Self::WIDTH;
+        let file = BufWriter::new(File::create(&path)?);
+
+        let mut encoder = png::Encoder::new(file, Self::WIDTH as u32, height as u32);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&self.page))
+            .map_err(|err| Error::IoError(err.to_string()))?;
+
+        log::info!("Saved printout to {}", path.display());
+
+        self.page.clear();
+
+        Ok(())
+    }
+}
+
+impl SerialDevice for Printer {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        self.receive(byte)
+    }
+}
+
+impl Drop for Printer {
+    fn drop(&mut self) {
+        // Save any printout that was not finished
+        if let Err(err) = self.save() {
+            log::error!("Failed to save printout: {}", err);
+        }
+    }
+}
+
+/// Decompress RLE-compressed image data into `out`.
+///
+/// Each run starts with a control byte. If bit 7 is set, the next byte is
+/// repeated (control & 0x7F) + 2 times. Otherwise, the next (control + 1) bytes
+/// are copied as-is.
+fn decompress(data: &[u8], out: &mut Vec<u8>) {
+    let mut i = 0;
+
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+
+        if control & 0x80 != 0 {
+            let count = (control & 0x7F) as usize + 2;
+
+            if let Some(byte) = data.get(i) {
+                out.resize(out.len() + count, *byte);
+            }
+
+            i += 1;
+        } else {
+            let count = control as usize + 1;
+            let end = (i + count).min(data.len());
+
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Send a packet to the printer, and return the (alive, status) response
+    fn send(printer: &mut Printer, command: u8, compressed: bool, data: &[u8]) -> (u8, u8) {
+        let length = data.len() as u16;
+        let mut packet = vec![
+            0x88,
+            0x33,
+            command,
+            compressed as u8,
+            length as u8,
+            (length >> 8) as u8,
+        ];
+        packet.extend_from_slice(data);
+
+        let checksum = packet[2..]
+            .iter()
+            .fold(0u16, |sum, b| sum.wrapping_add(*b as u16));
+        packet.extend_from_slice(&checksum.to_le_bytes());
+
+        for byte in packet {
+            assert_eq!(printer.transfer(byte), 0x00);
+        }
+
+        (printer.transfer(0x00), printer.transfer(0x00))
+    }
+
+    #[test]
+    fn rle_decompress() {
+        let mut out = Vec::new();
+        decompress(&[0x81, 0xAA, 0x01, 0x01, 0x02], &mut out);
+        assert_eq!(out, vec![0xAA, 0xAA, 0xAA, 0x01, 0x02]);
+    }
+
+    #[test]
+    fn print() {
+        let dir = std::env::temp_dir().join(format!("gbc-printer-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut printer = Printer::new(&dir).unwrap();
+
+        assert_eq!(send(&mut printer, command::INIT, false, &[]), (0x81, 0x00));
+
+        // Two rows of black tiles (0x280 bytes), compressed
+        let data = [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFA, 0xFF];
+        let (_, status) = send(&mut printer, command::DATA, true, &data);
+        assert_eq!(status, status::UNPROCESSED_DATA);
+
+        // Print with a bottom margin
+        let (_, status) = send(&mut printer, command::PRINT, false, &[1, 0x13, 0xE4, 0x40]);
+        assert_eq!(status, status::PRINTING);
+
+        for _ in 0..Printer::PRINT_DURATION {
+            send(&mut printer, command::STATUS, false, &[]);
+        }
+        assert_eq!(send(&mut printer, command::STATUS, false, &[]).1, 0x00);
+
+        let png = std::fs::read(dir.join("print-001.png")).unwrap();
+        assert_eq!(&png[1..4], b"PNG");
+
+        // Checksum errors are reported
+        let mut packet = vec![0x88, 0x33, command::STATUS, 0, 0, 0, 0xFF, 0xFF];
+        packet.extend_from_slice(&[0, 0]);
+        let response: Vec<u8> = packet.iter().map(|b| printer.transfer(*b)).collect();
+        assert_eq!(response[9], status::CHECKSUM_ERROR);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn data_full() {
+        let dir = std::env::temp_dir().join(format!("gbc-printer-full-{}", std::process::id()));
+        let mut printer = Printer::new(&dir).unwrap();
+
+        send(&mut printer, command::INIT, false, &[]);
+
+        // The buffer holds 9 packets of two tile rows each
+        let data = [0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFA, 0x00];
+
+        for _ in 0..8 {
+            let (_, status) = send(&mut printer, command::DATA, true, &data);
+            assert_eq!(status, status::UNPROCESSED_DATA);
+        }
+
+        let (_, status) = send(&mut printer, command::DATA, true, &data);
+        assert_eq!(status, status::UNPROCESSED_DATA | status::DATA_FULL);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,385 @@
+/// A device connected to the serial port via the link cable (e.g., a printer,
+/// another Gameboy, or a test harness).
+///
+/// Devices exchange whole bytes with the serial port. The bits of the incoming
+/// byte are still shifted in one at a time, so transfer timing is unaffected:
+/// with the internal clock, bits are shifted on the Gameboy's serial clock, and
+/// with the external clock, as the device clocks them.
+pub trait SerialDevice {
+    /// Called when the Gameboy starts a transfer using its internal clock.
+    ///
+    /// `byte` is the outgoing byte (i.e., the value of SB). Returns the byte
+    /// received from the device.
+    fn transfer(&mut self, byte: u8) -> u8;
+
+    /// Called on every step while the Gameboy is waiting for a transfer that
+    /// is clocked by the other side (i.e., external clock).
+    ///
+    /// `byte` is the outgoing byte. Return the byte received from the device to
+    /// complete the transfer, or `None` if the device has not started one.
+    ///
+    /// By default, devices never provide a clock.
+    fn external_transfer(&mut self, _byte: u8) -> Option<u8> {
+        None
+    }
+
+    /// Called on every step of a transfer that the device has started on the
+    /// external clock (i.e., once `external_transfer` returned a byte).
+    ///
+    /// Returns the number of bits clocked by the device since the last call.
+    /// By default, the whole byte is clocked at once.
+    fn external_clock(&mut self) -> u8 {
+        8
+    }
+
+    /// Called each time the Gameboy shifts a bit using its internal clock.
+    fn clock(&mut self) {}
+
+    /// Called when the Gameboy stops waiting for a transfer on the external
+    /// clock before it completes (e.g., SC is cleared, or the Gameboy switches
+    /// to the internal clock). The device must forget the outgoing byte.
+    fn cancel_external_transfer(&mut self) {}
+}
+
+/// Serial port
+///
+/// A transfer shifts out the 8 bits of SB (MSB first) while shifting in the
+/// bits received from the other side. With the internal clock, one bit is
+/// shifted on every falling edge of the serial clock, which is derived from
+/// the system counter (8192 Hz, or 262144 Hz in CGB fast mode). With the
+/// external clock, bits are only shifted when the other side provides a clock.
+///
+/// If no device is attached, every incoming bit reads as 1.
+#[cfg_attr(feature = "save", derive(serde::Serialize), derive(serde::Deserialize))]
+pub struct Serial {
+    /// Serial transfer data (0xFF01)
+    sb: u8,
+
+    /// Serial transfer control (0xFF02)
+    ///
+    /// Bit 7: Transfer start/in progress
+    /// Bit 1: Clock speed (CGB only)
+    /// Bit 0: Shift clock (0: external, 1: internal)
+    sc: u8,
+
+    /// Number of bits left to shift in the current transfer
+    bits: u8,
+
+    /// Byte being shifted in during the current transfer
+    incoming: u8,
+
+    /// Set once the other side has started clocking the current transfer
+    /// (external clock only)
+    clocked: bool,
+
+    /// Previous value of the serial clock, used to detect falling edges
+    prev_clock: bool,
+
+    /// Every byte sent using the internal clock. Mainly used in tests.
+    buffer: Vec<char>,
+
+    /// Device attached to the link cable, if any
+    #[cfg_attr(feature = "save", serde(skip))]
+    device: Option<Box<dyn SerialDevice>>,
+
+    cgb: bool,
+}
+
+impl Serial {
+    pub const SB_ADDR: u16 = 0xFF01;
+    pub const SC_ADDR: u16 = 0xFF02;
+
+    const SC_REQUEST_MASK: u8 = 1 << 7;
+    const SC_FAST_MASK: u8 = 1 << 1;
+    const SC_INTERNAL_MASK: u8 = 1 << 0;
+
+    pub fn new(cgb: bool) -> Self {
+        Self {
+            sb: 0,
+            sc: 0,
+            bits: 0,
+            incoming: 0xFF,
+            clocked: false,
+            prev_clock: false,
+            buffer: Vec::new(),
+            device: None,
+            cgb,
+        }
+    }
+
+    /// Attach a device to the serial port, replacing any existing device.
+    pub fn attach(&mut self, device: Box<dyn SerialDevice>) -> Option<Box<dyn SerialDevice>> {
+        let previous = self.detach();
+        self.device = Some(device);
+
+        previous
+    }
+
+    /// Detach the device attached to the serial port, if any.
+    pub fn detach(&mut self) -> Option<Box<dyn SerialDevice>> {
+        let mut device = self.device.take()?;
+
+        // The device can no longer complete a pending transfer
+        if self.external_pending() {
+            device.cancel_external_transfer();
+        }
+
+        Some(device)
+    }
+
+    #[inline]
+    fn active(&self) -> bool {
+        self.sc & Self::SC_REQUEST_MASK != 0
+    }
+
+    #[inline]
+    fn internal_clock(&self) -> bool {
+        self.sc & Self::SC_INTERNAL_MASK != 0
+    }
+
+    /// Returns `true` if the Gameboy is waiting for a transfer on the external clock
+    #[inline]
+    fn external_pending(&self) -> bool {
+        self.active() && !self.internal_clock()
+    }
+
+    /// Returns the bit of the system counter that drives the internal serial clock
+    #[inline]
+    fn clock_bit(&self) -> u16 {
+        if self.cgb && self.sc & Self::SC_FAST_MASK != 0 {
+            3
+        } else {
+            8
+        }
+    }
+
+    /// Execute a single step of the serial port.
+    ///
+    /// `counter` is the current value of the timer's internal system counter.
+    ///
+    /// Returns `true` if a transfer completed and an interrupt should be triggered.
+    pub fn step(&mut self, counter: u16) -> bool {
+        let clock = counter & 1 << self.clock_bit() != 0;
+        let falling_edge = self.prev_clock && !clock;
+
+        self.prev_clock = clock;
+
+        if !self.active() {
+            return false;
+        }
+
+        if !self.internal_clock() {
+            return self.external_step();
+        }
+
+        if !falling_edge {
+            return false;
+        }
+
+        if let Some(device) = self.device.as_mut() {
+            device.clock();
+        }
+
+        self.shift()
+    }
+
+    /// Execute a single step of a transfer that is clocked by the other side.
+    ///
+    /// Bytes are exchanged as soon as the other side starts the transfer, but
+    /// the incoming bits are only shifted in as the other side clocks them.
+    /// This way, both sides complete the transfer on the same cycle.
+    ///
+    /// Returns `true` if the transfer is complete.
+    fn external_step(&mut self) -> bool {
+        let device = match self.device.as_mut() {
+            Some(device) => device,
+            None => return false,
+        };
+
+        if !self.clocked {
+            match device.external_transfer(self.sb) {
+                Some(incoming) => {
+                    self.incoming = incoming;
+                    self.clocked = true;
+                }
+                None => return false,
+            }
+        }
+
+        for _ in 0..device.external_clock() {
+            if self.shift() {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Shift out a single bit, and shift in the next bit of the incoming byte.
+    ///
+    /// Returns `true` if the transfer is complete.
+    fn shift(&mut self) -> bool {
+        let bit = self.incoming >> 7;
+
+        self.sb = self.sb << 1 | bit;
+        self.incoming <<= 1;
+        self.bits -= 1;
+
+        if self.bits == 0 {
+            self.sc &= !Self::SC_REQUEST_MASK;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            Self::SB_ADDR => self.sb,
+            Self::SC_ADDR => {
+                // Unused bits read as 1. The clock speed bit only exists on CGB.
+                if self.cgb {
+                    self.sc | 0x7C
+                } else {
+                    self.sc | 0x7E
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            Self::SB_ADDR => self.sb = value,
+            Self::SC_ADDR => {
+                let external_pending = self.external_pending();
+
+                self.sc = value & 0x83;
+
+                if external_pending && !self.external_pending() {
+                    if let Some(device) = self.device.as_mut() {
+                        device.cancel_external_transfer();
+                    }
+                }
+
+                if self.active() {
+                    // Start (or restart) a transfer
+                    self.bits = 8;
+                    self.clocked = false;
+
+                    if self.internal_clock() {
+                        self.buffer.push(self.sb as char);
+
+                        // Exchange bytes with the device. With no device
+                        // attached, the incoming bits are all 1s.
+                        let sb = self.sb;
+                        self.incoming = self
+                            .device
+                            .as_mut()
+                            .map_or(0xFF, |device| device.transfer(sb));
+                    }
+                } else {
+                    // Clearing the start bit aborts the transfer
+                    self.bits = 0;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Returns a handle to the serial buffer
+    ///
+    /// This buffer contains every character logged to the serial port.
+    /// Mainly used in tests.
+    pub fn buffer(&self) -> &[char] {
+        &self.buffer
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Run the serial port for `cycles` clock cycles, one M-cycle at a time
+    fn run(serial: &mut Serial, counter: &mut u16, cycles: u32) -> bool {
+        let mut interrupt = false;
+
+        for _ in 0..cycles / 4 {
+            *counter = counter.wrapping_add(4);
+            interrupt |= serial.step(*counter);
+        }
+
+        interrupt
+    }
+
+    #[test]
+    fn internal_clock_transfer() {
+        let mut serial = Serial::new(false);
+        let mut counter = 0;
+
+        serial.write(Serial::SB_ADDR, 0x42);
+        serial.write(Serial::SC_ADDR, 0x81);
+        assert_eq!(serial.buffer(), &['B']);
+
+        // 8 bits at 8192 Hz take 4096 cycles
+        assert!(!run(&mut serial, &mut counter, 4092));
+        assert_eq!(serial.read(Serial::SC_ADDR) & 0x80, 0x80);
+
+        assert!(run(&mut serial, &mut counter, 4));
+        assert_eq!(serial.read(Serial::SC_ADDR), 0x7F);
+
+        // No cable attached
+        assert_eq!(serial.read(Serial::SB_ADDR), 0xFF);
+    }
+
+    /// Sends back each byte it receives on the next transfer
+    struct Echo {
+        last: u8,
+    }
+
+    impl SerialDevice for Echo {
+        fn transfer(&mut self, byte: u8) -> u8 {
+            std::mem::replace(&mut self.last, byte)
+        }
+
+        fn external_transfer(&mut self, byte: u8) -> Option<u8> {
+            Some(self.transfer(byte))
+        }
+    }
+
+    #[test]
+    fn device_transfer() {
+        let mut serial = Serial::new(false);
+        let mut counter = 0;
+
+        serial.attach(Box::new(Echo { last: 0x99 }));
+
+        serial.write(Serial::SB_ADDR, 0x42);
+        serial.write(Serial::SC_ADDR, 0x81);
+        assert!(run(&mut serial, &mut counter, 4096));
+        assert_eq!(serial.read(Serial::SB_ADDR), 0x99);
+
+        // External clock: the device completes the transfer immediately
+        serial.write(Serial::SB_ADDR, 0x10);
+        serial.write(Serial::SC_ADDR, 0x80);
+        assert!(run(&mut serial, &mut counter, 4));
+        assert_eq!(serial.read(Serial::SB_ADDR), 0x42);
+
+        assert!(serial.detach().is_some());
+        serial.write(Serial::SC_ADDR, 0x81);
+        assert!(run(&mut serial, &mut counter, 4096));
+        assert_eq!(serial.read(Serial::SB_ADDR), 0xFF);
+    }
+
+    #[test]
+    fn external_clock_transfer() {
+        let mut serial = Serial::new(true);
+        let mut counter = 0;
+
+        serial.write(Serial::SC_ADDR, 0x80);
+
+        // Without a clock from the other side, the transfer never completes
+        assert!(!run(&mut serial, &mut counter, 8192));
+        assert_eq!(serial.read(Serial::SC_ADDR), 0xFC);
+    }
+}
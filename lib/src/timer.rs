@@ -1,19 +1,23 @@
 /// Internal CGB timer functionality
+///
+/// The timer is built around a 16-bit system counter that is incremented on
+/// every CPU clock cycle. DIV exposes the upper 8 bits of this counter, and
+/// TIMA is incremented on the falling edge of the counter bit selected by TAC.
+///
+/// Since TIMA is driven by an edge detector, writes to DIV or TAC can cause
+/// spurious TIMA increments, just like on real hardware.
 #[cfg_attr(feature = "save", derive(serde::Serialize), derive(serde::Deserialize))]
 pub struct Timer {
-    /// Divider register (0xFF04)
+    /// Internal system counter
     ///
-    /// Ticks at frequency = Cpu::CYCLE_TIME * 256
-    ///
-    /// It is affected by CGB double-speed mode.
-    div: u8,
-    div_counter: u16,
+    /// The upper 8 bits are exposed as the divider register (0xFF04). It
+    /// ticks at the CPU clock rate, so it is affected by CGB double-speed mode.
+    counter: u16,
 
     /// Timer counter (0xFF05)
     ///
     /// Ticks at frequency specified by TAC register.
     tima: u8,
-    tima_counter: u16,
 
     /// Timer modulo (0xFF06)
     ///
@@ -25,89 +29,124 @@ pub struct Timer {
     /// Bit 2: Timer enable
     /// Bits 0-1: Clock select
     tac: u8,
+
+    /// Number of clock cycles left until TMA is loaded into TIMA after an
+    /// overflow. During this time, TIMA reads as 0x00, and a write to TIMA
+    /// cancels the reload (and the interrupt).
+    reload_delay: u8,
+
+    /// Set during the M-cycle in which TMA is loaded into TIMA. Writes to
+    /// TIMA are ignored in this cycle, and writes to TMA are also written
+    /// to TIMA.
+    reloading: bool,
 }
 
 impl Timer {
-    /// Timer clock runs at 16.384 kHz.
-    ///
-    /// As a ratio, this is equal to 256 CPU clock cycles.
-    pub const TIMER_RATIO: u16 = 256;
+    /// Number of clock cycles between a TIMA overflow and the reload from TMA
+    const RELOAD_DELAY: u8 = 4;
 
     pub fn new() -> Self {
         Self {
-            div: 0,
-            div_counter: 0,
+            counter: 0,
             tima: 0,
-            tima_counter: 0,
             tma: 0,
             tac: 0,
+            reload_delay: 0,
+            reloading: false,
         }
     }
 
+    /// Returns the current value of the DIV register
+    #[inline]
+    pub fn div(&self) -> u8 {
+        (self.counter >> 8) as u8
+    }
+
+    /// Returns the current value of the internal system counter
+    #[inline]
+    pub fn counter(&self) -> u16 {
+        self.counter
+    }
+
     #[inline]
     pub fn enabled(&self) -> bool {
         (self.tac & 1 << 2) != 0
     }
 
-    /// This function is called once per CPU step from the main Gameboy loop.
+    /// Advance the timer by the given number of clock cycles.
+    ///
+    /// This function is called once per M-cycle by the memory bus.
     ///
     /// Returns `true` if an interrupt should be triggered.
     pub fn step(&mut self, cycles: u16, speed: bool) -> bool {
-        // DIV: Ticks once per 256 clock cycles in single-speed and twice as fast
-        // in double-speed
-        //
-        // Note: The TAC enable flag does not affect this counter
-        let threshold = if speed { 128 } else { 256 };
-        self.div_counter += cycles;
-        if self.div_counter >= threshold {
-            self.div = self.div.wrapping_add(1);
-            self.div_counter -= threshold;
-        }
+        // `cycles` is scaled to the base clock rate, but the system counter
+        // runs at the CPU clock rate
+        let cycles = if speed { cycles * 2 } else { cycles };
+        let mut interrupt = false;
 
-        if !self.enabled() {
-            return false;
-        }
+        // A reload only affects writes in the M-cycle it happens in
+        self.reloading = false;
 
-        let threshold = self.tima_ratio();
-        let mut interrupt = false;
+        for _ in 0..cycles {
+            if self.reload_delay > 0 {
+                self.reload_delay -= 1;
 
-        self.tima_counter += cycles;
-        while self.tima_counter >= threshold {
-            let tima = self.tima.wrapping_add(1);
+                if self.reload_delay == 0 {
+                    // Load TMA into TIMA, and trigger an interrupt
+                    self.tima = self.tma;
+                    self.reloading = true;
+                    interrupt = true;
+                }
+            }
 
-            self.tima = if tima < self.tima {
-                // When TIMA overflows, load TMA into TIMA, and trigger an interrupt
-                interrupt = true;
-                self.tma
-            } else {
-                tima
-            };
+            let signal = self.signal();
+            self.counter = self.counter.wrapping_add(1);
 
-            self.tima_counter -= threshold;
+            if signal && !self.signal() {
+                self.increment();
+            }
         }
 
         interrupt
     }
 
-    /// Returns the ratio of CPU clock cycle time to current TIMA clock
-    /// mode in TAC
+    /// Returns the bit of the system counter that drives TIMA, based on the
+    /// clock select in TAC
     #[inline]
-    fn tima_ratio(&self) -> u16 {
+    fn counter_bit(&self) -> u16 {
         match self.tac & 0x3 {
-            0 => Self::TIMER_RATIO * 4,
-            1 => Self::TIMER_RATIO / 16,
-            2 => Self::TIMER_RATIO / 4,
-            3 => Self::TIMER_RATIO,
+            0 => 9,
+            1 => 3,
+            2 => 5,
+            3 => 7,
             _ => unreachable!(),
         }
     }
 
+    /// Input to the TIMA falling edge detector: the selected counter bit
+    /// ANDed with the timer enable bit
+    #[inline]
+    fn signal(&self) -> bool {
+        self.enabled() && self.counter & 1 << self.counter_bit() != 0
+    }
+
+    /// Increment TIMA, and schedule a reload if it overflows
+    fn increment(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+
+        self.tima = tima;
+
+        if overflow {
+            self.reload_delay = Self::RELOAD_DELAY;
+        }
+    }
+
     pub fn read(&self, addr: u16) -> u8 {
         match addr {
-            0xFF04 => self.div,
+            0xFF04 => self.div(),
             0xFF05 => self.tima,
             0xFF06 => self.tma,
-            0xFF07 => self.tac,
+            0xFF07 => self.tac | 0xF8,
             _ => unreachable!(),
         }
     }
@@ -115,14 +154,110 @@ impl Timer {
     pub fn write(&mut self, addr: u16, value: u8) {
         match addr {
             0xFF04 => {
-                // Writes to DIV reset the register
-                self.div = 0;
-                self.div_counter = 0;
+                // Writes to DIV reset the entire system counter. If the selected
+                // bit was set, this is seen as a falling edge.
+                let signal = self.signal();
+                self.counter = 0;
+
+                if signal {
+                    self.increment();
+                }
+            }
+            0xFF05 => {
+                // Writes in the cycle TMA is loaded are ignored, while writes
+                // before that cancel the pending reload
+                if !self.reloading {
+                    self.tima = value;
+                    self.reload_delay = 0;
+                }
+            }
+            0xFF06 => {
+                self.tma = value;
+
+                if self.reloading {
+                    self.tima = value;
+                }
+            }
+            0xFF07 => {
+                // Disabling the timer or changing the clock select can cause
+                // a falling edge
+                let signal = self.signal();
+                self.tac = value & 0x07;
+
+                if signal && !self.signal() {
+                    self.increment();
+                }
             }
-            0xFF05 => self.tima = value,
-            0xFF06 => self.tma = value,
-            0xFF07 => self.tac = value,
             _ => unreachable!(),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Step the timer one M-cycle at a time
+    fn step(timer: &mut Timer, cycles: u16) -> bool {
+        let mut interrupt = false;
+
+        for _ in 0..cycles / 4 {
+            interrupt |= timer.step(4, false);
+        }
+
+        interrupt
+    }
+
+    #[test]
+    fn div_write_increments_tima() {
+        let mut timer = Timer::new();
+
+        // Enable the timer at 262144 Hz (bit 3)
+        timer.write(0xFF07, 0x05);
+        step(&mut timer, 8);
+        assert_eq!(timer.read(0xFF05), 0);
+
+        // Bit 3 is now set, so resetting DIV is a falling edge
+        timer.write(0xFF04, 0);
+        assert_eq!(timer.read(0xFF05), 1);
+        assert_eq!(timer.read(0xFF04), 0);
+
+        // Bit 3 is clear, so no increment
+        step(&mut timer, 4);
+        timer.write(0xFF04, 0);
+        assert_eq!(timer.read(0xFF05), 1);
+    }
+
+    #[test]
+    fn tima_reload() {
+        let mut timer = Timer::new();
+
+        timer.write(0xFF05, 0xFF);
+        timer.write(0xFF06, 0x42);
+        timer.write(0xFF07, 0x05);
+
+        // TIMA reads as 0 for one M-cycle after overflow
+        assert!(!step(&mut timer, 16));
+        assert_eq!(timer.read(0xFF05), 0);
+
+        // Then TMA is loaded and the interrupt is triggered
+        assert!(step(&mut timer, 4));
+        assert_eq!(timer.read(0xFF05), 0x42);
+
+        // Writing TIMA during the reload cycle is ignored, but writing TMA
+        // also updates TIMA
+        timer.write(0xFF05, 0x10);
+        assert_eq!(timer.read(0xFF05), 0x42);
+        timer.write(0xFF06, 0x50);
+        assert_eq!(timer.read(0xFF05), 0x50);
+
+        // Writing TIMA right after the overflow cancels the reload
+        step(&mut timer, 4);
+        timer.write(0xFF05, 0xFF);
+        step(&mut timer, 8);
+        assert_eq!(timer.read(0xFF05), 0);
+        timer.write(0xFF05, 0x80);
+        assert!(!step(&mut timer, 4));
+        assert_eq!(timer.read(0xFF05), 0x80);
+    }
+}
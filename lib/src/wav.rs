@@ -0,0 +1,199 @@
+//! Minimal WAV writer for recording Gameboy audio
+//!
+//! Audio is written as 16-bit stereo PCM. Since samples are pulled from the
+//! emulator's audio stream, the recording is exact with respect to emulated
+//! time: it does not depend on how fast the emulator is actually running.
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::Gameboy;
+
+const NUM_CHANNELS: u16 = 2;
+const BITS_PER_SAMPLE: u16 = 16;
+const BLOCK_ALIGN: u16 = NUM_CHANNELS * BITS_PER_SAMPLE / 8;
+
+/// Size of the RIFF/WAVE header, in bytes
+const HEADER_SIZE: u32 = 44;
+
+/// Writes interleaved stereo `i16` samples to a WAV file.
+///
+/// The header is written with placeholder sizes on creation and fixed up by
+/// `WavWriter::finish`, which is also called when the writer is dropped.
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    sample_rate: u32,
+
+    /// Number of bytes of sample data written so far
+    data_size: u32,
+
+    /// Scratch buffer used by `WavWriter::record`
+    samples: Vec<i16>,
+
+    finished: bool,
+}
+
+impl WavWriter<BufWriter<File>> {
+    /// Create a WAV file at `path`, truncating any existing file.
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32) -> Result<Self> {
+        let file = File::create(path)?;
+        Self::new(BufWriter::new(file), sample_rate)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(writer: W, sample_rate: u32) -> Result<Self> {
+        if sample_rate == 0 {
+            return Err(Error::InvalidValue(
+                "WAV sample rate must be greater than 0".to_string(),
+            ));
+        }
+
+        let mut wav = Self {
+            writer,
+            sample_rate,
+            data_size: 0,
+            samples: Vec::new(),
+            finished: false,
+        };
+
+        wav.write_header()?;
+
+        Ok(wav)
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        let byte_rate = self.sample_rate * BLOCK_ALIGN as u32;
+
+        let w = &mut self.writer;
+        w.write_all(b"RIFF")?;
+        w.write_all(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        w.write_all(b"WAVE")?;
+
+        // Format chunk: uncompressed PCM
+        w.write_all(b"fmt ")?;
+        w.write_all(&16u32.to_le_bytes())?;
+        w.write_all(&1u16.to_le_bytes())?;
+        w.write_all(&NUM_CHANNELS.to_le_bytes())?;
+        w.write_all(&self.sample_rate.to_le_bytes())?;
+        w.write_all(&byte_rate.to_le_bytes())?;
+        w.write_all(&BLOCK_ALIGN.to_le_bytes())?;
+        w.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+
+        w.write_all(b"data")?;
+        w.write_all(&self.data_size.to_le_bytes())?;
+
+        Ok(())
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Number of stereo frames written so far
+    pub fn frames(&self) -> u32 {
+        self.data_size / BLOCK_ALIGN as u32
+    }
+
+    /// Write interleaved stereo samples (left, right).
+    pub fn write_samples(&mut self, samples: &[i16]) -> Result<()> {
+        for sample in samples {
+            self.writer.write_all(&sample.to_le_bytes())?;
+        }
+
+        self.data_size += (samples.len() * 2) as u32;
+
+        Ok(())
+    }
+
+    /// Drain all pending audio samples from the `Gameboy` and write them out.
+    ///
+    /// The `Gameboy` sample rate must match the rate of this writer. Call this
+    /// after every frame (or every few frames) to avoid losing samples.
+    ///
+    /// Returns the number of stereo frames written.
+    pub fn record(&mut self, gameboy: &mut Gameboy) -> Result<usize> {
+        if gameboy.sample_rate() != Some(self.sample_rate) {
+            return Err(Error::InvalidValue(format!(
+                "Gameboy sample rate {:?} does not match WAV sample rate {}",
+                gameboy.sample_rate(),
+                self.sample_rate
+            )));
+        }
+
+        let mut samples = std::mem::take(&mut self.samples);
+        samples.clear();
+
+        let frames = gameboy.audio_samples(&mut samples);
+        let result = self.write_samples(&samples);
+
+        self.samples = samples;
+
+        result.map(|_| frames)
+    }
+
+    /// Fix up the header sizes and flush the underlying writer.
+    ///
+    /// The writer must not be used after this has been called.
+    pub fn finish(&mut self) -> Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+
+        self.finished = true;
+
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Drop for WavWriter<W> {
+    fn drop(&mut self) {
+        if let Err(err) = self.finish() {
+            log::error!("Failed to finish WAV file: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    #[test]
+    fn header_sizes() {
+        let mut buf = Cursor::new(Vec::new());
+
+        {
+            let mut wav = WavWriter::new(&mut buf, 48000).unwrap();
+            wav.write_samples(&[1, -1, 2, -2, 3, -3]).unwrap();
+            assert_eq!(wav.frames(), 3);
+            wav.finish().unwrap();
+        }
+
+        let data = buf.into_inner();
+        let read_u32 = |offset: usize| {
+            u32::from_le_bytes([
+                data[offset],
+                data[offset + 1],
+                data[offset + 2],
+                data[offset + 3],
+            ])
+        };
+
+        assert_eq!(data.len(), HEADER_SIZE as usize + 12);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(read_u32(4), HEADER_SIZE - 8 + 12);
+        assert_eq!(read_u32(24), 48000);
+        assert_eq!(read_u32(28), 48000 * 4);
+        assert_eq!(&data[36..40], b"data");
+        assert_eq!(read_u32(40), 12);
+        assert_eq!(&data[44..46], &1i16.to_le_bytes());
+    }
+}